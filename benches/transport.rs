@@ -0,0 +1,183 @@
+use bincode::{DefaultOptions, Options};
+use bytes::{Bytes, BytesMut};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::{executor::block_on, SinkExt, StreamExt};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_util::codec::Encoder;
+use yew::transport::{Cipher, Key, PlainCodec, Role, SafeCodec, Transport, WithPayload};
+
+const SIZES: [usize; 3] = [64, 1024, 16 * 1024];
+
+/// Discards everything written, never yields anything to read.
+struct Null;
+
+impl AsyncWrite for Null {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for Null {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        _: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Pending
+    }
+}
+
+/// Reads the same bytes over and over, discards everything written.
+struct Repeat {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl AsyncRead for Repeat {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while buf.remaining() > 0 {
+            let pos = self.pos;
+            let n = buf.remaining().min(self.data.len() - pos);
+            buf.put_slice(&self.data[pos..pos + n]);
+            self.pos = (pos + n) % self.data.len();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Repeat {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Plain frame whose data is `data`.
+fn frame(data: &[u8]) -> Vec<u8> {
+    let mut frame = (data.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(data);
+    frame
+}
+
+fn codec(cipher: Cipher) -> SafeCodec {
+    SafeCodec::with_key(&Key::new(&[7; 32]).unwrap(), Role::Client, cipher)
+}
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    for cipher in Cipher::ALL.iter().copied() {
+        for size in SIZES.iter().copied() {
+            let mut codec = codec(cipher);
+            let item = Bytes::from(vec![0; size]);
+            let mut dst = BytesMut::new();
+
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_with_input(BenchmarkId::new(cipher.name(), size), &size, |b, _| {
+                b.iter(|| {
+                    dst.clear();
+                    codec.encode(item.clone(), &mut dst).unwrap();
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn send(c: &mut Criterion) {
+    let mut group = c.benchmark_group("send");
+    for size in SIZES.iter().copied() {
+        let cipher = Cipher::ChaCha20Poly1305;
+        let mut transport = Transport::<_, (), Vec<u8>>::new(Null, codec(cipher));
+        let message = vec![0; size];
+
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new(cipher.name(), size), &size, |b, _| {
+            b.iter(|| block_on(transport.send(message.clone())).unwrap())
+        });
+
+        let mut transport = Transport::<_, (), Vec<u8>, _>::new(Null, PlainCodec::new());
+        group.bench_with_input(BenchmarkId::new("plain", size), &size, |b, _| {
+            b.iter(|| block_on(transport.send(message.clone())).unwrap())
+        });
+    }
+    group.finish();
+}
+
+/// Channel data serialized as `Vec<u8>` against raw [`WithPayload`] bytes.
+fn payload(c: &mut Criterion) {
+    let mut group = c.benchmark_group("payload");
+    for size in SIZES.iter().copied() {
+        let cipher = Cipher::ChaCha20Poly1305;
+        let message = vec![0; size];
+        let bytes = Bytes::from(message.clone());
+        group.throughput(Throughput::Bytes(size as u64));
+
+        let mut transport = Transport::<_, (), Vec<u8>>::new(Null, codec(cipher));
+        group.bench_with_input(BenchmarkId::new("send-serde", size), &size, |b, _| {
+            b.iter(|| block_on(transport.send(message.clone())).unwrap())
+        });
+
+        let mut transport = Transport::<_, (), WithPayload<()>>::new(Null, codec(cipher));
+        group.bench_with_input(BenchmarkId::new("send-raw", size), &size, |b, _| {
+            b.iter(|| {
+                let item = WithPayload {
+                    message: (),
+                    payload: bytes.clone(),
+                };
+                block_on(transport.send(item)).unwrap()
+            })
+        });
+
+        // 接收端使用明文帧, 密封的帧不能重复读取
+        let data = frame(&DefaultOptions::new().serialize(&message).unwrap());
+        let mut transport =
+            Transport::<_, Vec<u8>, (), _>::new(Repeat { data, pos: 0 }, PlainCodec::new());
+        group.bench_with_input(BenchmarkId::new("receive-serde", size), &size, |b, _| {
+            b.iter(|| block_on(transport.next()).unwrap().unwrap())
+        });
+
+        // `()` 序列化后为空, 帧中只有 payload
+        let data = frame(&bytes);
+        let mut transport =
+            Transport::<_, WithPayload<()>, (), _>::new(Repeat { data, pos: 0 }, PlainCodec::new());
+        group.bench_with_input(BenchmarkId::new("receive-raw", size), &size, |b, _| {
+            b.iter(|| block_on(transport.next()).unwrap().unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encode, send, payload);
+criterion_main!(benches);
@@ -1,32 +1,73 @@
-use bytes::{BufMut, BytesMut};
+use anyhow::{bail, Context};
+use bytes::{BufMut, Bytes, BytesMut};
 use futures::{SinkExt, StreamExt};
-use std::{io, option::Option, result::Result};
+use std::{
+    env, fmt, io, net::SocketAddr, option::Option, path::PathBuf, result::Result, time::Duration,
+};
 use tokio::{
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncWrite},
+    net::{lookup_host, TcpListener, TcpStream},
     select,
     sync::oneshot,
+    task::JoinHandle,
+    time,
 };
 use tokio_util::codec::{Decoder, Encoder, Framed};
-use yew::client::Channel;
+use yew::{
+    client::{Channel, Client},
+    protocol::Payload,
+    transport::{
+        Builder, Connection, Key, LongPoll, Noise, NoisePattern, QuicClient, QuicStream,
+        RekeyPolicy, Stats, TlsClient, Transport, Verification, WebSocket,
+    },
+};
 
 #[tokio::main]
-async fn main() {
+async fn main() -> anyhow::Result<()> {
+    let builder = builder()?;
+    let report = opt("--stats", "YEW_STATS")
+        .map(|s| s.parse().map(Duration::from_secs))
+        .transpose()
+        .context("invalid --stats")?;
+    if report.is_some() {
+        eprintln!("[client] {:?}", builder.rekey_policy());
+        eprintln!(
+            "[client] padding {}, cover {:?}, compression {:?}, format {}",
+            builder.padding_policy(),
+            builder.cover_interval(),
+            builder.compression_level(),
+            builder.message_format()
+        );
+    }
+
     // TODO 重连机制
-    let server_addr = "127.0.0.1:11999";
+    let endpoint = endpoint()?;
+    let underlay = underlay(&endpoint).await?;
 
-    let conn = TcpStream::connect(server_addr).await.unwrap();
-    let mut client = yew::client::new::<TcpStream, Request, Response>(conn);
+    let mut client = connect(&endpoint, &builder, &underlay).await?;
+    let mut reporter =
+        report.map(|every| self::report(endpoint.to_string(), client.stats().clone(), every));
 
     // 断网即使重连后, 监听也失效
-    let lst = TcpListener::bind("0.0.0.0:1080").await.unwrap();
+    let socks = opt("--socks", "YEW_SOCKS").unwrap_or_else(|| "0.0.0.0:1080".to_owned());
+    let lst = TcpListener::bind(&socks)
+        .await
+        .with_context(|| format!("failed to listen on {}", socks))?;
     loop {
         let (conn, _) = lst.accept().await.unwrap();
 
         let mut result = client.connect();
         if result.is_err() {
-            let conn = TcpStream::connect(server_addr).await.unwrap();
-            client = yew::client::new::<TcpStream, Request, Response>(conn);
+            client = connect(&endpoint, &builder, &underlay).await?;
             result = client.connect();
+
+            if let Some(every) = report {
+                let stats = client.stats().clone();
+                let old = reporter.replace(self::report(endpoint.to_string(), stats, every));
+                if let Some(old) = old {
+                    old.abort();
+                }
+            }
         }
 
         if let Ok(channel) = result {
@@ -35,6 +76,478 @@ async fn main() {
     }
 }
 
+/// Where the server is.
+#[derive(Clone)]
+enum Endpoint {
+    /// `host:port`, over TCP or QUIC.
+    Net(String),
+    /// `unix:<path>`, a Unix socket.
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// A command whose stdin and stdout lead to the server, such as
+    /// `ssh host server --stdio`.
+    #[cfg(unix)]
+    Exec(String),
+}
+
+/// The server of `--exec <command>` or `YEW_EXEC`, else of `--server` or
+/// `YEW_SERVER`, by default `127.0.0.1:11999`. Both Unix sockets and
+/// commands are only supported on Unix.
+fn endpoint() -> anyhow::Result<Endpoint> {
+    if let Some(command) = opt("--exec", "YEW_EXEC") {
+        return exec(command);
+    }
+    let addr = opt("--server", "YEW_SERVER").unwrap_or_else(|| "127.0.0.1:11999".to_owned());
+    match addr.strip_prefix("unix:") {
+        Some(path) => unix(path.into()),
+        None => Ok(Endpoint::Net(addr)),
+    }
+}
+
+#[cfg(unix)]
+fn exec(command: String) -> anyhow::Result<Endpoint> {
+    Ok(Endpoint::Exec(command))
+}
+
+#[cfg(not(unix))]
+fn exec(_: String) -> anyhow::Result<Endpoint> {
+    bail!("--exec is not supported on this platform")
+}
+
+#[cfg(unix)]
+fn unix(path: PathBuf) -> anyhow::Result<Endpoint> {
+    Ok(Endpoint::Unix(path))
+}
+
+#[cfg(not(unix))]
+fn unix(_: PathBuf) -> anyhow::Result<Endpoint> {
+    bail!("unix sockets are not supported on this platform")
+}
+
+impl Endpoint {
+    /// Host the server is reached by, `localhost` unless over the network.
+    fn host(&self) -> &str {
+        match self {
+            Endpoint::Net(addr) => host(addr),
+            #[cfg(unix)]
+            Endpoint::Unix(_) | Endpoint::Exec(_) => "localhost",
+        }
+    }
+
+    /// Host with the port, as sent in the `Host` of a WebSocket upgrade.
+    fn authority(&self) -> &str {
+        match self {
+            Endpoint::Net(addr) => addr,
+            #[cfg(unix)]
+            Endpoint::Unix(_) | Endpoint::Exec(_) => "localhost",
+        }
+    }
+
+    async fn connect(&self) -> anyhow::Result<Box<dyn Io>> {
+        let conn: Box<dyn Io> = match self {
+            Endpoint::Net(addr) => Box::new(TcpStream::connect(addr).await?),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+            #[cfg(unix)]
+            Endpoint::Exec(command) => {
+                use std::process::Stdio;
+                use tokio::process::Command;
+
+                // 进程随 stdin 关闭而退出, 错误输出留给用户
+                let mut child = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .spawn()?;
+                let stdin = child.stdin.take().context("no stdin")?;
+                let stdout = child.stdout.take().context("no stdout")?;
+                Box::new(tokio::io::join(stdout, stdin))
+            }
+        };
+        Ok(conn)
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Net(addr) => f.write_str(addr),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+            #[cfg(unix)]
+            Endpoint::Exec(command) => write!(f, "`{}`", command),
+        }
+    }
+}
+
+/// How the client reaches the server.
+enum Underlay {
+    /// A byte stream, then TLS and a WebSocket, those configured.
+    Stream {
+        tls: Option<TlsClient>,
+        websocket: Option<WebSocket>,
+    },
+    /// HTTP requests, each over a connection with TLS if configured, to the
+    /// server or to an HTTP proxy.
+    LongPoll {
+        tls: Option<TlsClient>,
+        long_poll: LongPoll,
+        proxy: Option<Endpoint>,
+    },
+    Quic(QuicClient),
+}
+
+async fn underlay(endpoint: &Endpoint) -> anyhow::Result<Underlay> {
+    let websocket = websocket(endpoint);
+    let long_poll = long_poll(endpoint);
+    let verification = verification(endpoint)?;
+    if !quic() {
+        let tls = match verification {
+            Some((sni, verification)) => {
+                Some(TlsClient::new(&sni, verification).context("invalid --tls-sni")?)
+            }
+            None => None,
+        };
+        let long_poll = match long_poll {
+            Some(long_poll) => long_poll,
+            None => return Ok(Underlay::Stream { tls, websocket }),
+        };
+        if !matches!(endpoint, Endpoint::Net(_)) {
+            bail!("--http-path needs a host:port server");
+        }
+        if websocket.is_some() {
+            bail!("--ws-path does not go with --http-path");
+        }
+        // 代理只转发明文 HTTP
+        let proxy = opt("--http-proxy", "YEW_HTTP_PROXY").map(Endpoint::Net);
+        if proxy.is_some() && tls.is_some() {
+            bail!("--http-proxy does not go with --tls");
+        }
+        let long_poll = long_poll.proxy(proxy.is_some());
+        return Ok(Underlay::LongPoll {
+            tls,
+            long_poll,
+            proxy,
+        });
+    }
+
+    #[cfg_attr(not(unix), allow(clippy::infallible_destructuring_match))]
+    let server_addr = match endpoint {
+        Endpoint::Net(addr) => addr,
+        #[cfg(unix)]
+        _ => bail!("--quic needs a host:port server"),
+    };
+    if websocket.is_some() {
+        bail!("--ws-path does not go with --quic");
+    }
+    if long_poll.is_some() {
+        bail!("--http-path does not go with --quic");
+    }
+    // QUIC 总是使用 TLS, 证书的验证方式同 --tls
+    let (sni, verification) =
+        verification.unwrap_or_else(|| (endpoint.host().to_owned(), Verification::WebPki));
+    let bind: SocketAddr = match resolve(server_addr).await? {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let quic = QuicClient::new(bind, &sni, verification).context("invalid --tls-sni")?;
+    Ok(Underlay::Quic(quic))
+}
+
+async fn resolve(addr: &str) -> anyhow::Result<SocketAddr> {
+    let mut addrs = lookup_host(addr)
+        .await
+        .with_context(|| format!("failed to resolve {}", addr))?;
+    addrs
+        .next()
+        .with_context(|| format!("{} has no address", addr))
+}
+
+async fn connect(
+    addr: &Endpoint,
+    builder: &Builder,
+    underlay: &Underlay,
+) -> anyhow::Result<Client<Request, Response>> {
+    let (tls, websocket) = match (underlay, addr) {
+        (Underlay::Stream { tls, websocket }, _) => (tls.as_ref(), websocket.as_ref()),
+        (
+            Underlay::LongPoll {
+                tls,
+                long_poll,
+                proxy,
+            },
+            _,
+        ) => {
+            let server = proxy.as_ref().unwrap_or(addr).clone();
+            let tls = tls.clone();
+            // 每个 HTTP 连接都重新建立 TCP 与 TLS
+            let connect = move || {
+                let server = server.clone();
+                let tls = tls.clone();
+                async move {
+                    let mut conn = server.connect().await.map_err(io::Error::other)?;
+                    if let Some(tls) = tls {
+                        conn = Box::new(tls.connect(conn).await?);
+                    }
+                    Ok(conn)
+                }
+            };
+            let conn = long_poll
+                .connect(connect)
+                .await
+                .with_context(|| format!("long poll session with {} failed", addr))?;
+            let client = handshake(Box::new(conn), builder).await;
+            return client.with_context(|| format!("handshake with {} failed", addr));
+        }
+        (Underlay::Quic(quic), Endpoint::Net(server_addr)) => {
+            let conn = quic
+                .connect(resolve(server_addr).await?)
+                .await
+                .with_context(|| format!("QUIC handshake with {} failed", addr))?;
+            let client = handshake_quic(conn, builder).await;
+            return client.with_context(|| format!("handshake with {} failed", addr));
+        }
+        #[cfg(unix)]
+        (Underlay::Quic(_), _) => unreachable!("QUIC over a network address only"),
+    };
+
+    // TCP (或 Unix socket, 命令), TLS, WebSocket 逐层建立
+    let mut conn = addr
+        .connect()
+        .await
+        .with_context(|| format!("failed to connect to {}", addr))?;
+    if let Some(tls) = tls {
+        let tls = tls.connect(conn).await;
+        conn = Box::new(tls.with_context(|| format!("TLS handshake with {} failed", addr))?);
+    }
+    if let Some(websocket) = websocket {
+        let websocket = websocket.connect(conn).await;
+        conn =
+            Box::new(websocket.with_context(|| format!("websocket upgrade with {} failed", addr))?);
+    }
+    let client = handshake(conn, builder).await;
+    client.with_context(|| format!("handshake with {} failed", addr))
+}
+
+/// Connection under the tunnel, through whichever layers are configured.
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+async fn handshake(conn: Box<dyn Io>, builder: &Builder) -> io::Result<Client<Request, Response>> {
+    if plaintext() {
+        builder.plaintext(conn).await.map(yew::client::new)
+    } else {
+        yew::client::connect(conn, builder.clone()).await
+    }
+}
+
+async fn handshake_quic(
+    conn: Connection,
+    builder: &Builder,
+) -> io::Result<Client<Request, Response>> {
+    if plaintext() {
+        let control = builder.plaintext(QuicStream::open(&conn).await?).await?;
+        Ok(yew::client::new_quic(conn, control, builder.clone()))
+    } else {
+        yew::client::connect_quic(conn, builder.clone()).await
+    }
+}
+
+fn builder() -> anyhow::Result<Builder> {
+    // 使用 Noise 握手或明文时不需要共享密钥
+    let mut builder = match noise()? {
+        Some(noise) => Transport::builder().noise(Some(noise)),
+        None if plaintext() => Transport::builder(),
+        None => Transport::builder().key(load_key()?),
+    };
+    if let Some(ciphers) = opt("--ciphers", "YEW_CIPHERS") {
+        let ciphers = ciphers
+            .split(',')
+            .map(str::parse)
+            .collect::<io::Result<_>>();
+        builder = builder.ciphers(ciphers.context("invalid --ciphers")?);
+    }
+
+    // 0 表示不限制
+    let mut rekey = RekeyPolicy::default();
+    if let Some(n) = opt("--rekey-bytes", "YEW_REKEY_BYTES") {
+        rekey.bytes = Some(n.parse().context("invalid --rekey-bytes")?).filter(|&n| n > 0);
+    }
+    if let Some(n) = opt("--rekey-frames", "YEW_REKEY_FRAMES") {
+        rekey.frames = Some(n.parse().context("invalid --rekey-frames")?).filter(|&n| n > 0);
+    }
+    if let Some(n) = opt("--rekey-interval", "YEW_REKEY_INTERVAL") {
+        let secs = n.parse().context("invalid --rekey-interval")?;
+        rekey.interval = Some(Duration::from_secs(secs)).filter(|t| !t.is_zero());
+    }
+    builder = builder.rekey(rekey);
+
+    if let Some(padding) = opt("--padding", "YEW_PADDING") {
+        builder = builder.padding(padding.parse().context("invalid --padding")?);
+    }
+    if let Some(n) = opt("--cover", "YEW_COVER") {
+        let millis = n.parse().context("invalid --cover")?;
+        builder = builder.cover(Some(Duration::from_millis(millis)));
+    }
+    if let Some(n) = opt("--compress", "YEW_COMPRESS") {
+        let level = n.parse().context("invalid --compress")?;
+        builder = builder.compression(Some(level).filter(|&level| level > 0));
+    }
+    if let Some(format) = opt("--format", "YEW_FORMAT") {
+        builder = builder.format(format.parse().context("invalid --format")?);
+    }
+    if let Some(n) = opt("--max-frame", "YEW_MAX_FRAME") {
+        builder = builder.max_frame_len(n.parse().context("invalid --max-frame")?);
+    }
+    if let Some(n) = opt("--max-message", "YEW_MAX_MESSAGE") {
+        builder = builder.max_message_len(n.parse().context("invalid --max-message")?);
+    }
+    Ok(builder)
+}
+
+/// Loads the Noise handshake from `--noise <nk|xx|ik>`, `--noise-key <path>`
+/// and `--noise-remote <base64>`, or `YEW_NOISE`, `YEW_NOISE_KEY` and
+/// `YEW_NOISE_REMOTE`.
+fn noise() -> anyhow::Result<Option<Noise>> {
+    let pattern: NoisePattern = match opt("--noise", "YEW_NOISE") {
+        Some(pattern) => pattern.parse().context("invalid --noise")?,
+        None => return Ok(None),
+    };
+    let path = opt("--noise-key", "YEW_NOISE_KEY").context("--noise needs --noise-key <path>")?;
+    let key = Key::from_file(&path).with_context(|| format!("failed to load key file {}", path))?;
+
+    let mut noise = Noise::new(pattern, key);
+    if let Some(remote) = opt("--noise-remote", "YEW_NOISE_REMOTE") {
+        noise = noise.remote(Key::from_base64(&remote).context("invalid --noise-remote")?);
+    } else if pattern.needs_remote() {
+        bail!(
+            "noise {} needs the server public key, use --noise-remote <base64>",
+            pattern
+        );
+    } else {
+        eprintln!(
+            "[client] noise {} without --noise-remote accepts any server",
+            pattern
+        );
+    }
+    eprintln!(
+        "[client] noise {}, public key {}",
+        pattern,
+        noise.public_key().to_base64()
+    );
+    Ok(Some(noise))
+}
+
+/// Prints `stats` every `every` until the returned task is aborted.
+fn report(name: String, stats: Stats, every: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            time::sleep(every).await;
+            eprintln!("[client] {} {}", name, stats);
+        }
+    })
+}
+
+/// Returns the value following `name` on the command line.
+fn arg(name: &str) -> Option<String> {
+    env::args().skip_while(|a| a != name).nth(1)
+}
+
+/// Returns the command line option `name`, falling back to the environment variable `var`.
+fn opt(name: &str, var: &str) -> Option<String> {
+    arg(name).or_else(|| env::var(var).ok())
+}
+
+/// Runs the tunnel over a WebSocket when `--ws-path` or `YEW_WS_PATH` is
+/// set, with the `Host` from `--ws-host` or `YEW_WS_HOST`, by default the
+/// server address.
+fn websocket(endpoint: &Endpoint) -> Option<WebSocket> {
+    let path = opt("--ws-path", "YEW_WS_PATH")?;
+    let host = opt("--ws-host", "YEW_WS_HOST").unwrap_or_else(|| endpoint.authority().to_owned());
+    Some(WebSocket::new(path).host(host))
+}
+
+/// Runs the tunnel over HTTP long polling when `--http-path` or
+/// `YEW_HTTP_PATH` is set, with the `Host` from `--http-host` or
+/// `YEW_HTTP_HOST`, by default the server address. Requests go through the
+/// HTTP proxy of `--http-proxy` or `YEW_HTTP_PROXY` if given.
+fn long_poll(endpoint: &Endpoint) -> Option<LongPoll> {
+    let path = opt("--http-path", "YEW_HTTP_PATH")?;
+    let host =
+        opt("--http-host", "YEW_HTTP_HOST").unwrap_or_else(|| endpoint.authority().to_owned());
+    Some(LongPoll::new(path).host(host))
+}
+
+/// Runs the tunnel inside TLS when `--tls` or `YEW_TLS` is set, or any of
+/// the options below, returning the server name and how to verify the
+/// server. The server is verified by the SHA-256 fingerprint pinned with
+/// `--tls-pin`, by the CAs of `--tls-ca <path>`, or else by the public web
+/// CAs. `--tls-sni` sets the server name, by default the host of the server
+/// address.
+fn verification(endpoint: &Endpoint) -> anyhow::Result<Option<(String, Verification)>> {
+    let pin = opt("--tls-pin", "YEW_TLS_PIN");
+    let ca = opt("--tls-ca", "YEW_TLS_CA");
+    let sni = opt("--tls-sni", "YEW_TLS_SNI");
+    if !flag("--tls", "YEW_TLS") && pin.is_none() && ca.is_none() && sni.is_none() {
+        return Ok(None);
+    }
+
+    let verification = match (pin, ca) {
+        (Some(pin), _) => Verification::pin(&pin).context("invalid --tls-pin")?,
+        (None, Some(path)) => Verification::ca_file(&path)
+            .with_context(|| format!("failed to load CA file {}", path))?,
+        (None, None) => Verification::WebPki,
+    };
+    let sni = sni.unwrap_or_else(|| endpoint.host().to_owned());
+    Ok(Some((sni, verification)))
+}
+
+/// Whether `--quic` or `YEW_QUIC` is set: the tunnel runs over QUIC, each
+/// channel on a stream of its own.
+fn quic() -> bool {
+    flag("--quic", "YEW_QUIC")
+}
+
+/// Host part of `addr`, without the port and IPv6 brackets.
+fn host(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Whether the flag `name` is on the command line or the environment
+/// variable `var` is set.
+fn flag(name: &str, var: &str) -> bool {
+    env::args().any(|a| a == name) || env::var_os(var).is_some()
+}
+
+/// Whether `--plaintext` or `YEW_PLAINTEXT` is set: frames are sent without
+/// a handshake or encryption, over a link that is already secure.
+fn plaintext() -> bool {
+    flag("--plaintext", "YEW_PLAINTEXT")
+}
+
+/// Loads the tunnel key from `--key-file`, `--key`, `YEW_KEY_FILE` or `YEW_KEY`, in that order.
+fn load_key() -> anyhow::Result<Key> {
+    if let Some(path) = arg("--key-file") {
+        return Key::from_file(&path).with_context(|| format!("failed to load key file {}", path));
+    }
+    if let Some(key) = arg("--key") {
+        return Key::from_base64(&key).context("invalid --key");
+    }
+    if let Ok(path) = env::var("YEW_KEY_FILE") {
+        return Key::from_file(&path).with_context(|| format!("failed to load key file {}", path));
+    }
+    if env::var_os("YEW_KEY").is_some() {
+        return Key::from_env("YEW_KEY").context("invalid YEW_KEY");
+    }
+
+    bail!(
+        "no tunnel key configured, use --key-file <path>, --key <base64>, YEW_KEY_FILE or YEW_KEY"
+    )
+}
+
 fn process(mut conn: TcpStream, mut channel: Channel<Request, Response>) {
     tokio::spawn(async move {
         if let Ok((host, port)) = yew::socks::handshake(&mut conn).await {
@@ -66,13 +579,49 @@ fn process(mut conn: TcpStream, mut channel: Channel<Request, Response>) {
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub enum Request {
     Connect(String),
-    Data(Vec<u8>),
+    Data(Bytes),
 }
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Response {
-    data: Vec<u8>,
+    data: Bytes,
+}
+
+// 数据以 raw frame 发送, 不经过序列化
+impl Payload for Request {
+    fn into_payload(self) -> Result<Bytes, Self> {
+        match self {
+            Request::Data(data) => Ok(data),
+            request => Err(request),
+        }
+    }
+
+    fn from_payload(payload: Bytes) -> Option<Self> {
+        Some(Request::Data(payload))
+    }
+
+    fn payload_len(&self) -> usize {
+        match self {
+            Request::Connect(addr) => addr.len(),
+            Request::Data(data) => data.len(),
+        }
+    }
+}
+
+impl Payload for Response {
+    fn into_payload(self) -> Result<Bytes, Self> {
+        Ok(self.data)
+    }
+
+    fn from_payload(payload: Bytes) -> Option<Self> {
+        Some(Response { data: payload })
+    }
+
+    fn payload_len(&self) -> usize {
+        self.data.len()
+    }
 }
 
+#[derive(Default)]
 pub struct SimpleServerCodec(());
 
 impl SimpleServerCodec {
@@ -88,7 +637,7 @@ impl Decoder for SimpleServerCodec {
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Response>, io::Error> {
         if !buf.is_empty() {
             Ok(Some(Response {
-                data: buf.split().to_vec(),
+                data: buf.split().freeze(),
             }))
         } else {
             Ok(None)
@@ -102,13 +651,14 @@ impl Encoder<Request> for SimpleServerCodec {
     fn encode(&mut self, data: Request, buf: &mut BytesMut) -> Result<(), io::Error> {
         if let Request::Data(data) = data {
             buf.reserve(data.len());
-            buf.put_slice(data.as_slice());
+            buf.put_slice(&data);
             return Ok(());
         }
-        Err(io::Error::new(io::ErrorKind::Other, "err"))
+        Err(io::Error::other("err"))
     }
 }
 
+#[derive(Default)]
 pub struct SimpleClientCodec(());
 
 impl SimpleClientCodec {
@@ -123,7 +673,7 @@ impl Decoder for SimpleClientCodec {
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Request>, io::Error> {
         if !buf.is_empty() {
-            Ok(Some(Request::Data(buf.split().to_vec())))
+            Ok(Some(Request::Data(buf.split().freeze())))
         } else {
             Ok(None)
         }
@@ -136,7 +686,7 @@ impl Encoder<Response> for SimpleClientCodec {
     fn encode(&mut self, data: Response, buf: &mut BytesMut) -> Result<(), io::Error> {
         let v = data.data;
         buf.reserve(v.len());
-        buf.put_slice(v.as_slice());
+        buf.put_slice(&v);
         Ok(())
     }
 }
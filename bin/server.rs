@@ -1,38 +1,604 @@
-use bytes::{BufMut, BytesMut};
+use anyhow::{bail, Context};
+use bytes::{BufMut, Bytes, BytesMut};
 use futures::StreamExt;
-use std::{io, option::Option, result::Result};
+use std::{
+    env, io,
+    net::SocketAddr,
+    option::Option,
+    path::Path,
+    result::Result,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use tokio::{
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncWrite},
+    net::{lookup_host, TcpListener, TcpStream},
     select,
     sync::oneshot,
+    task::JoinHandle,
+    time,
 };
 use tokio_util::codec::{Decoder, Encoder, Framed};
-use yew::server::Channel;
+use yew::{
+    protocol::Payload,
+    server::{Channel, Server},
+    transport::{
+        Builder, Certificate, Connection, Key, LongPoll, LongPollServer, Noise, NoisePattern,
+        QuicServer, QuicStream, RekeyPolicy, ReplayCache, Stats, TlsServer, Transport, Users,
+        WebSocket,
+    },
+};
 
 #[tokio::main]
-async fn main() {
+async fn main() -> anyhow::Result<()> {
+    let builder = builder()?;
+    let report = opt("--stats", "YEW_STATS")
+        .map(|s| s.parse().map(Duration::from_secs))
+        .transpose()
+        .context("invalid --stats")?;
+    if report.is_some() {
+        eprintln!("[server] {:?}", builder.rekey_policy());
+        eprintln!(
+            "[server] padding {}, cover {:?}, compression {:?}, format {}",
+            builder.padding_policy(),
+            builder.cover_interval(),
+            builder.compression_level(),
+            builder.message_format()
+        );
+    }
+    if let Some(addr) = builder.fallback_addr() {
+        eprintln!("[server] unauthenticated connections relayed to {}", addr);
+    }
+
+    let certificate = certificate()?;
     // 断网后, 重启前 失效
-    let lst = TcpListener::bind("0.0.0.0:11999").await.unwrap();
-    loop {
-        let (conn, _) = lst.accept().await.unwrap();
+    let addr = opt("--listen", "YEW_LISTEN").unwrap_or_else(|| "0.0.0.0:11999".to_owned());
+    let stdio = flag("--stdio", "YEW_STDIO");
+    // 明文模式不认证 client, 公开监听时等于开放代理
+    if plaintext() && !plaintext_allowed(&addr, stdio) {
+        bail!("--plaintext authenticates no client, use it with --stdio, a unix: or a loopback --listen");
+    }
+    if quic() {
+        if stdio {
+            bail!("--stdio does not go with --quic");
+        }
+        return serve_quic(&addr, builder, certificate, report).await;
+    }
+
+    let tls = match certificate {
+        Some(certificate) => Some(TlsServer::new(certificate).context("invalid certificate")?),
+        None => None,
+    };
+    let websocket = websocket();
+    if let Some(websocket) = &websocket {
+        eprintln!(
+            "[server] websocket on {}, host {}",
+            websocket.path(),
+            websocket.host_name().unwrap_or("any")
+        );
+    }
+
+    let long_poll = long_poll();
+    if let Some(long_poll) = &long_poll {
+        if websocket.is_some() {
+            bail!("--http-path does not go with --ws-path");
+        }
+        if stdio {
+            bail!("--http-path does not go with --stdio");
+        }
+        eprintln!(
+            "[server] long polling on {}, host {}",
+            long_poll.path(),
+            long_poll.host_name().unwrap_or("any")
+        );
+    }
 
+    let layers = Layers {
+        builder,
+        tls,
+        websocket,
+        long_poll: long_poll.map(LongPollServer::new),
+        report,
+    };
+    // 每个 session 如同一个连接
+    if let Some(long_poll) = layers.long_poll.clone() {
+        let layers = layers.clone();
         tokio::spawn(async move {
-            let mut server = yew::server::new::<TcpStream, Request, Response>(conn);
-            loop {
-                match server.accept().await {
-                    Ok(channel) => {
-                        process(channel);
-                    }
-                    Err(_) => {
-                        // println!("[server] close!! {:?}", err);
-                        break;
-                    }
+            while let Some(stream) = long_poll.accept().await {
+                let layers = layers.clone();
+                let peer = format!("http session {}", stream.session());
+                tokio::spawn(async move { layers.tunnel(peer, Box::new(stream)).await });
+            }
+        });
+    }
+
+    // 只服务 stdin/stdout 上的一个连接, 例如由 ssh 启动时
+    if stdio {
+        let conn = tokio::io::join(tokio::io::stdin(), tokio::io::stdout());
+        layers.serve("stdio".to_owned(), Box::new(conn)).await;
+        return Ok(());
+    }
+
+    if let Some(path) = addr.strip_prefix("unix:") {
+        return serve_unix(path, layers).await;
+    }
+
+    let lst = TcpListener::bind(&addr)
+        .await
+        .with_context(|| format!("failed to listen on {}", addr))?;
+    loop {
+        let (conn, peer) = lst.accept().await.unwrap();
+
+        let layers = layers.clone();
+        tokio::spawn(async move { layers.serve(peer.to_string(), Box::new(conn)).await });
+    }
+}
+
+/// Serves the Unix socket at `path`, in place of the socket file a previous
+/// run left.
+#[cfg(unix)]
+async fn serve_unix(path: &str, layers: Layers) -> anyhow::Result<()> {
+    use std::{fs, os::unix::fs::FileTypeExt};
+    use tokio::net::UnixListener;
+
+    // 上次运行留下的 socket 文件
+    if fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        fs::remove_file(path).with_context(|| format!("failed to remove {}", path))?;
+    }
+    let lst =
+        UnixListener::bind(path).with_context(|| format!("failed to listen on unix:{}", path))?;
+    for n in 1.. {
+        let (conn, _) = lst.accept().await?;
+        let layers = layers.clone();
+        let peer = format!("unix:{}#{}", path, n);
+        tokio::spawn(async move { layers.serve(peer, Box::new(conn)).await });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(_: &str, _: Layers) -> anyhow::Result<()> {
+    bail!("unix sockets are not supported on this platform")
+}
+
+/// What every connection over a byte stream goes through.
+#[derive(Clone)]
+struct Layers {
+    builder: Builder,
+    tls: Option<TlsServer>,
+    websocket: Option<WebSocket>,
+    long_poll: Option<LongPollServer>,
+    report: Option<Duration>,
+}
+
+impl Layers {
+    /// Runs the layers and the handshake over `conn` from `peer`, then
+    /// serves its channels. With long polling, `conn` only carries the
+    /// requests of sessions.
+    async fn serve(self, peer: String, conn: Box<dyn Io>) {
+        let conn = match underlay(conn, self.tls.clone(), self.websocket.clone()).await {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("[server] connection from {} failed: {:#}", peer, e);
+                return;
+            }
+        };
+        match &self.long_poll {
+            Some(long_poll) => {
+                if let Err(e) = long_poll.serve(conn).await {
+                    eprintln!("[server] http connection from {} failed: {}", peer, e);
                 }
             }
+            None => self.tunnel(peer, conn).await,
+        }
+    }
+
+    /// Runs the handshake over `conn` from `peer`, then serves its channels.
+    async fn tunnel(self, peer: String, conn: Box<dyn Io>) {
+        match handshake(conn, self.builder).await {
+            Ok(server) => serve(&peer, server, self.report).await,
+            Err(e) => eprintln!("[server] handshake with {} failed: {}", peer, e),
+        }
+    }
+}
+
+/// Serves QUIC on the UDP address `addr`, with a self-signed certificate if
+/// none is configured.
+async fn serve_quic(
+    addr: &str,
+    builder: Builder,
+    certificate: Option<Certificate>,
+    report: Option<Duration>,
+) -> anyhow::Result<()> {
+    if websocket().is_some() {
+        bail!("--ws-path does not go with --quic");
+    }
+    if long_poll().is_some() {
+        bail!("--http-path does not go with --quic");
+    }
+    let certificate = match certificate {
+        Some(certificate) => certificate,
+        None => {
+            let certificate = Certificate::self_signed(&names()).context("invalid --tls-name")?;
+            eprintln!("[server] tls certificate {}", certificate.fingerprint());
+            certificate
+        }
+    };
+    let bind = lookup_host(addr)
+        .await
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .with_context(|| format!("invalid --listen {}", addr))?;
+    let endpoint = QuicServer::bind(bind, certificate)
+        .with_context(|| format!("failed to listen on udp {}", addr))?;
+    eprintln!("[server] quic on udp {}", endpoint.local_addr()?);
 
-            // println!("[server] connection close");
+    while let Some(incoming) = endpoint.accept().await {
+        let builder = builder.clone();
+        tokio::spawn(async move {
+            let peer = incoming.remote_address();
+            let conn = match incoming.await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    eprintln!("[server] connection from {} failed: {}", peer, e);
+                    return;
+                }
+            };
+            match handshake_quic(conn, builder).await {
+                Ok(server) => serve(&peer.to_string(), server, report).await,
+                Err(e) => eprintln!("[server] handshake with {} failed: {}", peer, e),
+            }
         });
     }
+    Ok(())
+}
+
+/// Accepts the channels of `server` until its connection closes.
+async fn serve(peer: &str, mut server: Server<Request, Response>, report: Option<Duration>) {
+    // 记录使用的密钥, 以判断旧密钥何时可以移除
+    if plaintext() {
+        eprintln!("[server] {} connected in plaintext", peer);
+    } else {
+        eprintln!(
+            "[server] {} authenticated as {} with key {}",
+            peer,
+            server.user().unwrap_or("-"),
+            server.key_id().unwrap_or("-")
+        );
+    }
+    let reporter =
+        report.map(|every| self::report(peer.to_string(), server.stats().clone(), every));
+
+    while let Ok(channel) = server.accept().await {
+        process(channel);
+    }
+
+    if let Some(reporter) = reporter {
+        reporter.abort();
+        eprintln!("[server] {} closed, {}", peer, server.stats());
+    }
+    // println!("[server] connection close");
+}
+
+/// Connection under the tunnel, through whichever layers are configured.
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// Runs the TLS handshake and the websocket upgrade, those configured.
+async fn underlay(
+    mut conn: Box<dyn Io>,
+    tls: Option<TlsServer>,
+    websocket: Option<WebSocket>,
+) -> anyhow::Result<Box<dyn Io>> {
+    if let Some(tls) = tls {
+        conn = Box::new(tls.accept(conn).await.context("TLS handshake failed")?);
+    }
+    if let Some(websocket) = websocket {
+        conn = Box::new(
+            websocket
+                .accept(conn)
+                .await
+                .context("websocket upgrade failed")?,
+        );
+    }
+    Ok(conn)
+}
+
+async fn handshake(conn: Box<dyn Io>, builder: Builder) -> io::Result<Server<Request, Response>> {
+    if plaintext() {
+        builder.plaintext(conn).await.map(yew::server::new)
+    } else {
+        yew::server::accept(conn, builder).await
+    }
+}
+
+async fn handshake_quic(
+    conn: Connection,
+    builder: Builder,
+) -> io::Result<Server<Request, Response>> {
+    if plaintext() {
+        let control = builder.plaintext(QuicStream::accept(&conn).await?).await?;
+        Ok(yew::server::new_quic(conn, control, builder))
+    } else {
+        yew::server::accept_quic(conn, builder).await
+    }
+}
+
+fn builder() -> anyhow::Result<Builder> {
+    // 多用户, 使用 Noise 握手或明文时, 共享密钥可选
+    let noise = noise()?;
+    let mut builder = Transport::builder();
+    if let Some(path) = opt("--users", "YEW_USERS") {
+        let users = Users::from_file(&path)
+            .with_context(|| format!("failed to load users file {}", path))?;
+        eprintln!(
+            "[server] {} keys of {} users loaded from {}",
+            users.len(),
+            users.names().len(),
+            path
+        );
+        for (name, key, validity) in users.iter() {
+            let secs = |t: Option<SystemTime>| match t {
+                Some(t) => t
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs())
+                    .to_string(),
+                None => "-".to_owned(),
+            };
+            eprintln!(
+                "[server]   {} key {} valid {} .. {}",
+                name,
+                key.fingerprint(),
+                secs(validity.not_before),
+                secs(validity.not_after)
+            );
+        }
+        builder = builder.users(users);
+        if let Some(key) = find_key()? {
+            builder = builder.key(key);
+        }
+    } else if noise.is_none() && !plaintext() {
+        builder = builder.key(load_key()?);
+    }
+    if let Some(noise) = noise {
+        if noise.pattern().mutual() && opt("--users", "YEW_USERS").is_none() {
+            bail!(
+                "noise {} needs the public keys of the clients, use --users <path>",
+                noise.pattern()
+            );
+        }
+        builder = builder.noise(Some(noise));
+    }
+    if let Some(ciphers) = opt("--ciphers", "YEW_CIPHERS") {
+        let ciphers = ciphers
+            .split(',')
+            .map(str::parse)
+            .collect::<io::Result<_>>();
+        builder = builder.ciphers(ciphers.context("invalid --ciphers")?);
+    }
+
+    // 0 表示不限制
+    let mut rekey = RekeyPolicy::default();
+    if let Some(n) = opt("--rekey-bytes", "YEW_REKEY_BYTES") {
+        rekey.bytes = Some(n.parse().context("invalid --rekey-bytes")?).filter(|&n| n > 0);
+    }
+    if let Some(n) = opt("--rekey-frames", "YEW_REKEY_FRAMES") {
+        rekey.frames = Some(n.parse().context("invalid --rekey-frames")?).filter(|&n| n > 0);
+    }
+    if let Some(n) = opt("--rekey-interval", "YEW_REKEY_INTERVAL") {
+        let secs = n.parse().context("invalid --rekey-interval")?;
+        rekey.interval = Some(Duration::from_secs(secs)).filter(|t| !t.is_zero());
+    }
+    builder = builder.rekey(rekey);
+
+    if let Some(padding) = opt("--padding", "YEW_PADDING") {
+        builder = builder.padding(padding.parse().context("invalid --padding")?);
+    }
+    if let Some(n) = opt("--cover", "YEW_COVER") {
+        let millis = n.parse().context("invalid --cover")?;
+        builder = builder.cover(Some(Duration::from_millis(millis)));
+    }
+    if let Some(n) = opt("--compress", "YEW_COMPRESS") {
+        let level = n.parse().context("invalid --compress")?;
+        builder = builder.compression(Some(level).filter(|&level| level > 0));
+    }
+    if let Some(format) = opt("--format", "YEW_FORMAT") {
+        builder = builder.format(format.parse().context("invalid --format")?);
+    }
+    if let Some(n) = opt("--max-frame", "YEW_MAX_FRAME") {
+        builder = builder.max_frame_len(n.parse().context("invalid --max-frame")?);
+    }
+    if let Some(n) = opt("--max-message", "YEW_MAX_MESSAGE") {
+        builder = builder.max_message_len(n.parse().context("invalid --max-message")?);
+    }
+
+    let window = opt("--replay-window", "YEW_REPLAY_WINDOW")
+        .map(|n| n.parse().map(Duration::from_secs))
+        .transpose()
+        .context("invalid --replay-window")?;
+    let capacity = opt("--replay-capacity", "YEW_REPLAY_CAPACITY")
+        .map(|n| n.parse())
+        .transpose()
+        .context("invalid --replay-capacity")?;
+    if window.is_some() || capacity.is_some() {
+        let default = ReplayCache::default();
+        builder = builder.replay_cache(ReplayCache::new(
+            window.unwrap_or_else(|| default.window()),
+            capacity.unwrap_or_else(|| default.capacity()),
+        ));
+    }
+
+    // 握手失败的连接转发给 fallback, 例如本地 web 服务
+    if let Some(addr) = opt("--fallback", "YEW_FALLBACK") {
+        builder = builder.fallback(Some(addr));
+    }
+    Ok(builder)
+}
+
+/// Loads the Noise handshake from `--noise <nk|xx|ik>` and `--noise-key <path>`,
+/// or `YEW_NOISE` and `YEW_NOISE_KEY`.
+fn noise() -> anyhow::Result<Option<Noise>> {
+    let pattern: NoisePattern = match opt("--noise", "YEW_NOISE") {
+        Some(pattern) => pattern.parse().context("invalid --noise")?,
+        None => return Ok(None),
+    };
+    let path = opt("--noise-key", "YEW_NOISE_KEY").context("--noise needs --noise-key <path>")?;
+    let key = Key::from_file(&path).with_context(|| format!("failed to load key file {}", path))?;
+
+    let noise = Noise::new(pattern, key);
+    eprintln!(
+        "[server] noise {}, public key {}",
+        pattern,
+        noise.public_key().to_base64()
+    );
+    Ok(Some(noise))
+}
+
+/// Prints `stats` every `every` until the returned task is aborted.
+fn report(name: String, stats: Stats, every: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            time::sleep(every).await;
+            eprintln!("[server] {} {}", name, stats);
+        }
+    })
+}
+
+/// Returns the value following `name` on the command line.
+fn arg(name: &str) -> Option<String> {
+    env::args().skip_while(|a| a != name).nth(1)
+}
+
+/// Returns the command line option `name`, falling back to the environment variable `var`.
+fn opt(name: &str, var: &str) -> Option<String> {
+    arg(name).or_else(|| env::var(var).ok())
+}
+
+/// Accepts the tunnel over a WebSocket when `--ws-path` or `YEW_WS_PATH` is
+/// set, only for the `Host` from `--ws-host` or `YEW_WS_HOST` if given.
+fn websocket() -> Option<WebSocket> {
+    let websocket = WebSocket::new(opt("--ws-path", "YEW_WS_PATH")?);
+    match opt("--ws-host", "YEW_WS_HOST") {
+        Some(host) => Some(websocket.host(host)),
+        None => Some(websocket),
+    }
+}
+
+/// Accepts the tunnel over HTTP long polling when `--http-path` or
+/// `YEW_HTTP_PATH` is set, only for the `Host` from `--http-host` or
+/// `YEW_HTTP_HOST` if given.
+fn long_poll() -> Option<LongPoll> {
+    let long_poll = LongPoll::new(opt("--http-path", "YEW_HTTP_PATH")?);
+    match opt("--http-host", "YEW_HTTP_HOST") {
+        Some(host) => Some(long_poll.host(host)),
+        None => Some(long_poll),
+    }
+}
+
+/// Serves the tunnel inside TLS with the certificate of `--tls-cert <path>`
+/// and `--tls-key <path>`, generated self-signed and saved there if neither
+/// exists, or with a self-signed one made at start for `--tls`.
+fn certificate() -> anyhow::Result<Option<Certificate>> {
+    let certificate = match (
+        opt("--tls-cert", "YEW_TLS_CERT"),
+        opt("--tls-key", "YEW_TLS_KEY"),
+    ) {
+        (Some(cert), Some(key)) if !Path::new(&cert).exists() && !Path::new(&key).exists() => {
+            let certificate = Certificate::self_signed(&names()).context("invalid --tls-name")?;
+            certificate
+                .save_pem_files(&cert, &key)
+                .with_context(|| format!("failed to write {} and {}", cert, key))?;
+            eprintln!(
+                "[server] self-signed certificate written to {} and {}",
+                cert, key
+            );
+            certificate
+        }
+        (Some(cert), Some(key)) => Certificate::from_pem_files(&cert, &key)
+            .with_context(|| format!("failed to load certificate {} and key {}", cert, key))?,
+        (None, None) if flag("--tls", "YEW_TLS") => {
+            Certificate::self_signed(&names()).context("invalid --tls-name")?
+        }
+        (None, None) => return Ok(None),
+        _ => bail!("--tls-cert and --tls-key go together"),
+    };
+
+    // client 用 --tls-pin 固定该指纹
+    eprintln!("[server] tls certificate {}", certificate.fingerprint());
+    Ok(Some(certificate))
+}
+
+/// Names of self-signed certificates, those of `--tls-name`, comma
+/// separated, by default `localhost`.
+fn names() -> Vec<String> {
+    let names = opt("--tls-name", "YEW_TLS_NAME").unwrap_or_else(|| "localhost".to_owned());
+    names.split(',').map(|n| n.trim().to_owned()).collect()
+}
+
+/// Whether `--quic` or `YEW_QUIC` is set: the tunnel is served over QUIC on
+/// the UDP port of the listen address, each channel on a stream of its own.
+fn quic() -> bool {
+    flag("--quic", "YEW_QUIC")
+}
+
+/// Whether the flag `name` is on the command line or the environment
+/// variable `var` is set.
+fn flag(name: &str, var: &str) -> bool {
+    env::args().any(|a| a == name) || env::var_os(var).is_some()
+}
+
+/// Whether `--plaintext` or `YEW_PLAINTEXT` is set: frames are sent without
+/// a handshake or encryption, over a link that is already secure.
+fn plaintext() -> bool {
+    flag("--plaintext", "YEW_PLAINTEXT")
+}
+
+/// Whether plaintext may be served on the listen address `addr`: only over
+/// stdio, on a Unix socket or on a loopback address, since anyone who
+/// reaches it gets through. TLS does not count, it authenticates no client.
+fn plaintext_allowed(addr: &str, stdio: bool) -> bool {
+    if stdio || addr.starts_with("unix:") {
+        return true;
+    }
+    match addr.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().is_loopback(),
+        Err(_) => addr
+            .rsplit_once(':')
+            .is_some_and(|(host, _)| host == "localhost"),
+    }
+}
+
+/// Loads the shared tunnel key from `--key-file`, `--key`, `YEW_KEY_FILE` or `YEW_KEY`, in that order.
+fn load_key() -> anyhow::Result<Key> {
+    match find_key()? {
+        Some(key) => Ok(key),
+        None => bail!(
+            "no tunnel key configured, use --key-file <path>, --key <base64>, YEW_KEY_FILE, YEW_KEY or --users <path>"
+        ),
+    }
+}
+
+/// Like [`load_key`], but a missing key is not an error.
+fn find_key() -> anyhow::Result<Option<Key>> {
+    if let Some(path) = arg("--key-file") {
+        let key =
+            Key::from_file(&path).with_context(|| format!("failed to load key file {}", path));
+        return key.map(Some);
+    }
+    if let Some(key) = arg("--key") {
+        return Key::from_base64(&key).context("invalid --key").map(Some);
+    }
+    if let Ok(path) = env::var("YEW_KEY_FILE") {
+        let key =
+            Key::from_file(&path).with_context(|| format!("failed to load key file {}", path));
+        return key.map(Some);
+    }
+    if env::var_os("YEW_KEY").is_some() {
+        return Key::from_env("YEW_KEY")
+            .context("invalid YEW_KEY")
+            .map(Some);
+    }
+    Ok(None)
 }
 
 fn process(mut channel: Channel<Request, Response>) {
@@ -66,13 +632,49 @@ fn process(mut channel: Channel<Request, Response>) {
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub enum Request {
     Connect(String),
-    Data(Vec<u8>),
+    Data(Bytes),
 }
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Response {
-    data: Vec<u8>,
+    data: Bytes,
 }
 
+// 数据以 raw frame 发送, 不经过序列化
+impl Payload for Request {
+    fn into_payload(self) -> Result<Bytes, Self> {
+        match self {
+            Request::Data(data) => Ok(data),
+            request => Err(request),
+        }
+    }
+
+    fn from_payload(payload: Bytes) -> Option<Self> {
+        Some(Request::Data(payload))
+    }
+
+    fn payload_len(&self) -> usize {
+        match self {
+            Request::Connect(addr) => addr.len(),
+            Request::Data(data) => data.len(),
+        }
+    }
+}
+
+impl Payload for Response {
+    fn into_payload(self) -> Result<Bytes, Self> {
+        Ok(self.data)
+    }
+
+    fn from_payload(payload: Bytes) -> Option<Self> {
+        Some(Response { data: payload })
+    }
+
+    fn payload_len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+#[derive(Default)]
 pub struct SimpleServerCodec(());
 
 impl SimpleServerCodec {
@@ -88,7 +690,7 @@ impl Decoder for SimpleServerCodec {
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Response>, io::Error> {
         if !buf.is_empty() {
             Ok(Some(Response {
-                data: buf.split().to_vec(),
+                data: buf.split().freeze(),
             }))
         } else {
             Ok(None)
@@ -102,13 +704,14 @@ impl Encoder<Request> for SimpleServerCodec {
     fn encode(&mut self, data: Request, buf: &mut BytesMut) -> Result<(), io::Error> {
         if let Request::Data(data) = data {
             buf.reserve(data.len());
-            buf.put_slice(data.as_slice());
+            buf.put_slice(&data);
             return Ok(());
         }
-        Err(io::Error::new(io::ErrorKind::Other, "err"))
+        Err(io::Error::other("err"))
     }
 }
 
+#[derive(Default)]
 pub struct SimpleClientCodec(());
 
 impl SimpleClientCodec {
@@ -123,7 +726,7 @@ impl Decoder for SimpleClientCodec {
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Request>, io::Error> {
         if !buf.is_empty() {
-            Ok(Some(Request::Data(buf.split().to_vec())))
+            Ok(Some(Request::Data(buf.split().freeze())))
         } else {
             Ok(None)
         }
@@ -136,7 +739,27 @@ impl Encoder<Response> for SimpleClientCodec {
     fn encode(&mut self, data: Response, buf: &mut BytesMut) -> Result<(), io::Error> {
         let v = data.data;
         buf.reserve(v.len());
-        buf.put_slice(v.as_slice());
+        buf.put_slice(&v);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::plaintext_allowed;
+
+    #[test]
+    fn plaintext_needs_a_private_listener() {
+        assert!(!plaintext_allowed("0.0.0.0:11999", false));
+        assert!(!plaintext_allowed("[::]:11999", false));
+        assert!(!plaintext_allowed("192.168.1.2:11999", false));
+        assert!(!plaintext_allowed("example.com:11999", false));
+        assert!(!plaintext_allowed("localhost.example.com:11999", false));
+
+        assert!(plaintext_allowed("127.0.0.1:11999", false));
+        assert!(plaintext_allowed("[::1]:11999", false));
+        assert!(plaintext_allowed("localhost:11999", false));
+        assert!(plaintext_allowed("unix:/run/yew.sock", false));
+        assert!(plaintext_allowed("0.0.0.0:11999", true));
+    }
+}
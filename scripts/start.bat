@@ -1,3 +1,3 @@
 @ECHO OFF
 %1 start mshta vbscript:createobject("wscript.shell").run("""%~0"" ::",0)(window.close)&&exit
-start /b proxy.exe
\ No newline at end of file
+start /b proxy.exe --key-file yew.key
\ No newline at end of file
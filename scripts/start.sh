@@ -1 +1 @@
-nohup /root/server >/dev/null 2>&1 &
\ No newline at end of file
+nohup /root/server --key-file /root/yew.key >/dev/null 2>&1 &
\ No newline at end of file
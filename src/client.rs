@@ -1,10 +1,15 @@
-use super::{transport::Transport, Request, Response};
+use super::{
+    flow::{cost, no_credit, queue_grant, Consumed, Credit, Route},
+    protocol::{self, Greeting, Payload, ProtocolError, COVER, FLOW, RAW},
+    transport::{Builder, Cover, FrameCodec, Stats, Transport, WithPayload},
+    Request, Response,
+};
 
 use futures::{ready, Future, Sink, Stream};
 use pin_project_lite::pin_project;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     fmt::Debug,
     io,
     option::Option,
@@ -17,49 +22,103 @@ use std::{
 };
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
 };
 
+mod quic;
+pub use quic::{connect_quic, new_quic};
+
 #[derive(Debug)]
 enum Message<Req, Resp> {
     Open {
         id: usize,
         sender: UnboundedSender<Resp>,
+        credit: Arc<Credit>,
     },
     Data {
         id: usize,
         message: Req,
     },
+    /// The channel consumed `credit` bytes of the server.
+    Window {
+        id: usize,
+        credit: u32,
+    },
     Close {
         id: usize,
     },
 }
 
-pub fn new<S, Req, Resp>(io: S) -> Client<Req, Resp>
+/// Runs the dispatcher of `transport`, whose handshake is done, in the
+/// background.
+///
+/// The transport comes from [`Builder::connect`], [`Builder::plaintext`], or
+/// is set up by hand with any [`FrameCodec`]. The dispatcher first exchanges
+/// a [hello](protocol) with the server, if the server turns out to be
+/// incompatible the connection is closed and opening channels fails.
+pub fn new<S, C, Req, Resp>(
+    transport: Transport<S, WithPayload<Response<Resp>>, WithPayload<Request<Req>>, C>,
+) -> Client<Req, Resp>
 where
-    S: AsyncWrite + AsyncRead + Send + 'static,
-    Req: Serialize + Send + 'static,
-    Resp: for<'a> Deserialize<'a> + Send + 'static,
+    S: AsyncWrite + AsyncRead + Unpin + Send + 'static,
+    C: FrameCodec + Send + 'static,
+    Req: Serialize + Payload + Send + 'static,
+    Resp: for<'a> Deserialize<'a> + Payload + Send + 'static,
 {
-    let inner = Transport::from(io);
+    spawn(transport).0
+}
+
+/// Runs the handshake of `builder` over `io`, then the dispatcher, see [`new`].
+///
+/// Returns once the server's hello is received, so that an incompatible
+/// server is reported here.
+pub async fn connect<S, Req, Resp>(io: S, builder: Builder) -> io::Result<Client<Req, Resp>>
+where
+    S: AsyncWrite + AsyncRead + Unpin + Send + 'static,
+    Req: Serialize + Payload + Send + 'static,
+    Resp: for<'a> Deserialize<'a> + Payload + Send + 'static,
+{
+    let (client, done) = spawn(builder.connect(io).await?);
+    protocol::wait(done).await?;
+    Ok(client)
+}
+
+fn spawn<S, C, Req, Resp>(
+    transport: Transport<S, WithPayload<Response<Resp>>, WithPayload<Request<Req>>, C>,
+) -> (
+    Client<Req, Resp>,
+    oneshot::Receiver<Result<u32, ProtocolError>>,
+)
+where
+    S: AsyncWrite + AsyncRead + Unpin + Send + 'static,
+    C: FrameCodec + Send + 'static,
+    Req: Serialize + Payload + Send + 'static,
+    Resp: for<'a> Deserialize<'a> + Payload + Send + 'static,
+{
+    let stats = transport.stats().clone();
+    let cover = transport.cover_interval().map(Cover::new);
+    let (greeting, done) = Greeting::new(transport.format());
 
     let (sender, receiver) = mpsc::unbounded_channel();
 
-    let fut = Dispatchor {
-        inner,
+    tokio::spawn(Dispatchor {
+        inner: transport,
         receiver,
         senders: HashMap::new(),
-    };
-
-    tokio::spawn(async {
-        let _ = fut.await;
-        // println!("[client] connection closed: {:?}", result);
+        grants: VecDeque::new(),
+        greeting,
+        cover,
     });
 
-    Client {
+    let client = Client {
         next_id: Arc::new(AtomicUsize::new(1)),
         sender,
-    }
+        stats,
+    };
+    (client, done)
 }
 
 //
@@ -67,62 +126,190 @@ where
 //
 
 pin_project! {
-    struct Dispatchor<S, Req, Resp> {
+    struct Dispatchor<S, C, Req, Resp> {
         #[pin]
-        inner: Transport<S, Response<Resp>, Request<Req>>,
+        inner: Transport<S, WithPayload<Response<Resp>>, WithPayload<Request<Req>>, C>,
 
         #[pin]
         receiver: UnboundedReceiver<Message<Req, Resp>>,
 
-        senders: HashMap<usize, UnboundedSender<Resp>>,
+        senders: HashMap<usize, Route<Resp>>,
+
+        // 已关闭的 channel 收到的消息, 直接归还给 server
+        grants: VecDeque<(usize, u32)>,
+
+        greeting: Greeting,
+
+        #[pin]
+        cover: Option<Cover>,
     }
 }
 
-impl<S, Req, Resp> Dispatchor<S, Req, Resp>
+impl<S, C, Req, Resp> Dispatchor<S, C, Req, Resp>
 where
     S: AsyncWrite + AsyncRead,
-    Req: Serialize,
-    Resp: for<'a> Deserialize<'a>,
+    C: FrameCodec,
+    Req: Serialize + Payload,
+    Resp: for<'a> Deserialize<'a> + Payload,
 {
     fn read_half(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<()>>> {
-        let p: Poll<Option<Response<Resp>>> = self.as_mut().project().inner.poll_next(cx)?;
-        Poll::Ready(match ready!(p) {
-            Some(response) => {
-                let id = response.id;
-
-                if let Some(tx) = self.as_mut().project().senders.get_mut(&id) {
-                    // channel 可能关闭, 忽略错误
-                    let _ = tx.send(response.message);
+        if self.greeting.sending() {
+            return Poll::Pending;
+        }
+
+        let p: Poll<Option<WithPayload<Response<Resp>>>> =
+            self.as_mut().project().inner.poll_next(cx)?;
+        let (response, payload) = match ready!(p) {
+            Some(WithPayload { message, payload }) => (Some(message), payload),
+            None => (None, Default::default()),
+        };
+
+        // 第一条消息必须是 hello
+        if let Some(response) = &response {
+            let hello = match response {
+                Response::Hello(hello) => Some(hello),
+                _ => None,
+            };
+            if let Err(e) = self.as_mut().project().greeting.receive(hello) {
+                // 尽量让对端收到本端的 hello, 以便报告原因
+                let _ = self.as_mut().project().inner.poll_flush(cx);
+                return Poll::Ready(Some(Err(e.into())));
+            }
+        }
+
+        Poll::Ready(match response {
+            Some(Response::Hello(_)) => {
+                // 旧版本的 server 没有流量控制
+                if !self.greeting.has(FLOW) {
+                    for route in self.as_mut().project().senders.values() {
+                        route.credit.unlimit();
+                    }
+                }
+
+                Some(Ok(()))
+            }
+            Some(Response::Data { id, message }) => {
+                self.as_mut().deliver(id, message)?;
+
+                Some(Ok(()))
+            }
+            Some(Response::Raw { id }) => {
+                let message = Resp::from_payload(payload).ok_or_else(protocol::unexpected_raw)?;
+                self.as_mut().deliver(id, message)?;
+
+                Some(Ok(()))
+            }
+            Some(Response::Window { id, credit }) => {
+                if let Some(route) = self.as_mut().project().senders.get(&id) {
+                    route.credit.grant(credit);
                 }
 
                 Some(Ok(()))
             }
+            Some(Response::Cover) => {
+                self.as_mut()
+                    .project()
+                    .inner
+                    .stats()
+                    .record_cover_received();
+
+                Some(Ok(()))
+            }
             None => None,
         })
     }
 
+    /// Hands `message` to channel `id`, within the window of the server.
+    fn deliver(self: Pin<&mut Self>, id: usize, message: Resp) -> io::Result<()> {
+        let flow = self.greeting.has(FLOW);
+        let this = self.project();
+        match this.senders.get_mut(&id) {
+            Some(route) => {
+                if flow {
+                    route.window.receive(id, cost(&message))?;
+                }
+                // channel 可能关闭, 忽略错误
+                let _ = route.sender.send(message);
+            }
+            None if flow => queue_grant(this.grants, id, cost(&message)),
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn reset_cover(self: Pin<&mut Self>) {
+        if let Some(cover) = self.project().cover.as_pin_mut() {
+            cover.reset();
+        }
+    }
+
     fn write_half(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<()>>> {
-        let mut inner: Pin<&mut Transport<S, Response<Resp>, Request<Req>>> =
-            self.as_mut().project().inner;
+        let mut inner = self.as_mut().project().inner;
 
         //      let a: Poll<()> = inner.as_mut().poll_ready(cx)?;
-        while let Poll::Pending = inner.as_mut().poll_ready(cx)? {
+        while inner.as_mut().poll_ready(cx)?.is_pending() {
             ready!(inner.as_mut().poll_flush(cx)?);
         }
+        // 上次 flush 未完成时先写完, 例如由后台线程写入的 stdout
+        ready!(inner.as_mut().poll_flush(cx)?);
+
+        // 先发送 hello
+        if let Some(hello) = self.as_mut().project().greeting.take_local() {
+            self.as_mut()
+                .project()
+                .inner
+                .start_send(Request::Hello(hello).into())?;
+
+            ready!(self.as_mut().project().inner.poll_flush(cx)?);
+
+            return Poll::Ready(Some(Ok(())));
+        }
+
+        // 空闲时发送 cover frame, 对端支持时
+        let this = self.as_mut().project();
+        if let (true, Some(cover)) = (this.greeting.has(COVER), this.cover.as_pin_mut()) {
+            if cover.poll_due(cx).is_ready() {
+                self.as_mut()
+                    .project()
+                    .inner
+                    .start_send(Request::Cover.into())?;
+                self.as_mut().project().inner.stats().record_cover_sent();
+
+                ready!(self.as_mut().project().inner.poll_flush(cx)?);
+
+                return Poll::Ready(Some(Ok(())));
+            }
+        }
+
+        if let Some((id, credit)) = self.as_mut().project().grants.pop_front() {
+            self.as_mut()
+                .project()
+                .inner
+                .start_send(Request::Window { id, credit }.into())?;
+
+            ready!(self.as_mut().project().inner.poll_flush(cx)?);
+
+            return Poll::Ready(Some(Ok(())));
+        }
 
         let result: Option<Message<Req, Resp>> =
             ready!(self.as_mut().project().receiver.poll_recv(cx));
 
         Poll::Ready(match result {
             Some(request) => match request {
-                Message::Open { id, sender } => {
-                    self.as_mut().project().senders.insert(id, sender);
+                Message::Open { id, sender, credit } => {
+                    if self.greeting.agreed() && !self.greeting.has(FLOW) {
+                        credit.unlimit();
+                    }
+                    let route = Route::new(sender, credit);
+                    self.as_mut().project().senders.insert(id, route);
 
                     // TODO
                     self.as_mut()
                         .project()
                         .inner
-                        .start_send(Request::Open { id })?;
+                        .start_send(Request::Open { id }.into())?;
+                    self.as_mut().reset_cover();
 
                     ready!(self.as_mut().project().inner.poll_flush(cx)?);
 
@@ -130,22 +317,38 @@ where
                 }
                 Message::Data { id, message } => {
                     // let result: () = self.as_mut().project().inner.start_send(Request::Data { id, message })?;
-                    self.as_mut()
-                        .project()
-                        .inner
-                        .start_send(Request::Data { id, message })?;
+                    let item = data(self.greeting.has(RAW), id, message);
+                    self.as_mut().project().inner.start_send(item)?;
+                    self.as_mut().reset_cover();
 
                     ready!(self.as_mut().project().inner.poll_flush(cx)?);
 
                     Some(Ok(()))
                 }
+                Message::Window { id, credit } => {
+                    let flow = self.greeting.has(FLOW);
+                    if let (true, Some(route)) =
+                        (flow, self.as_mut().project().senders.get_mut(&id))
+                    {
+                        route.window.grant(credit);
+                        self.as_mut()
+                            .project()
+                            .inner
+                            .start_send(Request::Window { id, credit }.into())?;
+
+                        ready!(self.as_mut().project().inner.poll_flush(cx)?);
+                    }
+
+                    Some(Ok(()))
+                }
                 Message::Close { id } => {
                     self.as_mut().project().senders.remove(&id);
 
                     self.as_mut()
                         .project()
                         .inner
-                        .start_send(Request::Cancel { id })?;
+                        .start_send(Request::Cancel { id }.into())?;
+                    self.as_mut().reset_cover();
 
                     ready!(self.as_mut().project().inner.poll_flush(cx)?);
 
@@ -157,11 +360,26 @@ where
     }
 }
 
-impl<S, Req, Resp> Future for Dispatchor<S, Req, Resp>
+/// Envelope of channel data, raw when the server can take it.
+fn data<Req: Payload>(raw: bool, id: usize, message: Req) -> WithPayload<Request<Req>> {
+    if !raw {
+        return Request::Data { id, message }.into();
+    }
+    match message.into_payload() {
+        Ok(payload) => WithPayload {
+            message: Request::Raw { id },
+            payload,
+        },
+        Err(message) => Request::Data { id, message }.into(),
+    }
+}
+
+impl<S, C, Req, Resp> Future for Dispatchor<S, C, Req, Resp>
 where
     S: AsyncWrite + AsyncRead,
-    Req: Serialize,
-    Resp: for<'a> Deserialize<'a>,
+    C: FrameCodec,
+    Req: Serialize + Payload,
+    Resp: for<'a> Deserialize<'a> + Payload,
 {
     type Output = anyhow::Result<()>;
 
@@ -176,6 +394,10 @@ where
                     // println!("[client] Read none");
                     return Poll::Ready(Ok(()));
                 }
+                (Poll::Ready(Some(Err(_))), _) => {
+                    // 解密失败 (重放, 乱序, 丢帧), 断开连接
+                    return Poll::Ready(Ok(()));
+                }
                 (read, Poll::Ready(None)) => {
                     // println!("[client] Write none");
                     // println!("[client] is empty? {:?}", self.as_mut().senders.is_empty());
@@ -203,16 +425,22 @@ where
     }
 }
 
-///
-///  Client
-///
+//
+// Client
+//
 
 pub struct Client<Req, Resp> {
     next_id: Arc<AtomicUsize>,                   // new id
     sender: UnboundedSender<Message<Req, Resp>>, // clone on new channel
+    stats: Stats,
 }
 
 impl<Req, Resp> Client<Req, Resp> {
+    /// Traffic counters of the underlying connection.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
     fn next_id(&self) -> usize {
         self.next_id.fetch_add(1, Ordering::Relaxed)
     }
@@ -220,30 +448,40 @@ impl<Req, Resp> Client<Req, Resp> {
     pub fn connect(&mut self) -> io::Result<Channel<Req, Resp>> {
         let id = self.next_id();
         let (sender, receiver) = mpsc::unbounded_channel();
+        let credit = Credit::new();
 
         // open
-        match self.sender.send(Message::Open { id, sender }) {
+        let open = Message::Open {
+            id,
+            sender,
+            credit: credit.clone(),
+        };
+        match self.sender.send(open) {
             Ok(_) => {
                 let sender = self.sender.clone();
                 Ok(Channel {
                     id,
                     sender,
                     receiver,
+                    credit,
+                    consumed: Consumed::default(),
                 })
             }
-            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+            Err(e) => Err(io::Error::other(e.to_string())),
         }
     }
 }
 
-///
-/// Channel
-///
+//
+// Channel
+//
 
 pub struct Channel<Req, Resp> {
     id: usize,
     sender: UnboundedSender<Message<Req, Resp>>, // send to BaseChannel
     receiver: UnboundedReceiver<Resp>,           // receive from BaseChannel
+    credit: Arc<Credit>,                         // bytes that may be sent
+    consumed: Consumed,                          // bytes to grant back
 }
 
 impl<Req, Resp> Drop for Channel<Req, Resp> {
@@ -253,25 +491,33 @@ impl<Req, Resp> Drop for Channel<Req, Resp> {
     }
 }
 
-impl<Req, Resp> Stream for Channel<Req, Resp> {
+impl<Req, Resp: Payload> Stream for Channel<Req, Resp> {
     type Item = io::Result<Resp>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        Poll::Ready(match ready!(self.as_mut().receiver.poll_recv(cx)) {
-            Some(resp) => Some(Ok(resp)),
-            None => None,
-        })
+        let message = ready!(self.as_mut().receiver.poll_recv(cx));
+        if let Some(message) = &message {
+            // 消费了一半窗口时, 允许 server 继续发送
+            if let Some(credit) = self.consumed.add(cost(message)) {
+                let id = self.id;
+                let _ = self.sender.send(Message::Window { id, credit });
+            }
+        }
+        Poll::Ready(message.map(Ok))
     }
 }
 
-impl<Req, Resp> Sink<Req> for Channel<Req, Resp> {
+impl<Req: Payload, Resp> Sink<Req> for Channel<Req, Resp> {
     type Error = io::Error;
 
-    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.credit.poll_ready(cx)
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: Req) -> Result<(), Self::Error> {
+        if !self.credit.take(cost(&item)) {
+            return Err(no_credit());
+        }
         let msg = Message::Data {
             id: self.id,
             message: item,
@@ -280,7 +526,7 @@ impl<Req, Resp> Sink<Req> for Channel<Req, Resp> {
         self.as_mut()
             .sender
             .send(msg)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+            .map_err(|e| io::Error::other(e.to_string()))
     }
 
     fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -291,3 +537,126 @@ impl<Req, Resp> Sink<Req> for Channel<Req, Resp> {
         Poll::Ready(Ok(()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{new, spawn, Client};
+    use crate::{
+        protocol::{self, Hello, ProtocolError, MIN_VERSION, VERSION, WINDOW},
+        server::{self, Server},
+        transport::{Builder, PlainCodec, Transport, WithPayload},
+        Request, Response,
+    };
+    use bytes::Bytes;
+    use futures::{SinkExt, TryStreamExt};
+    use std::time::Duration;
+    use tokio::{io::DuplexStream, time::timeout};
+
+    /// A server driven by hand.
+    type Peer = Transport<
+        DuplexStream,
+        WithPayload<Request<Bytes>>,
+        WithPayload<Response<Bytes>>,
+        PlainCodec,
+    >;
+
+    async fn pair() -> (Client<Bytes, Bytes>, Server<Bytes, Bytes>) {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let builder = Builder::new();
+        let (client, server) =
+            futures::try_join!(builder.plaintext(client), builder.plaintext(server)).unwrap();
+        (new(client), server::new(server))
+    }
+
+    #[tokio::test]
+    async fn sender_waits_for_the_window() {
+        let (mut client, mut server) = pair().await;
+        let mut channel = client.connect().unwrap();
+        let message = Bytes::from(vec![0; 64 * 1024]);
+
+        // 对端不读取时, 发送一个窗口后停下
+        let mut sent = 0;
+        while timeout(Duration::from_millis(200), channel.send(message.clone()))
+            .await
+            .is_ok()
+        {
+            sent += 1;
+            assert!(sent * message.len() <= WINDOW as usize + message.len());
+        }
+        assert!(sent * message.len() >= WINDOW as usize);
+
+        // 对端消费半个窗口后继续
+        let mut accepted = server.accept().await.unwrap();
+        for _ in 0..sent / 2 + 1 {
+            accepted.try_next().await.unwrap().unwrap();
+        }
+        timeout(Duration::from_secs(5), channel.send(message))
+            .await
+            .expect("window was not granted back")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn blocked_sender_fails_when_the_server_goes_away() {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let builder = Builder::new();
+        let (client, mut peer): (_, Peer) =
+            futures::try_join!(builder.plaintext(client), builder.plaintext(server)).unwrap();
+        let mut client: Client<Bytes, Bytes> = new(client);
+        peer.send(Response::Hello(Hello::local()).into())
+            .await
+            .unwrap();
+
+        // 用完窗口
+        let mut channel = client.connect().unwrap();
+        let message = Bytes::from(vec![0; 64 * 1024]);
+        while timeout(Duration::from_millis(200), channel.send(message.clone()))
+            .await
+            .is_ok()
+        {}
+
+        // 连接断开后, 等待额度的发送失败而不是一直等待
+        drop(peer);
+        let result = timeout(Duration::from_secs(5), channel.send(message))
+            .await
+            .expect("blocked send did not fail");
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn incompatible_server_is_reported() {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let builder = Builder::new();
+        let (client, mut peer): (_, Peer) =
+            futures::try_join!(builder.plaintext(client), builder.plaintext(server)).unwrap();
+        let (mut client, done) = spawn::<_, _, Bytes, Bytes>(client);
+        let hello = Hello {
+            version: VERSION + 1,
+            min_version: VERSION + 1,
+            ..Hello::local()
+        };
+        peer.send(Response::Hello(hello).into()).await.unwrap();
+
+        let e = protocol::wait(done).await.unwrap_err();
+        let e = e.into_inner().unwrap().downcast::<ProtocolError>().unwrap();
+        let expected = ProtocolError::Incompatible {
+            local: (MIN_VERSION, VERSION),
+            remote: (VERSION + 1, VERSION + 1),
+        };
+        assert_eq!(*e, expected);
+
+        // server 仍收到 client 的 hello, 之后连接关闭, 无法再打开 channel
+        match peer.try_next().await.unwrap() {
+            Some(WithPayload {
+                message: Request::Hello(hello),
+                ..
+            }) => assert_eq!(hello.version, VERSION),
+            other => panic!("expected a hello, got {:?}", other.map(|m| m.message)),
+        }
+        let closed = async { while let Ok(Some(_)) = peer.try_next().await {} };
+        timeout(Duration::from_secs(5), closed)
+            .await
+            .expect("client kept the connection");
+        assert!(client.connect().is_err());
+    }
+}
@@ -0,0 +1,246 @@
+//! Channels of a [`Client`] on the streams of a QUIC connection.
+
+use super::{data, spawn, Client, Message};
+use crate::{
+    flow::{cost, CloseOnDrop, Credit},
+    protocol::{self, Payload, ProtocolError, RAW},
+    transport::{Builder, Connection, FrameCodec, QuicStream, Stats, Transport, WithPayload},
+    Request, Response,
+};
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    sync::{atomic::AtomicUsize, Arc},
+};
+use tokio::{
+    select,
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::JoinSet,
+};
+
+/// Runs the handshake of `builder` over the first stream of `connection`,
+/// then the dispatcher, see [`new_quic`].
+///
+/// Returns once the server's hello is received, so that an incompatible
+/// server is reported here.
+pub async fn connect_quic<Req, Resp>(
+    connection: Connection,
+    builder: Builder,
+) -> io::Result<Client<Req, Resp>>
+where
+    Req: Serialize + Payload + Send + 'static,
+    Resp: for<'a> Deserialize<'a> + Payload + Send + 'static,
+{
+    let control = builder
+        .connect(QuicStream::open(&connection).await?)
+        .await?;
+    let (client, done) = spawn_quic(connection, control, builder);
+    protocol::wait(done).await?;
+    Ok(client)
+}
+
+/// Runs the dispatcher of `connection` in the background, with `control`
+/// over its first stream, whose handshake is done.
+///
+/// The hello is exchanged over `control`, which then only carries cover
+/// messages and closes the connection when it ends. Each channel opens a
+/// stream of its own, its messages framed in plaintext as the format and
+/// limits of `builder` say, QUIC already encrypting them.
+pub fn new_quic<C, Req, Resp>(
+    connection: Connection,
+    control: Transport<QuicStream, WithPayload<Response<Resp>>, WithPayload<Request<Req>>, C>,
+    builder: Builder,
+) -> Client<Req, Resp>
+where
+    C: FrameCodec + Send + 'static,
+    Req: Serialize + Payload + Send + 'static,
+    Resp: for<'a> Deserialize<'a> + Payload + Send + 'static,
+{
+    spawn_quic(connection, control, builder).0
+}
+
+fn spawn_quic<C, Req, Resp>(
+    connection: Connection,
+    control: Transport<QuicStream, WithPayload<Response<Resp>>, WithPayload<Request<Req>>, C>,
+    builder: Builder,
+) -> (
+    Client<Req, Resp>,
+    oneshot::Receiver<Result<u32, ProtocolError>>,
+)
+where
+    C: FrameCodec + Send + 'static,
+    Req: Serialize + Payload + Send + 'static,
+    Resp: for<'a> Deserialize<'a> + Payload + Send + 'static,
+{
+    let (control, greeting) = spawn(control);
+    let stats = control.stats.clone();
+    let (done, receiver_done) = oneshot::channel();
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        // 先完成 hello, 才知道能否使用 raw
+        let capabilities = match greeting.await {
+            Ok(result) => {
+                let _ = done.send(result.clone());
+                result.ok()
+            }
+            Err(_) => None,
+        };
+        if let Some(capabilities) = capabilities {
+            let dispatchor = Dispatchor {
+                connection: connection.clone(),
+                builder,
+                stats: control.stats.clone(),
+                raw: capabilities & RAW != 0,
+            };
+            dispatchor.run(receiver, &control).await;
+        }
+        connection.close(0u32.into(), b"");
+    });
+
+    let client = Client {
+        next_id: Arc::new(AtomicUsize::new(1)),
+        sender,
+        stats,
+    };
+    (client, receiver_done)
+}
+
+struct Dispatchor {
+    connection: Connection,
+    builder: Builder,
+    stats: Stats,
+    raw: bool,
+}
+
+impl Dispatchor {
+    /// Hands the messages of the channels to their streams until the client
+    /// and its channels are dropped, or `control` or the connection ends.
+    async fn run<Req, Resp>(
+        &self,
+        mut receiver: UnboundedReceiver<Message<Req, Resp>>,
+        control: &Client<Req, Resp>,
+    ) where
+        Req: Serialize + Payload + Send + 'static,
+        Resp: for<'a> Deserialize<'a> + Payload + Send + 'static,
+    {
+        // 每个 channel 的发送队列, 以及 server 还可以发来的消息数
+        let mut senders: HashMap<usize, (UnboundedSender<Req>, Arc<Credit>)> = HashMap::new();
+        let mut streams = JoinSet::new();
+        loop {
+            let message = select! {
+                message = receiver.recv() => message,
+                Some(_) = streams.join_next() => continue,
+                _ = control.sender.closed() => return,
+                _ = self.connection.closed() => return,
+            };
+            match message {
+                Some(Message::Open { id, sender, credit }) => {
+                    let (tx, rx) = mpsc::unbounded_channel();
+                    let window = Credit::new();
+                    senders.insert(id, (tx, window.clone()));
+                    let stream = self.stream(id, sender, rx, credit, window);
+                    streams.spawn(async move {
+                        // 单个 channel 出错不影响其他 channel
+                        let _ = stream.await;
+                    });
+                }
+                Some(Message::Data { id, message }) => {
+                    if let Some((tx, _)) = senders.get(&id) {
+                        let _ = tx.send(message);
+                    }
+                }
+                Some(Message::Window { id, credit }) => {
+                    if let Some((_, window)) = senders.get(&id) {
+                        window.grant(credit);
+                    }
+                }
+                Some(Message::Close { id }) => {
+                    senders.remove(&id);
+                }
+                None => {
+                    // 各 stream 发送完剩余的数据再关闭连接
+                    drop(senders);
+                    while streams.join_next().await.is_some() {}
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Opens the stream of channel `id` and relays its messages in both
+    /// directions, until the channel is closed.
+    ///
+    /// The channel gets `credit` back as its messages are written, and the
+    /// stream is only read while the channel has not consumed `window`,
+    /// leaving the backpressure to QUIC.
+    fn stream<Req, Resp>(
+        &self,
+        id: usize,
+        sender: UnboundedSender<Resp>,
+        mut receiver: UnboundedReceiver<Req>,
+        credit: Arc<Credit>,
+        window: Arc<Credit>,
+    ) -> impl std::future::Future<Output = io::Result<()>>
+    where
+        Req: Serialize + Payload + Send + 'static,
+        Resp: for<'a> Deserialize<'a> + Payload + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        let builder = self.builder.clone();
+        let stats = self.stats.clone();
+        let raw = self.raw;
+
+        async move {
+            // 无论 stream 如何结束, channel 都不再等待额度
+            let _closed = CloseOnDrop(credit.clone());
+            let stream = QuicStream::open(&connection).await?;
+            let transport: Transport<_, WithPayload<Response<Resp>>, WithPayload<Request<Req>>, _> =
+                builder.framed(stream, stats);
+            let (mut sink, mut stream) = transport.split();
+
+            // server 收到 open 才能看到这个 stream
+            sink.send(Request::Open { id }.into()).await?;
+
+            let read = async move {
+                while let Some(WithPayload { message, payload }) = stream.try_next().await? {
+                    let message = match message {
+                        Response::Data { message, .. } => message,
+                        Response::Raw { .. } => {
+                            Resp::from_payload(payload).ok_or_else(protocol::unexpected_raw)?
+                        }
+                        Response::Hello(_) | Response::Cover | Response::Window { .. } => continue,
+                    };
+                    window.acquire(cost(&message)).await?;
+                    if sender.send(message).is_err() {
+                        break;
+                    }
+                }
+                Ok::<_, io::Error>(())
+            };
+            let write = async move {
+                while let Some(message) = receiver.recv().await {
+                    let spent = cost(&message);
+                    sink.send(data(raw, id, message)).await?;
+                    credit.grant(spent);
+                }
+                sink.close().await
+            };
+
+            // channel 关闭后不再读取; server 关闭时仍发送剩余的数据
+            tokio::pin!(write);
+            select! {
+                result = read => {
+                    result?;
+                    write.await
+                }
+                result = &mut write => result,
+            }
+        }
+    }
+}
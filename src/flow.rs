@@ -0,0 +1,222 @@
+//! Credits of channels, see [`FLOW`](crate::protocol::FLOW).
+//!
+//! Only channels are bounded: each buffers at most a window and a message,
+//! but nothing limits how many a peer opens, so the memory of a connection
+//! grows with the number of its open channels.
+
+use crate::protocol::{Payload, WINDOW};
+use futures::future::poll_fn;
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    io,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Bytes a channel may still send, shared by the channel and whoever grants
+/// more: the dispatcher on window updates of the peer, or the task of a
+/// QUIC stream as it writes them.
+///
+/// A message is sent while any credit is left and may overdraw it, so a
+/// message larger than the window still goes through once the peer caught
+/// up. Only one task waits for credit at a time.
+#[derive(Debug)]
+pub(crate) struct Credit {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    credit: i64,
+    /// The peer has no flow control, credit is never short.
+    unlimited: bool,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+impl Credit {
+    /// Credit of a new channel, the initial window.
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(Credit {
+            state: Mutex::new(State {
+                credit: WINDOW.into(),
+                unlimited: false,
+                closed: false,
+                waker: None,
+            }),
+        })
+    }
+
+    /// Ready once a message may be sent, an error once the channel is
+    /// closed.
+    pub(crate) fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if state.unlimited || state.credit > 0 {
+            return Poll::Ready(Ok(()));
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Spends `cost`, `false` if there is no credit left.
+    pub(crate) fn take(&self, cost: u32) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.unlimited {
+            return true;
+        }
+        if state.credit <= 0 {
+            return false;
+        }
+        state.credit -= i64::from(cost);
+        true
+    }
+
+    /// Waits for credit and spends `cost`.
+    pub(crate) async fn acquire(&self, cost: u32) -> io::Result<()> {
+        poll_fn(|cx| self.poll_ready(cx)).await?;
+        self.take(cost);
+        Ok(())
+    }
+
+    /// Adds `n` bytes of credit.
+    pub(crate) fn grant(&self, n: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.credit = state.credit.saturating_add(n.into());
+        wake(state);
+    }
+
+    /// Lifts the limit, the peer predating flow control.
+    pub(crate) fn unlimit(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.unlimited = true;
+        wake(state);
+    }
+
+    /// Fails the waiting and further sending, the peer closed the channel.
+    pub(crate) fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        wake(state);
+    }
+}
+
+fn wake(mut state: std::sync::MutexGuard<'_, State>) {
+    let waker = state.waker.take();
+    drop(state);
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+/// Sending without waiting for `poll_ready` of the channel's `Sink`.
+pub(crate) fn no_credit() -> io::Error {
+    io::Error::other("channel has no credit, poll_ready first")
+}
+
+/// What a message costs against the window: its payload and a fixed
+/// overhead, so that messages without payload are limited too.
+pub(crate) fn cost<T: Payload>(message: &T) -> u32 {
+    const OVERHEAD: u32 = 64;
+    let len = u32::try_from(message.payload_len()).unwrap_or(u32::MAX);
+    len.saturating_add(OVERHEAD)
+}
+
+/// Counts the bytes a channel consumes, to grant them back to the peer half
+/// a window at a time.
+#[derive(Debug, Default)]
+pub(crate) struct Consumed(u32);
+
+impl Consumed {
+    /// Counts a message of `cost`, returning the credit to grant if it is
+    /// time.
+    pub(crate) fn add(&mut self, cost: u32) -> Option<u32> {
+        self.0 = self.0.saturating_add(cost);
+        if self.0 < WINDOW / 2 {
+            return None;
+        }
+        Some(std::mem::take(&mut self.0))
+    }
+}
+
+/// Bytes the peer may still send to a channel, the dispatcher closing the
+/// connection of a peer that sends more. Mirrors the [`Credit`] of the peer,
+/// which may overdraw by one message.
+#[derive(Debug)]
+pub(crate) struct Window(i64);
+
+impl Default for Window {
+    fn default() -> Self {
+        Window(WINDOW.into())
+    }
+}
+
+impl Window {
+    /// Counts a message of `cost` received for channel `id`.
+    pub(crate) fn receive(&mut self, id: usize, cost: u32) -> io::Result<()> {
+        if self.0 <= 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("peer exceeded the window of channel {}", id),
+            ));
+        }
+        self.0 -= i64::from(cost);
+        Ok(())
+    }
+
+    /// Adds the credit granted to the peer.
+    pub(crate) fn grant(&mut self, n: u32) {
+        self.0 = self.0.saturating_add(n.into());
+    }
+}
+
+/// What a dispatcher keeps of an open channel. Dropping it closes the
+/// credit, so the channel stops waiting once the dispatcher forgets it or
+/// ends with the connection.
+#[derive(Debug)]
+pub(crate) struct Route<T> {
+    /// Where the messages of the peer go.
+    pub(crate) sender: UnboundedSender<T>,
+    pub(crate) credit: Arc<Credit>,
+    pub(crate) window: Window,
+}
+
+impl<T> Route<T> {
+    pub(crate) fn new(sender: UnboundedSender<T>, credit: Arc<Credit>) -> Self {
+        Route {
+            sender,
+            credit,
+            window: Window::default(),
+        }
+    }
+}
+
+impl<T> Drop for Route<T> {
+    fn drop(&mut self) {
+        self.credit.close();
+    }
+}
+
+/// Closes a credit when dropped, held by the task of a QUIC stream so that
+/// the channel stops waiting however the task ends.
+#[derive(Debug)]
+pub(crate) struct CloseOnDrop(pub(crate) Arc<Credit>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/// Queues a grant of `credit` to channel `id`, merged with the last one if
+/// it is for the same channel.
+pub(crate) fn queue_grant(grants: &mut VecDeque<(usize, u32)>, id: usize, credit: u32) {
+    match grants.back_mut() {
+        Some((last, n)) if *last == id => *n = n.saturating_add(credit),
+        _ => grants.push_back((id, credit)),
+    }
+}
@@ -1,18 +1,62 @@
 pub mod transport;
 
 pub mod client;
+mod flow;
+pub mod protocol;
 pub mod server;
 pub mod socks;
+#[cfg(test)]
+mod testing;
 
+/// Envelope of the messages a client sends over a
+/// [`Transport`](transport::Transport), one per channel event.
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
-enum Request<T> {
-    Open { id: usize },
-    Data { id: usize, message: T },
-    Cancel { id: usize },
+pub enum Request<T> {
+    /// First message, see [`protocol`].
+    Hello(protocol::Hello),
+    Open {
+        id: usize,
+    },
+    Data {
+        id: usize,
+        message: T,
+    },
+    Cancel {
+        id: usize,
+    },
+    /// Cover traffic, discarded by the server.
+    Cover,
+    /// Data of channel `id`, whose bytes follow the envelope in the frame,
+    /// see [`Payload`](protocol::Payload).
+    Raw {
+        id: usize,
+    },
+    /// The client consumed `credit` bytes of channel `id`, the server may
+    /// send as many more, see [`FLOW`](protocol::FLOW).
+    Window {
+        id: usize,
+        credit: u32,
+    },
 }
 
+/// Envelope of the messages a server sends back.
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
-struct Response<T> {
-    id: usize,
-    message: T,
+pub enum Response<T> {
+    /// First message, see [`protocol`].
+    Hello(protocol::Hello),
+    Data {
+        id: usize,
+        message: T,
+    },
+    /// Cover traffic, discarded by the client.
+    Cover,
+    /// Data of channel `id`, whose bytes follow the envelope in the frame.
+    Raw {
+        id: usize,
+    },
+    /// The server consumed `credit` bytes of channel `id`.
+    Window {
+        id: usize,
+        credit: u32,
+    },
 }
@@ -0,0 +1,338 @@
+//! Hello exchanged by the dispatchers before any channel message.
+//!
+//! Each side sends a [`Hello`] as its first [`Request`](crate::Request) or
+//! [`Response`](crate::Response) without waiting for the other one, and
+//! expects one as the first message it receives. The two sides are compatible
+//! when their ranges of protocol versions overlap, features are only used
+//! when both advertise the capability.
+//!
+//! With the [`RAW`] capability, channel data of [`Payload`] messages skips
+//! serialization. It is not advertised with [`Format::Json`], so that each
+//! frame stays a single JSON document a peer in any language can parse.
+//! With the [`FLOW`] capability, each side of a channel sends at most
+//! [`WINDOW`] bytes the other side has not consumed yet.
+
+use crate::transport::Format;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{error, fmt, io};
+use tokio::sync::oneshot;
+
+/// Marks the hello of a yew peer.
+pub const MAGIC: [u8; 4] = *b"yew\0";
+
+/// Protocol version spoken by this side.
+pub const VERSION: u16 = 1;
+
+/// Oldest protocol version this side still speaks.
+pub const MIN_VERSION: u16 = 1;
+
+/// Capability: cover messages are understood and discarded.
+pub const COVER: u32 = 0x01;
+
+/// Capability: channel data may be sent raw after its envelope, see
+/// [`Payload`].
+pub const RAW: u32 = 0x02;
+
+/// Capability: channels have credit-based flow control. Each side may send
+/// [`WINDOW`] bytes of a channel at first, and sends a `Window` update as it
+/// consumes those of the peer, granting it as many more. A message costs its
+/// [`payload_len`](Payload::payload_len) plus a fixed overhead, and may be
+/// sent while any credit is left, so a channel buffers at most a window and
+/// one message. A peer that exceeds its window is disconnected.
+pub const FLOW: u32 = 0x04;
+
+/// Bytes of a channel a side may send before the peer grants more, see
+/// [`FLOW`].
+pub const WINDOW: u32 = 1 << 20;
+
+/// Capabilities of this side.
+pub const CAPABILITIES: u32 = COVER | RAW | FLOW;
+
+/// Capabilities of this side when messages are serialized as `format`.
+pub fn capabilities(format: Format) -> u32 {
+    match format {
+        // 原始数据跟在 JSON 之后, 帧就不再是一个 JSON 文档
+        Format::Json => CAPABILITIES & !RAW,
+        Format::Bincode => CAPABILITIES,
+    }
+}
+
+/// First message of each side.
+///
+/// It stays the first variant of `Request` and `Response` with this layout in
+/// every version, so that any two versions can read each other's hello.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    pub magic: [u8; 4],
+    pub version: u16,
+    pub min_version: u16,
+    pub capabilities: u32,
+}
+
+impl Hello {
+    /// Hello of this side.
+    pub fn local() -> Self {
+        Hello {
+            magic: MAGIC,
+            version: VERSION,
+            min_version: MIN_VERSION,
+            capabilities: CAPABILITIES,
+        }
+    }
+
+    /// Checks the `peer`'s hello, returning the capabilities both sides have.
+    pub fn agree(&self, peer: &Hello) -> Result<u32, ProtocolError> {
+        if peer.magic != MAGIC {
+            return Err(ProtocolError::NotYew);
+        }
+        if peer.version < self.min_version || self.version < peer.min_version {
+            return Err(ProtocolError::Incompatible {
+                local: (self.min_version, self.version),
+                remote: (peer.min_version, peer.version),
+            });
+        }
+        Ok(self.capabilities & peer.capabilities)
+    }
+}
+
+/// Channel messages that may travel as raw bytes, skipping serialization.
+///
+/// When both sides have the [`RAW`] capability, a message for which
+/// [`into_payload`](Payload::into_payload) returns bytes is sent as a
+/// `Raw` envelope followed by those bytes, and rebuilt by the peer with
+/// [`from_payload`](Payload::from_payload). Otherwise it is serialized as
+/// usual. The defaults serialize every message, `impl Payload for T {}` is
+/// enough for types that carry no bulk data.
+pub trait Payload: Sized {
+    /// The bytes `self` consists of, or `self` back if it has to be
+    /// serialized.
+    fn into_payload(self) -> Result<Bytes, Self> {
+        Err(self)
+    }
+
+    /// Message made of `payload`, `None` if this type has none such.
+    fn from_payload(payload: Bytes) -> Option<Self> {
+        let _ = payload;
+        None
+    }
+
+    /// Bytes of bulk data in `self`, counted against the window of its
+    /// channel, see [`FLOW`].
+    fn payload_len(&self) -> usize {
+        0
+    }
+}
+
+impl Payload for Bytes {
+    fn into_payload(self) -> Result<Bytes, Self> {
+        Ok(self)
+    }
+
+    fn from_payload(payload: Bytes) -> Option<Self> {
+        Some(payload)
+    }
+
+    fn payload_len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl Payload for Vec<u8> {
+    fn into_payload(self) -> Result<Bytes, Self> {
+        Ok(self.into())
+    }
+
+    fn from_payload(payload: Bytes) -> Option<Self> {
+        Some(payload.to_vec())
+    }
+
+    fn payload_len(&self) -> usize {
+        self.len()
+    }
+}
+
+/// Raw data for a channel whose messages cannot be made of bytes.
+pub(crate) fn unexpected_raw() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "peer sent raw data for messages that cannot carry it",
+    )
+}
+
+/// Errors of the hello exchange.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The first message of the peer is not a hello, it predates protocol
+    /// versions.
+    MissingHello,
+    /// The peer's hello lacks the [`MAGIC`], it is not a yew peer.
+    NotYew,
+    /// The ranges of protocol versions, oldest and newest, do not overlap.
+    Incompatible {
+        local: (u16, u16),
+        remote: (u16, u16),
+    },
+    /// The peer sent a second hello.
+    UnexpectedHello,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::MissingHello => {
+                f.write_str("peer sent no hello, it speaks an older protocol")
+            }
+            ProtocolError::NotYew => f.write_str("peer hello has no yew magic"),
+            ProtocolError::Incompatible { local, remote } => write!(
+                f,
+                "peer speaks protocol versions {} to {}, this side {} to {}",
+                remote.0, remote.1, local.0, local.1
+            ),
+            ProtocolError::UnexpectedHello => f.write_str("peer sent a second hello"),
+        }
+    }
+}
+
+impl error::Error for ProtocolError {}
+
+impl From<ProtocolError> for io::Error {
+    fn from(e: ProtocolError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Hello exchange of one dispatcher.
+pub(crate) struct Greeting {
+    /// Hello of this side.
+    hello: Hello,
+    /// Hello still to send.
+    local: Option<Hello>,
+    /// Capabilities both sides have, `None` until the peer's hello.
+    agreed: Option<u32>,
+    /// Told the outcome of the exchange.
+    done: Option<oneshot::Sender<Result<u32, ProtocolError>>>,
+}
+
+impl Greeting {
+    /// Exchange for a transport serializing messages as `format`.
+    pub(crate) fn new(format: Format) -> (Self, oneshot::Receiver<Result<u32, ProtocolError>>) {
+        let (done, receiver) = oneshot::channel();
+        let hello = Hello {
+            capabilities: capabilities(format),
+            ..Hello::local()
+        };
+        let greeting = Greeting {
+            hello,
+            local: Some(hello),
+            agreed: None,
+            done: Some(done),
+        };
+        (greeting, receiver)
+    }
+
+    /// Takes the hello to send first.
+    pub(crate) fn take_local(&mut self) -> Option<Hello> {
+        self.local.take()
+    }
+
+    /// Whether the hello of this side is still to be sent. Nothing is read
+    /// before, so that a peer this side rejects still gets its hello and can
+    /// report why.
+    pub(crate) fn sending(&self) -> bool {
+        self.local.is_some()
+    }
+
+    /// Checks a received message, `hello` if it is one.
+    pub(crate) fn receive(&mut self, hello: Option<&Hello>) -> Result<(), ProtocolError> {
+        let result = match (self.agreed, hello) {
+            (Some(_), None) => return Ok(()),
+            (Some(_), Some(_)) => Err(ProtocolError::UnexpectedHello),
+            (None, None) => Err(ProtocolError::MissingHello),
+            (None, Some(peer)) => self.hello.agree(peer),
+        };
+        if let Some(done) = self.done.take() {
+            let _ = done.send(result.clone());
+        }
+        self.agreed = Some(result?);
+        Ok(())
+    }
+
+    /// Whether the peer's hello is received and agreed to.
+    pub(crate) fn agreed(&self) -> bool {
+        self.agreed.is_some()
+    }
+
+    /// Whether both sides have `capability`, `false` before the peer's hello.
+    pub(crate) fn has(&self, capability: u32) -> bool {
+        self.agreed.is_some_and(|agreed| agreed & capability != 0)
+    }
+}
+
+/// Waits for the outcome of the hello exchange of a dispatcher.
+pub(crate) async fn wait(done: oneshot::Receiver<Result<u32, ProtocolError>>) -> io::Result<u32> {
+    match done.await {
+        Ok(result) => result.map_err(io::Error::from),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed before the peer's hello",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(min_version: u16, version: u16, capabilities: u32) -> Hello {
+        Hello {
+            magic: MAGIC,
+            version,
+            min_version,
+            capabilities,
+        }
+    }
+
+    #[test]
+    fn overlapping_versions_agree_on_common_capabilities() {
+        let local = hello(1, 3, COVER | RAW | FLOW);
+        for peer in [
+            hello(1, 1, COVER),
+            hello(3, 5, COVER | FLOW),
+            hello(2, 2, 0),
+        ] {
+            let agreed = local.agree(&peer).unwrap();
+            assert_eq!(agreed, local.capabilities & peer.capabilities);
+            assert_eq!(peer.agree(&local), Ok(agreed));
+        }
+    }
+
+    #[test]
+    fn disjoint_versions_are_incompatible() {
+        let local = hello(2, 3, CAPABILITIES);
+        for peer in [hello(1, 1, CAPABILITIES), hello(4, 6, CAPABILITIES)] {
+            let e = local.agree(&peer).unwrap_err();
+            assert_eq!(
+                e,
+                ProtocolError::Incompatible {
+                    local: (2, 3),
+                    remote: (peer.min_version, peer.version),
+                }
+            );
+            let expected = format!(
+                "peer speaks protocol versions {} to {}, this side 2 to 3",
+                peer.min_version, peer.version
+            );
+            assert_eq!(e.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn hello_without_magic_is_rejected() {
+        let peer = Hello {
+            magic: *b"http",
+            ..Hello::local()
+        };
+        assert_eq!(Hello::local().agree(&peer), Err(ProtocolError::NotYew));
+    }
+}
@@ -1,80 +1,222 @@
-use super::transport::Transport;
+use super::flow::{cost, no_credit, queue_grant, Consumed, Credit, Route};
+use super::protocol::{self, Greeting, Payload, ProtocolError, COVER, FLOW, RAW};
+use super::transport::{Builder, Cover, FrameCodec, Stats, Transport, WithPayload};
 use super::Request;
 use super::Response;
 use futures::{ready, Future, Sink, Stream};
 use pin_project_lite::pin_project;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     io,
     option::Option,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
 };
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
 };
 
+mod quic;
+pub use quic::{accept_quic, new_quic};
+
 #[derive(Debug)]
 enum Message<Resp> {
-    Data { id: usize, message: Resp },
-    Close { id: usize },
+    Data {
+        id: usize,
+        message: Resp,
+    },
+    /// The channel consumed `credit` bytes of the client.
+    Window {
+        id: usize,
+        credit: u32,
+    },
+    Close {
+        id: usize,
+    },
 }
 
-pub fn new<S, Req, Resp>(io: S) -> Server<Req, Resp>
+/// A channel opened by the client: its id, messages and credit.
+type Accepted<Req> = (usize, UnboundedReceiver<Request<Req>>, Arc<Credit>);
+
+/// Runs the dispatcher of `transport`, whose handshake is done, in the
+/// background.
+///
+/// The transport comes from [`Builder::accept`], [`Builder::plaintext`], or
+/// is set up by hand with any [`FrameCodec`]. The dispatcher first exchanges
+/// a [hello](protocol) with the client, if the client turns out to be
+/// incompatible the connection is closed and no channel is accepted.
+pub fn new<S, C, Req, Resp>(
+    transport: Transport<S, WithPayload<Request<Req>>, WithPayload<Response<Resp>>, C>,
+) -> Server<Req, Resp>
 where
-    S: AsyncWrite + AsyncRead + Send + 'static,
-    Req: for<'a> Deserialize<'a> + Send + 'static,
-    Resp: Serialize + Send + 'static,
+    S: AsyncWrite + AsyncRead + Unpin + Send + 'static,
+    C: FrameCodec + Send + 'static,
+    Req: for<'a> Deserialize<'a> + Payload + Send + 'static,
+    Resp: Serialize + Payload + Send + 'static,
 {
-    let (accept_sender, accept_receiver) = mpsc::unbounded_channel();
+    spawn(transport).0
+}
 
-    let inner = Transport::from(io);
+/// Runs the handshake of `builder` over `io`, then the dispatcher, see [`new`].
+///
+/// Returns once the client's hello is received, so that an incompatible
+/// client is reported here.
+pub async fn accept<S, Req, Resp>(io: S, builder: Builder) -> io::Result<Server<Req, Resp>>
+where
+    S: AsyncWrite + AsyncRead + Unpin + Send + 'static,
+    Req: for<'a> Deserialize<'a> + Payload + Send + 'static,
+    Resp: Serialize + Payload + Send + 'static,
+{
+    let (server, done) = spawn(builder.accept(io).await?);
+    protocol::wait(done).await?;
+    Ok(server)
+}
+
+fn spawn<S, C, Req, Resp>(
+    transport: Transport<S, WithPayload<Request<Req>>, WithPayload<Response<Resp>>, C>,
+) -> (
+    Server<Req, Resp>,
+    oneshot::Receiver<Result<u32, ProtocolError>>,
+)
+where
+    S: AsyncWrite + AsyncRead + Unpin + Send + 'static,
+    C: FrameCodec + Send + 'static,
+    Req: for<'a> Deserialize<'a> + Payload + Send + 'static,
+    Resp: Serialize + Payload + Send + 'static,
+{
+    let stats = transport.stats().clone();
+    let identity = Arc::new(Identity::of(&transport));
+    let cover = transport.cover_interval().map(Cover::new);
+    let (greeting, done) = Greeting::new(transport.format());
+
+    let (accept_sender, accept_receiver) = mpsc::unbounded_channel();
 
     let (sender, receiver) = mpsc::unbounded_channel();
 
-    let fut = Dispatchor {
-        inner,
+    tokio::spawn(Dispatchor {
+        inner: transport,
         receiver,
         senders: HashMap::new(),
+        grants: VecDeque::new(),
         accept_sender,
-    };
-
-    tokio::spawn(async move {
-        let _ = fut.await;
-        // println!("[server] dispatcher closed");
+        greeting,
+        cover,
     });
 
-    Server {
+    let server = Server {
         sender,
         accept_receiver,
-    }
+        stats,
+        identity,
+    };
+    (server, done)
 }
 
 pin_project! {
-    struct Dispatchor<S, Req, Resp> {
+    struct Dispatchor<S, C, Req, Resp> {
         #[pin]
-        inner: Transport<S, Request<Req>, Response<Resp>>,
+        inner: Transport<S, WithPayload<Request<Req>>, WithPayload<Response<Resp>>, C>,
 
         #[pin]
         receiver: UnboundedReceiver<Message<Resp>>,
 
-        senders: HashMap<usize, UnboundedSender<Request<Req>>>,
+        senders: HashMap<usize, Route<Request<Req>>>,
+
+        // 已关闭的 channel 收到的消息, 直接归还给 client
+        grants: VecDeque<(usize, u32)>,
+
+        accept_sender: UnboundedSender<Accepted<Req>>,
 
-        accept_sender: UnboundedSender<(usize, UnboundedReceiver<Request<Req>>)>
+        greeting: Greeting,
+
+        #[pin]
+        cover: Option<Cover>,
     }
 }
 
-impl<S, Req, Resp> Dispatchor<S, Req, Resp>
+impl<S, C, Req, Resp> Dispatchor<S, C, Req, Resp>
 where
     S: AsyncWrite + AsyncRead,
-    Req: for<'a> Deserialize<'a>,
-    Resp: Serialize,
+    C: FrameCodec,
+    Req: for<'a> Deserialize<'a> + Payload,
+    Resp: Serialize + Payload,
 {
+    /// Hands `message` to channel `id`, within the window of the client.
+    fn deliver(self: Pin<&mut Self>, id: usize, message: Req) -> io::Result<()> {
+        let flow = self.greeting.has(FLOW);
+        let this = self.project();
+        match this.senders.get_mut(&id) {
+            Some(route) => {
+                if flow {
+                    route.window.receive(id, cost(&message))?;
+                }
+                route
+                    .sender
+                    .send(Request::Data { id, message })
+                    .map_err(|e| io::Error::other(e.to_string()))?;
+            }
+            None if flow => queue_grant(this.grants, id, cost(&message)),
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn reset_cover(self: Pin<&mut Self>) {
+        if let Some(cover) = self.project().cover.as_pin_mut() {
+            cover.reset();
+        }
+    }
+
     fn write_half(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<()>>> {
-        while let Poll::Pending = self.as_mut().project().inner.poll_ready(cx)? {
+        while self.as_mut().project().inner.poll_ready(cx)?.is_pending() {
+            ready!(self.as_mut().project().inner.poll_flush(cx)?);
+        }
+        // 上次 flush 未完成时先写完, 例如由后台线程写入的 stdout
+        ready!(self.as_mut().project().inner.poll_flush(cx)?);
+
+        // 先发送 hello
+        if let Some(hello) = self.as_mut().project().greeting.take_local() {
+            self.as_mut()
+                .project()
+                .inner
+                .start_send(Response::Hello(hello).into())?;
+
+            ready!(self.as_mut().project().inner.poll_flush(cx)?);
+
+            return Poll::Ready(Some(Ok(())));
+        }
+
+        // 空闲时发送 cover frame, 对端支持时
+        let this = self.as_mut().project();
+        if let (true, Some(cover)) = (this.greeting.has(COVER), this.cover.as_pin_mut()) {
+            if cover.poll_due(cx).is_ready() {
+                self.as_mut()
+                    .project()
+                    .inner
+                    .start_send(Response::Cover.into())?;
+                self.as_mut().project().inner.stats().record_cover_sent();
+
+                ready!(self.as_mut().project().inner.poll_flush(cx)?);
+
+                return Poll::Ready(Some(Ok(())));
+            }
+        }
+
+        if let Some((id, credit)) = self.as_mut().project().grants.pop_front() {
+            self.as_mut()
+                .project()
+                .inner
+                .start_send(Response::Window { id, credit }.into())?;
+
             ready!(self.as_mut().project().inner.poll_flush(cx)?);
+
+            return Poll::Ready(Some(Ok(())));
         }
 
         let result: Option<Message<Resp>> = ready!(self.as_mut().project().receiver.poll_recv(cx));
@@ -82,15 +224,30 @@ where
         Poll::Ready(match result {
             Some(msg) => match msg {
                 Message::Data { id, message } => {
-                    self.as_mut()
-                        .project()
-                        .inner
-                        .start_send(Response { id, message })?;
+                    let item = data(self.greeting.has(RAW), id, message);
+                    self.as_mut().project().inner.start_send(item)?;
+                    self.as_mut().reset_cover();
 
                     ready!(self.as_mut().project().inner.poll_flush(cx)?);
 
                     Some(Ok(()))
                 }
+                Message::Window { id, credit } => {
+                    let flow = self.greeting.has(FLOW);
+                    if let (true, Some(route)) =
+                        (flow, self.as_mut().project().senders.get_mut(&id))
+                    {
+                        route.window.grant(credit);
+                        self.as_mut()
+                            .project()
+                            .inner
+                            .start_send(Response::Window { id, credit }.into())?;
+
+                        ready!(self.as_mut().project().inner.poll_flush(cx)?);
+                    }
+
+                    Some(Ok(()))
+                }
                 Message::Close { id } => {
                     self.as_mut().project().senders.remove(&id);
 
@@ -102,46 +259,90 @@ where
     }
 
     fn read_half(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<()>>> {
-        let result: Option<Request<Req>> = ready!(self.as_mut().project().inner.poll_next(cx)?);
+        if self.greeting.sending() {
+            return Poll::Pending;
+        }
+
+        let (result, payload) = match ready!(self.as_mut().project().inner.poll_next(cx)?) {
+            Some(WithPayload { message, payload }) => (Some(message), payload),
+            None => (None, Default::default()),
+        };
+
+        // 第一条消息必须是 hello
+        if let Some(request) = &result {
+            let hello = match request {
+                Request::Hello(hello) => Some(hello),
+                _ => None,
+            };
+            if let Err(e) = self.as_mut().project().greeting.receive(hello) {
+                // 尽量让对端收到本端的 hello, 以便报告原因
+                let _ = self.as_mut().project().inner.poll_flush(cx);
+                return Poll::Ready(Some(Err(e.into())));
+            }
+        }
 
         Poll::Ready(match result {
             Some(request) => {
                 match request {
+                    Request::Hello(_) => {}
                     Request::Open { id } => {
                         let (sender, receiver) = mpsc::unbounded_channel();
+                        // 旧版本的 client 没有流量控制
+                        let credit = Credit::new();
+                        if !self.greeting.has(FLOW) {
+                            credit.unlimit();
+                        }
 
-                        self.as_mut().project().senders.insert(id, sender);
+                        let route = Route::new(sender, credit.clone());
+                        self.as_mut().project().senders.insert(id, route);
 
                         self.as_mut()
                             .project()
                             .accept_sender
-                            .send((id, receiver))
-                            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                            .send((id, receiver, credit))
+                            .map_err(|e| io::Error::other(e.to_string()))?;
                     }
                     Request::Data { id, message } => {
-                        if let Some(tx) = self.as_mut().project().senders.get_mut(&id) {
-                            tx.send(Request::Data { id, message })
-                                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-                        }
+                        self.as_mut().deliver(id, message)?;
+                    }
+                    Request::Raw { id } => {
+                        let message =
+                            Req::from_payload(payload).ok_or_else(protocol::unexpected_raw)?;
+                        self.as_mut().deliver(id, message)?;
                     }
                     Request::Cancel { id } => {
-                        let sender: Option<UnboundedSender<Request<Req>>> =
+                        let route: Option<Route<Request<Req>>> =
                             self.as_mut().project().senders.remove(&id);
-                        if let Some(tx) = sender {
-                            tx.send(Request::Cancel { id })
-                                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                        // client 不再接收, route 释放时 channel 的发送随之失败
+                        if let Some(route) = route {
+                            route
+                                .sender
+                                .send(Request::Cancel { id })
+                                .map_err(|e| io::Error::other(e.to_string()))?;
                         }
                     }
+                    Request::Window { id, credit } => {
+                        if let Some(route) = self.as_mut().project().senders.get(&id) {
+                            route.credit.grant(credit);
+                        }
+                    }
+                    Request::Cover => {
+                        self.as_mut()
+                            .project()
+                            .inner
+                            .stats()
+                            .record_cover_received();
+                    }
                 };
                 Some(Ok(()))
             }
             None => {
-                let senders: &mut HashMap<usize, UnboundedSender<Request<Req>>> =
+                let senders: &mut HashMap<usize, Route<Request<Req>>> =
                     self.as_mut().project().senders;
 
                 let drain = senders.drain();
-                for (id, sender) in drain {
-                    let _ = sender.send(Request::Cancel { id });
+                for (id, route) in drain {
+                    let _ = route.sender.send(Request::Cancel { id });
                 }
 
                 None
@@ -150,11 +351,26 @@ where
     }
 }
 
-impl<S, Req, Resp> Future for Dispatchor<S, Req, Resp>
+/// Envelope of channel data, raw when the client can take it.
+fn data<Resp: Payload>(raw: bool, id: usize, message: Resp) -> WithPayload<Response<Resp>> {
+    if !raw {
+        return Response::Data { id, message }.into();
+    }
+    match message.into_payload() {
+        Ok(payload) => WithPayload {
+            message: Response::Raw { id },
+            payload,
+        },
+        Err(message) => Response::Data { id, message }.into(),
+    }
+}
+
+impl<S, C, Req, Resp> Future for Dispatchor<S, C, Req, Resp>
 where
     S: AsyncWrite + AsyncRead,
-    Req: for<'a> Deserialize<'a>,
-    Resp: Serialize,
+    C: FrameCodec,
+    Req: for<'a> Deserialize<'a> + Payload,
+    Resp: Serialize + Payload,
 {
     type Output = anyhow::Result<()>;
 
@@ -208,24 +424,67 @@ where
     }
 }
 
+/// Who the client authenticated as.
+#[derive(Debug)]
+struct Identity {
+    user: Option<String>,
+    key_id: Option<String>,
+}
+
+impl Identity {
+    fn of<S, Item, SinkItem, C>(transport: &Transport<S, Item, SinkItem, C>) -> Self
+    where
+        S: AsyncWrite + AsyncRead,
+        C: FrameCodec,
+    {
+        Identity {
+            user: transport.user().map(str::to_owned),
+            key_id: transport.key_id().map(str::to_owned),
+        }
+    }
+}
+
 pub struct Server<Req, Resp> {
     sender: UnboundedSender<Message<Resp>>,
-    accept_receiver: UnboundedReceiver<(usize, UnboundedReceiver<Request<Req>>)>,
+    accept_receiver: UnboundedReceiver<Accepted<Req>>,
+    stats: Stats,
+    identity: Arc<Identity>,
 }
 
 impl<Req, Resp> Server<Req, Resp> {
+    /// Traffic counters of the underlying connection.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// User the client authenticated as, `None` if the client used the shared
+    /// key or there was no handshake.
+    pub fn user(&self) -> Option<&str> {
+        self.identity.user.as_deref()
+    }
+
+    /// [Fingerprint](crate::transport::Key::fingerprint) of the key the client
+    /// used, `None` if the client has no key, as with Noise NK, or there was
+    /// no handshake.
+    pub fn key_id(&self) -> Option<&str> {
+        self.identity.key_id.as_deref()
+    }
+
     pub async fn accept(&mut self) -> io::Result<Channel<Req, Resp>> {
-        if let Some((id, receiver)) = self.accept_receiver.recv().await {
+        if let Some((id, receiver, credit)) = self.accept_receiver.recv().await {
             let ch = Channel {
                 id,
                 sender: self.sender.clone(),
                 receiver,
+                identity: self.identity.clone(),
+                credit,
+                consumed: Consumed::default(),
             };
 
             return Ok(ch);
         }
 
-        Err(io::Error::new(io::ErrorKind::Other, "closed"))
+        Err(io::Error::other("closed"))
     }
 }
 
@@ -233,12 +492,20 @@ pub struct Channel<Req, Resp> {
     id: usize,
     sender: UnboundedSender<Message<Resp>>,
     receiver: UnboundedReceiver<Request<Req>>,
+    identity: Arc<Identity>,
+    credit: Arc<Credit>,
+    consumed: Consumed,
 }
 
 impl<Req, Resp> Channel<Req, Resp> {
     pub fn get_id(&self) -> usize {
         self.id
     }
+
+    /// User of the connection this channel belongs to.
+    pub fn user(&self) -> Option<&str> {
+        self.identity.user.as_deref()
+    }
 }
 
 impl<Req, Resp> Drop for Channel<Req, Resp> {
@@ -248,14 +515,24 @@ impl<Req, Resp> Drop for Channel<Req, Resp> {
     }
 }
 
-impl<Req, Resp> Stream for Channel<Req, Resp> {
+impl<Req: Payload, Resp> Stream for Channel<Req, Resp> {
     type Item = io::Result<Req>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         Poll::Ready(match ready!(self.as_mut().receiver.poll_recv(cx)) {
             Some(request) => match request {
-                Request::Open { id: _ } => unreachable!(),
-                Request::Data { id: _, message } => Some(Ok(message)),
+                Request::Hello(_)
+                | Request::Open { id: _ }
+                | Request::Cover
+                | Request::Raw { id: _ }
+                | Request::Window { .. } => unreachable!(),
+                Request::Data { id, message } => {
+                    // 消费了一半窗口时, 允许 client 继续发送
+                    if let Some(credit) = self.consumed.add(cost(&message)) {
+                        let _ = self.sender.send(Message::Window { id, credit });
+                    }
+                    Some(Ok(message))
+                }
                 Request::Cancel { id: _ } => None,
             },
             None => None,
@@ -263,14 +540,17 @@ impl<Req, Resp> Stream for Channel<Req, Resp> {
     }
 }
 
-impl<Req, Resp> Sink<Resp> for Channel<Req, Resp> {
+impl<Req, Resp: Payload> Sink<Resp> for Channel<Req, Resp> {
     type Error = io::Error;
 
-    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.credit.poll_ready(cx)
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: Resp) -> Result<(), Self::Error> {
+        if !self.credit.take(cost(&item)) {
+            return Err(no_credit());
+        }
         let msg = Message::Data {
             id: self.id,
             message: item,
@@ -279,7 +559,7 @@ impl<Req, Resp> Sink<Resp> for Channel<Req, Resp> {
         self.as_mut()
             .sender
             .send(msg)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+            .map_err(|e| io::Error::other(e.to_string()))
     }
 
     fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -290,3 +570,93 @@ impl<Req, Resp> Sink<Resp> for Channel<Req, Resp> {
         Poll::Ready(Ok(()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{new, Server};
+    use crate::{
+        protocol::{Hello, WINDOW},
+        transport::{Builder, PlainCodec, Transport, WithPayload},
+        Request, Response,
+    };
+    use bytes::Bytes;
+    use futures::{SinkExt, TryStreamExt};
+    use std::time::Duration;
+    use tokio::{io::DuplexStream, time::timeout};
+
+    type Peer = Transport<
+        DuplexStream,
+        WithPayload<Response<Bytes>>,
+        WithPayload<Request<Bytes>>,
+        PlainCodec,
+    >;
+
+    /// A server and a client that is driven by hand, past its hello.
+    async fn pair() -> (Peer, Server<Bytes, Bytes>) {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let builder = Builder::new();
+        let (mut peer, transport) =
+            futures::try_join!(builder.plaintext(client), builder.plaintext(server)).unwrap();
+        let server = new(transport);
+        peer.send(Request::Hello(Hello::local()).into())
+            .await
+            .unwrap();
+        match peer.try_next().await.unwrap() {
+            Some(WithPayload {
+                message: Response::Hello(_),
+                ..
+            }) => {}
+            other => panic!("expected a hello, got {:?}", other.map(|m| m.message)),
+        }
+        (peer, server)
+    }
+
+    /// Waits for the server to close the connection, ignoring what it sends
+    /// before.
+    async fn disconnected(peer: &mut Peer) {
+        let closed = async { while let Ok(Some(_)) = peer.try_next().await {} };
+        timeout(Duration::from_secs(5), closed)
+            .await
+            .expect("server kept the connection");
+    }
+
+    #[tokio::test]
+    async fn peer_exceeding_its_window_is_disconnected() {
+        let (mut peer, _server) = pair().await;
+        peer.send(Request::Open { id: 1 }.into()).await.unwrap();
+
+        // 没有人读取 channel, server 不会归还额度
+        let message = Bytes::from(vec![0; 64 * 1024]);
+        let count = WINDOW as usize / message.len() + 2;
+        for _ in 0..count {
+            let data = Request::Data {
+                id: 1,
+                message: message.clone(),
+            };
+            if peer.send(data.into()).await.is_err() {
+                break;
+            }
+        }
+        disconnected(&mut peer).await;
+    }
+
+    #[tokio::test]
+    async fn peer_within_its_window_is_served() {
+        let (mut peer, mut server) = pair().await;
+        peer.send(Request::Open { id: 1 }.into()).await.unwrap();
+
+        let message = Bytes::from(vec![0; 64 * 1024]);
+        let count = WINDOW as usize / message.len();
+        for _ in 0..count {
+            let data = Request::Data {
+                id: 1,
+                message: message.clone(),
+            };
+            peer.send(data.into()).await.unwrap();
+        }
+        let mut channel = server.accept().await.unwrap();
+        for _ in 0..count {
+            assert_eq!(channel.try_next().await.unwrap(), Some(message.clone()));
+        }
+    }
+}
@@ -0,0 +1,270 @@
+//! Channels of a [`Server`] on the streams of a QUIC connection.
+
+use super::{data, spawn, Accepted, Message, Server};
+use crate::{
+    flow::{cost, CloseOnDrop, Credit},
+    protocol::{self, Payload, ProtocolError, RAW},
+    transport::{Builder, Connection, FrameCodec, QuicStream, Stats, Transport, WithPayload},
+    Request, Response,
+};
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, io, sync::Arc};
+use tokio::{
+    select,
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::JoinSet,
+};
+
+/// Runs the handshake of `builder` over the first stream of `connection`,
+/// then the dispatcher, see [`new_quic`].
+///
+/// Returns once the client's hello is received, so that an incompatible
+/// client is reported here. There is no [fallback](Builder::fallback), a
+/// client that fails the handshake already has a QUIC connection.
+pub async fn accept_quic<Req, Resp>(
+    connection: Connection,
+    builder: Builder,
+) -> io::Result<Server<Req, Resp>>
+where
+    Req: for<'a> Deserialize<'a> + Payload + Send + 'static,
+    Resp: Serialize + Payload + Send + 'static,
+{
+    let builder = builder.fallback(None);
+    let control = builder
+        .accept(QuicStream::accept(&connection).await?)
+        .await?;
+    let (server, done) = spawn_quic(connection, control, builder);
+    protocol::wait(done).await?;
+    Ok(server)
+}
+
+/// Runs the dispatcher of `connection` in the background, with `control`
+/// over the first stream the client opened, whose handshake is done.
+///
+/// Streams are only accepted once the hello over `control` is exchanged,
+/// each becomes a channel. The messages of channels are framed in plaintext
+/// as the format and limits of `builder` say, QUIC already encrypting them.
+/// [`Channel::get_id`](super::Channel::get_id) is the index of its stream.
+pub fn new_quic<C, Req, Resp>(
+    connection: Connection,
+    control: Transport<QuicStream, WithPayload<Request<Req>>, WithPayload<Response<Resp>>, C>,
+    builder: Builder,
+) -> Server<Req, Resp>
+where
+    C: FrameCodec + Send + 'static,
+    Req: for<'a> Deserialize<'a> + Payload + Send + 'static,
+    Resp: Serialize + Payload + Send + 'static,
+{
+    spawn_quic(connection, control, builder).0
+}
+
+fn spawn_quic<C, Req, Resp>(
+    connection: Connection,
+    control: Transport<QuicStream, WithPayload<Request<Req>>, WithPayload<Response<Resp>>, C>,
+    builder: Builder,
+) -> (
+    Server<Req, Resp>,
+    oneshot::Receiver<Result<u32, ProtocolError>>,
+)
+where
+    C: FrameCodec + Send + 'static,
+    Req: for<'a> Deserialize<'a> + Payload + Send + 'static,
+    Resp: Serialize + Payload + Send + 'static,
+{
+    let (control, greeting) = spawn(control);
+    let (done, receiver_done) = oneshot::channel();
+    let (accept_sender, accept_receiver) = mpsc::unbounded_channel();
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    let server = Server {
+        sender,
+        accept_receiver,
+        stats: control.stats.clone(),
+        identity: control.identity.clone(),
+    };
+
+    tokio::spawn(async move {
+        // hello 完成之前不接受 stream
+        let capabilities = match greeting.await {
+            Ok(result) => {
+                let _ = done.send(result.clone());
+                result.ok()
+            }
+            Err(_) => None,
+        };
+        if let Some(capabilities) = capabilities {
+            let dispatchor = Dispatchor {
+                connection: connection.clone(),
+                builder,
+                stats: control.stats.clone(),
+                raw: capabilities & RAW != 0,
+            };
+            dispatchor.run(receiver, accept_sender, &control).await;
+        }
+        connection.close(0u32.into(), b"");
+    });
+
+    (server, receiver_done)
+}
+
+struct Dispatchor {
+    connection: Connection,
+    builder: Builder,
+    stats: Stats,
+    raw: bool,
+}
+
+impl Dispatchor {
+    /// Accepts the streams of the client and hands the messages of the
+    /// channels to them, until the server and its channels are dropped, or
+    /// `control` or the connection ends.
+    async fn run<Req, Resp>(
+        &self,
+        mut receiver: UnboundedReceiver<Message<Resp>>,
+        accept_sender: UnboundedSender<Accepted<Req>>,
+        control: &Server<Req, Resp>,
+    ) where
+        Req: for<'a> Deserialize<'a> + Payload + Send + 'static,
+        Resp: Serialize + Payload + Send + 'static,
+    {
+        // 每个 channel 的发送队列, 以及 client 还可以发来的消息数
+        let mut senders: HashMap<usize, (UnboundedSender<Resp>, Arc<Credit>)> = HashMap::new();
+        let mut streams = JoinSet::new();
+        loop {
+            select! {
+                stream = QuicStream::accept(&self.connection) => {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(_) => return,
+                    };
+                    let id = stream.index() as usize;
+                    let (tx, rx) = mpsc::unbounded_channel();
+                    let window = Credit::new();
+                    senders.insert(id, (tx, window.clone()));
+                    let stream = self.stream(stream, id, accept_sender.clone(), rx, window);
+                    streams.spawn(async move {
+                        // 单个 channel 出错不影响其他 channel
+                        let _ = stream.await;
+                    });
+                }
+                message = receiver.recv() => match message {
+                    Some(Message::Data { id, message }) => {
+                        if let Some((tx, _)) = senders.get(&id) {
+                            let _ = tx.send(message);
+                        }
+                    }
+                    Some(Message::Window { id, credit }) => {
+                        if let Some((_, window)) = senders.get(&id) {
+                            window.grant(credit);
+                        }
+                    }
+                    Some(Message::Close { id }) => {
+                        senders.remove(&id);
+                    }
+                    None => {
+                        // 各 stream 发送完剩余的数据再关闭连接
+                        drop(senders);
+                        while streams.join_next().await.is_some() {}
+                        return;
+                    }
+                },
+                Some(_) = streams.join_next() => {}
+                _ = control.sender.closed() => return,
+            }
+        }
+    }
+
+    /// Reads the open of channel `id` from `stream`, then accepts the channel
+    /// and relays its messages in both directions, until it is closed.
+    ///
+    /// Credit is granted to the channel and read from `window` as with the
+    /// client, see [`client::new_quic`](crate::client::new_quic).
+    fn stream<Req, Resp>(
+        &self,
+        stream: QuicStream,
+        id: usize,
+        accept_sender: UnboundedSender<Accepted<Req>>,
+        mut receiver: UnboundedReceiver<Resp>,
+        window: Arc<Credit>,
+    ) -> impl std::future::Future<Output = io::Result<()>>
+    where
+        Req: for<'a> Deserialize<'a> + Payload + Send + 'static,
+        Resp: Serialize + Payload + Send + 'static,
+    {
+        let transport: Transport<_, WithPayload<Request<Req>>, WithPayload<Response<Resp>>, _> =
+            self.builder.framed(stream, self.stats.clone());
+        let raw = self.raw;
+
+        async move {
+            let (mut sink, mut stream) = transport.split();
+            match stream.try_next().await? {
+                Some(WithPayload {
+                    message: Request::Open { .. },
+                    ..
+                }) => {}
+                Some(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "stream does not start with an open",
+                    ))
+                }
+                None => return Ok(()),
+            }
+
+            let (sender, requests) = mpsc::unbounded_channel();
+            let credit = Credit::new();
+            accept_sender
+                .send((id, requests, credit.clone()))
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            // 无论 stream 如何结束, channel 都不再等待额度
+            let _closed = CloseOnDrop(credit.clone());
+
+            let closed = credit.clone();
+            let read = async move {
+                while let Some(WithPayload { message, payload }) = stream.try_next().await? {
+                    let message = match message {
+                        Request::Data { message, .. } => message,
+                        Request::Raw { .. } => {
+                            Req::from_payload(payload).ok_or_else(protocol::unexpected_raw)?
+                        }
+                        Request::Cancel { .. } => break,
+                        Request::Hello(_)
+                        | Request::Open { .. }
+                        | Request::Cover
+                        | Request::Window { .. } => continue,
+                    };
+                    window.acquire(cost(&message)).await?;
+                    if sender.send(Request::Data { id, message }).is_err() {
+                        break;
+                    }
+                }
+                // channel 的 stream 随之结束, 不再发送新的消息
+                closed.close();
+                let _ = sender.send(Request::Cancel { id });
+                Ok::<_, io::Error>(())
+            };
+            let write = async move {
+                while let Some(message) = receiver.recv().await {
+                    let spent = cost(&message);
+                    sink.send(data(raw, id, message)).await?;
+                    credit.grant(spent);
+                }
+                sink.close().await
+            };
+
+            // channel 关闭后不再读取; client 关闭时仍发送剩余的数据
+            tokio::pin!(write);
+            select! {
+                result = read => {
+                    result?;
+                    write.await
+                }
+                result = &mut write => result,
+            }
+        }
+    }
+}
@@ -9,42 +9,42 @@
         let mut buf = [0];
 
         // version
-        socket.read(&mut buf).await.unwrap();
+        socket.read_exact(&mut buf).await.unwrap();
         if buf[0] != v5::VERSION {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, ""));
+            return Err(std::io::Error::other(""));
         }
 
         // methods
-        socket.read(&mut buf).await.unwrap();
+        socket.read_exact(&mut buf).await.unwrap();
         let mut methods = vec![0; buf[0] as usize];
         socket.read_exact(&mut methods).await.unwrap();
         if !methods.contains(&v5::METH_NO_AUTH) {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, ""));
+            return Err(std::io::Error::other(""));
         }
 
         // [ varify username/password result ]
         socket
-            .write(&[v5::VERSION, v5::METH_NO_AUTH])
+            .write_all(&[v5::VERSION, v5::METH_NO_AUTH])
             .await
             .unwrap();
 
         // ack
-        socket.read(&mut buf).await.unwrap();
+        socket.read_exact(&mut buf).await.unwrap();
         if buf[0] != v5::VERSION {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, ""));
+            return Err(std::io::Error::other(""));
         }
 
         // cmd
-        socket.read(&mut buf).await.unwrap();
+        socket.read_exact(&mut buf).await.unwrap();
         if buf[0] != v5::CMD_CONNECT {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, ""));
+            return Err(std::io::Error::other(""));
         }
 
         // ignore
-        socket.read(&mut buf).await.unwrap();
+        socket.read_exact(&mut buf).await.unwrap();
 
         // host port
-        socket.read(&mut buf).await.unwrap();
+        socket.read_exact(&mut buf).await.unwrap();
 
         let ret;
         match buf[0] {
@@ -74,7 +74,7 @@
                 ret = (host, port);
             }
             v5::ATYP_DOMAIN => {
-                socket.read(&mut buf).await.unwrap();
+                socket.read_exact(&mut buf).await.unwrap();
                 let mut bytes = vec![0; buf[0] as usize];
                 socket.read_exact(&mut bytes).await.unwrap();
                 let host = String::from_utf8(bytes).unwrap();
@@ -85,10 +85,10 @@
 
                 ret = (host, port);
             }
-            _ => return Err(std::io::Error::new(std::io::ErrorKind::Other, "")),
+            _ => return Err(std::io::Error::other("")),
         }
         socket
-            .write_all(&mut [5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
             .await
             .unwrap();
 
@@ -0,0 +1,67 @@
+//! Helpers shared by the loopback tests of the transports.
+
+use crate::{
+    client::Client,
+    server::Server,
+    transport::{Builder, Key},
+};
+use bytes::Bytes;
+use futures::{SinkExt, TryStreamExt};
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// A builder with the key both ends of a test share.
+pub(crate) fn builder() -> Builder {
+    Builder::new().key(Key::new(&[7; 32]).unwrap())
+}
+
+/// Channels [`exchange`] opens.
+const CHANNELS: usize = 3;
+
+/// Opens a few channels over `client`, sends a short and a long message
+/// on each that `server` echoes back, then closes them and checks that the
+/// server sees them end.
+pub(crate) async fn exchange(client: &mut Client<Bytes, Bytes>, mut server: Server<Bytes, Bytes>) {
+    let echo = tokio::spawn(async move {
+        let mut echoes = Vec::new();
+        for _ in 0..CHANNELS {
+            let mut channel = server.accept().await.unwrap();
+            echoes.push(tokio::spawn(async move {
+                while let Some(message) = channel.try_next().await.unwrap() {
+                    channel.send(message).await.unwrap();
+                }
+            }));
+        }
+        // 每个 channel 都随 client 关闭而结束
+        for echo in echoes {
+            echo.await.unwrap();
+        }
+        server
+    });
+
+    let exchange = async {
+        let mut channels: Vec<_> = (0..CHANNELS).map(|_| client.connect().unwrap()).collect();
+        let messages: Vec<_> = (0..CHANNELS)
+            .map(|i| {
+                let short = Bytes::from(format!("channel {}", i));
+                let long = Bytes::from(vec![i as u8; 200 * 1024]);
+                [short, long]
+            })
+            .collect();
+        for (channel, messages) in channels.iter_mut().zip(&messages) {
+            for message in messages {
+                channel.send(message.clone()).await.unwrap();
+            }
+        }
+        for (channel, messages) in channels.iter_mut().zip(messages) {
+            for message in messages {
+                assert_eq!(channel.try_next().await.unwrap(), Some(message));
+            }
+        }
+        drop(channels);
+        echo.await.unwrap()
+    };
+    timeout(Duration::from_secs(10), exchange)
+        .await
+        .expect("exchange timed out");
+}
@@ -0,0 +1,423 @@
+use super::{
+    fallback::{self, Recorder},
+    handshake, noise, Cipher, Format, Key, Noise, Padding, PlainCodec, RekeyPolicy, ReplayCache,
+    Role, SafeCodec, Stats, Transport, Users, MAX_FRAME_LEN,
+};
+use std::{
+    io,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time,
+};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Configuration shared by every connection a [`Transport`] is built for.
+#[derive(Clone, Debug)]
+pub struct Builder {
+    key: Option<Key>,
+    users: Option<Arc<Users>>,
+    ciphers: Vec<Cipher>,
+    rekey: RekeyPolicy,
+    padding: Padding,
+    cover: Option<Duration>,
+    replay: Arc<ReplayCache>,
+    max_frame_len: usize,
+    max_message_len: u64,
+    fallback: Option<String>,
+    noise: Option<Noise>,
+    compression: Option<u32>,
+    format: Format,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            key: None,
+            users: None,
+            ciphers: Cipher::ALL.to_vec(),
+            rekey: RekeyPolicy::default(),
+            padding: Padding::None,
+            cover: None,
+            replay: Arc::default(),
+            max_frame_len: MAX_FRAME_LEN,
+            max_message_len: MAX_FRAME_LEN as u64,
+            fallback: None,
+            noise: None,
+            compression: None,
+            format: Format::default(),
+        }
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the pre-shared tunnel key.
+    pub fn key(mut self, key: Key) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Sets the users a server accepts, each with their own keys.
+    ///
+    /// Only the keys valid at the time of a handshake are tried. A key set with
+    /// [`key`](Self::key) is still accepted next to them, for clients that
+    /// belong to no user. With [`noise`](Self::noise) XX and IK, the keys of
+    /// users are the static public keys of their clients.
+    pub fn users(mut self, users: Users) -> Self {
+        self.users = Some(Arc::new(users));
+        self
+    }
+
+    /// Sets the enabled ciphers in order of preference.
+    ///
+    /// The client offers them in this order, the server picks the first of its
+    /// own list that the client offers.
+    pub fn ciphers(mut self, ciphers: Vec<Cipher>) -> Self {
+        self.ciphers = ciphers;
+        self
+    }
+
+    /// Sets when each side replaces its sending key.
+    pub fn rekey(mut self, policy: RekeyPolicy) -> Self {
+        self.rekey = policy;
+        self
+    }
+
+    pub fn rekey_policy(&self) -> &RekeyPolicy {
+        &self.rekey
+    }
+
+    /// Sets the padding sealed into every frame this side sends.
+    pub fn padding(mut self, padding: Padding) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Sends a cover frame whenever the connection has been idle for about
+    /// `interval`, `None` or a zero interval disables cover traffic.
+    ///
+    /// Cover frames are padded like any other frame and are discarded by the
+    /// receiving dispatcher.
+    pub fn cover(mut self, interval: Option<Duration>) -> Self {
+        // 间隔为零时每次 poll 都会到期, 不断发送 cover frame
+        self.cover = interval.filter(|t| !t.is_zero());
+        self
+    }
+
+    /// Sets the cache the server checks client hellos against.
+    ///
+    /// Clones of a builder share its cache, so every connection accepted with
+    /// them is checked against the hellos seen by the others.
+    pub fn replay_cache(mut self, cache: ReplayCache) -> Self {
+        self.replay = Arc::new(cache);
+        self
+    }
+
+    /// Sets the largest frame accepted from the peer or sent to it.
+    pub fn max_frame_len(mut self, len: usize) -> Self {
+        self.max_frame_len = len;
+        self
+    }
+
+    /// Sets the largest serialized message accepted from the peer or sent to
+    /// it, also bounding what deserializing a message may allocate.
+    pub fn max_message_len(mut self, len: u64) -> Self {
+        self.max_message_len = len;
+        self
+    }
+
+    /// Relays connections whose handshake fails to `addr`, a `host:port`
+    /// such as a local web server, `None` closes them.
+    ///
+    /// Everything the client sent is replayed to the backend before relaying
+    /// both directions, so that a prober sees an ordinary service. The server
+    /// answers nothing before the client hello authenticates, so the relayed
+    /// connection carries no trace of it. Accepting such a connection only
+    /// fails once the relay is closed.
+    pub fn fallback(mut self, addr: Option<String>) -> Self {
+        self.fallback = addr;
+        self
+    }
+
+    /// Runs a Noise handshake instead of the pre-shared key handshake, `None`
+    /// restores it. The pre-shared key is then unused.
+    pub fn noise(mut self, noise: Option<Noise>) -> Self {
+        self.noise = noise;
+        self
+    }
+
+    pub fn noise_config(&self) -> Option<&Noise> {
+        self.noise.as_ref()
+    }
+
+    pub fn fallback_addr(&self) -> Option<&str> {
+        self.fallback.as_deref()
+    }
+
+    /// Compresses frames with deflate at `level`, 0 to 9, if the peer enables
+    /// compression too. `None` disables it.
+    ///
+    /// Each frame is compressed on its own before it is sealed and padded,
+    /// and sent as is when that does not make it smaller. Decompressed frames
+    /// are held to [`max_frame_len`](Self::max_frame_len).
+    pub fn compression(mut self, level: Option<u32>) -> Self {
+        self.compression = level;
+        self
+    }
+
+    pub fn compression_level(&self) -> Option<u32> {
+        self.compression
+    }
+
+    /// Sets how messages are serialized, [`Format::Bincode`] by default.
+    ///
+    /// Both sides send their format during the handshake, which fails on
+    /// either side if they differ.
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn message_format(&self) -> Format {
+        self.format
+    }
+
+    pub fn padding_policy(&self) -> &Padding {
+        &self.padding
+    }
+
+    pub fn cover_interval(&self) -> Option<Duration> {
+        self.cover
+    }
+
+    /// Fails if the configuration is incomplete.
+    pub fn check(&self) -> io::Result<()> {
+        if self.ciphers.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no cipher enabled",
+            ));
+        }
+        self.padding.check()?;
+        if self.compression.is_some_and(|level| level > 9) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "compression level must be between 0 and 9",
+            ));
+        }
+        self.check_limits()?;
+        if self.noise.is_some() || self.users.as_ref().is_some_and(|users| !users.is_empty()) {
+            return Ok(());
+        }
+        self.key_ref().map(|_| ())
+    }
+
+    fn check_limits(&self) -> io::Result<()> {
+        if self.max_frame_len == 0 || self.max_message_len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame and message limits must not be zero",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Runs the client side of the handshake over `io`.
+    pub async fn connect<S, Item, SinkItem>(
+        &self,
+        io: S,
+    ) -> io::Result<Transport<S, Item, SinkItem>>
+    where
+        S: AsyncWrite + AsyncRead + Unpin,
+    {
+        self.handshake(io, Role::Client).await
+    }
+
+    /// Runs the server side of the handshake over `io`.
+    pub async fn accept<S, Item, SinkItem>(&self, io: S) -> io::Result<Transport<S, Item, SinkItem>>
+    where
+        S: AsyncWrite + AsyncRead + Unpin,
+    {
+        self.handshake(io, Role::Server).await
+    }
+
+    /// Frames messages over `io` in plaintext with a [`PlainCodec`], for a
+    /// link that is already encrypted and authenticated, such as a TLS or SSH
+    /// tunnel.
+    ///
+    /// There is no handshake and no key, both sides only send each other the
+    /// id of their [`format`](Self::format), failing if they differ. Of the
+    /// rest of the configuration only the frame and message limits and the
+    /// cover interval apply. Both sides of a connection call this.
+    pub async fn plaintext<S, Item, SinkItem>(
+        &self,
+        mut io: S,
+    ) -> io::Result<Transport<S, Item, SinkItem, PlainCodec>>
+    where
+        S: AsyncWrite + AsyncRead + Unpin,
+    {
+        self.check_limits()?;
+        let exchange = async {
+            io.write_all(&[self.format.id()]).await?;
+            io.flush().await?;
+            let mut id = [0];
+            io.read_exact(&mut id).await?;
+            handshake::check_format(self.format, Format::from_id(id[0]))?;
+            Ok(())
+        };
+        timeout(exchange).await?;
+
+        Ok(self.framed(io, Stats::default()).with_cover(self.cover))
+    }
+
+    /// Frames messages over `io` with a [`PlainCodec`] counting into
+    /// `stats`, without any exchange, for the streams of a connection whose
+    /// format is already checked.
+    pub(crate) fn framed<S, Item, SinkItem>(
+        &self,
+        io: S,
+        stats: Stats,
+    ) -> Transport<S, Item, SinkItem, PlainCodec>
+    where
+        S: AsyncWrite + AsyncRead,
+    {
+        let codec = PlainCodec::new()
+            .with_max_frame_len(self.max_frame_len)
+            .with_stats(stats);
+        Transport::with_format(io, codec, self.format, self.max_message_len)
+    }
+
+    async fn handshake<S, Item, SinkItem>(
+        &self,
+        mut io: S,
+        role: Role,
+    ) -> io::Result<Transport<S, Item, SinkItem>>
+    where
+        S: AsyncWrite + AsyncRead + Unpin,
+    {
+        self.check()?;
+        let options = if self.compression.is_some() {
+            handshake::DEFLATE
+        } else {
+            0
+        };
+        let (session, (user, key_id)) = match role {
+            Role::Client => match &self.noise {
+                None => {
+                    let key = self.key_ref()?;
+                    let session = timeout(handshake::client(
+                        &mut io,
+                        key,
+                        &self.ciphers,
+                        options,
+                        self.format,
+                    ))
+                    .await?;
+                    (session, (None, Some(key.fingerprint())))
+                }
+                Some(noise) => {
+                    let handshake =
+                        noise::client(&mut io, noise, &self.ciphers, options, self.format);
+                    let (session, remote) = timeout(handshake).await?;
+                    (session, (None, Some(remote.fingerprint())))
+                }
+            },
+            Role::Server => {
+                // 只尝试当前有效的密钥, Noise 不使用共享密钥
+                let now = SystemTime::now();
+                let mut candidates: Vec<(Option<&str>, &Key)> = match &self.users {
+                    Some(users) => users.valid_at(now).map(|(n, k)| (Some(n), k)).collect(),
+                    None => Vec::new(),
+                };
+                if self.noise.is_none() {
+                    candidates.extend(self.key.as_ref().map(|key| (None, key)));
+                }
+
+                let keys: Vec<&Key> = candidates.iter().map(|(_, key)| *key).collect();
+                // 有回落时, 第一条消息中途停顿的连接尽快转发
+                let pause = self.fallback.as_ref().map(|_| fallback::PAUSE_TIMEOUT);
+                let mut recorder = Recorder::new(&mut io).with_pause_timeout(pause);
+                let result = match &self.noise {
+                    None => timeout(handshake::server(
+                        &mut recorder,
+                        &keys,
+                        &self.ciphers,
+                        options,
+                        self.format,
+                        &self.replay,
+                    ))
+                    .await
+                    .map(|(session, index)| (session, Some(index))),
+                    Some(noise) => {
+                        let handshake = noise::server(
+                            &mut recorder,
+                            noise,
+                            &keys,
+                            &self.ciphers,
+                            options,
+                            self.format,
+                            &self.replay,
+                        );
+                        timeout(handshake).await
+                    }
+                };
+                match result {
+                    Ok((session, index)) => {
+                        let candidate = index.map(|index| candidates[index]);
+                        let user = candidate.and_then(|(user, _)| user);
+                        let key_id = candidate.map(|(_, key)| key.fingerprint());
+                        (session, (user, key_id))
+                    }
+                    Err(e) => {
+                        let read = recorder.into_unanswered();
+                        if let (Some(addr), Some(read)) = (&self.fallback, read) {
+                            if let Err(relay) = fallback::relay(&mut io, &read, addr).await {
+                                return Err(io::Error::new(
+                                    relay.kind(),
+                                    format!("{}, fallback to {} failed: {}", e, addr, relay),
+                                ));
+                            }
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+        };
+
+        let compression = self
+            .compression
+            .filter(|_| session.options & handshake::DEFLATE != 0);
+        let codec = SafeCodec::new(session)
+            .with_compression(compression)
+            .with_rekey(self.rekey)
+            .with_padding(self.padding.clone())
+            .with_max_frame_len(self.max_frame_len);
+        let transport = Transport::with_format(io, codec, self.format, self.max_message_len);
+        Ok(transport
+            .with_cover(self.cover)
+            .with_identity(user.map(str::to_owned), key_id))
+    }
+
+    fn key_ref(&self) -> io::Result<&Key> {
+        self.key
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no tunnel key configured"))
+    }
+}
+
+async fn timeout<T>(fut: impl std::future::Future<Output = io::Result<T>>) -> io::Result<T> {
+    match time::timeout(HANDSHAKE_TIMEOUT, fut).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "handshake timed out",
+        )),
+    }
+}
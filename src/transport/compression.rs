@@ -0,0 +1,149 @@
+use super::CodecError;
+use bytes::{Bytes, BytesMut};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+/// Frames shorter than this are sent as they are.
+const MIN_LEN: usize = 64;
+
+/// Raw deflate applied to each frame on its own, so that frames can still be
+/// decoded one at a time.
+pub(crate) struct Deflate {
+    compress: Compress,
+    decompress: Decompress,
+    out: Vec<u8>,
+}
+
+impl Deflate {
+    pub(crate) fn new(level: u32) -> Self {
+        Self {
+            compress: Compress::new(Compression::new(level), false),
+            decompress: Decompress::new(false),
+            out: Vec::new(),
+        }
+    }
+
+    /// Compresses `data`, or returns `None` if that would not make it smaller.
+    ///
+    /// Compression stops as soon as the output reaches the size of the input,
+    /// so incompressible data costs at most one pass.
+    pub(crate) fn compress(&mut self, data: &[u8]) -> Option<&[u8]> {
+        if data.len() < MIN_LEN {
+            return None;
+        }
+
+        // 输出区只有 len - 1 字节, 不会写入之前的帧留下的更大空间
+        self.compress.reset();
+        self.out.resize(data.len() - 1, 0);
+        let status = self
+            .compress
+            .compress(data, &mut self.out, FlushCompress::Finish);
+        match status {
+            Ok(Status::StreamEnd) => Some(&self.out[..self.compress.total_out() as usize]),
+            _ => None,
+        }
+    }
+
+    /// Decompresses `data`, failing once the output exceeds `max` bytes.
+    pub(crate) fn decompress(&mut self, data: &[u8], max: usize) -> Result<BytesMut, CodecError> {
+        self.decompress.reset(false);
+        let mut out = Vec::new();
+
+        // 逐步扩容, 输出超过 max 即停止, 防止压缩炸弹
+        loop {
+            if out.len() == out.capacity() {
+                if out.len() > max {
+                    return Err(CodecError::InflatedTooLarge { max });
+                }
+                let more = out.len().max(data.len() * 2).max(MIN_LEN);
+                out.reserve(more.min(max + 1 - out.len()));
+            }
+
+            let (consumed, produced) = (self.decompress.total_in(), out.len());
+            let status = self
+                .decompress
+                .decompress_vec(&data[consumed as usize..], &mut out, FlushDecompress::None)
+                .map_err(|_| CodecError::Malformed("invalid compressed data"))?;
+            let stalled = self.decompress.total_in() == consumed && out.len() == produced;
+            match status {
+                Status::StreamEnd if out.len() <= max => return Ok(Bytes::from(out).into()),
+                Status::StreamEnd => return Err(CodecError::InflatedTooLarge { max }),
+                _ if stalled && out.len() < out.capacity() => {
+                    return Err(CodecError::Malformed("truncated compressed data"));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data: Vec<u8> = b"yew ".iter().copied().cycle().take(10_000).collect();
+        let mut deflate = Deflate::new(6);
+        let compressed = deflate.compress(&data).unwrap().to_vec();
+        assert!(compressed.len() < data.len());
+        assert_eq!(deflate.decompress(&compressed, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn incompressible_data_is_left_alone() {
+        let mut data = vec![0; 4096];
+        ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut data).unwrap();
+        assert!(Deflate::new(9).compress(&data).is_none());
+        assert!(Deflate::new(9).compress(b"short").is_none());
+    }
+
+    #[test]
+    fn incompressible_data_stops_at_its_own_size() {
+        let mut deflate = Deflate::new(6);
+        deflate.compress(&vec![0; 1 << 20]).unwrap();
+
+        // 前一帧留下了更大的输出区, 压缩仍在输入的大小处停止
+        let mut data = vec![0; 64 << 10];
+        ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut data).unwrap();
+        assert!(deflate.compress(&data).is_none());
+        assert!(deflate.compress.total_out() < data.len() as u64);
+
+        let data: Vec<u8> = b"yew ".iter().copied().cycle().take(1000).collect();
+        let compressed = deflate.compress(&data).unwrap().to_vec();
+        assert_eq!(deflate.decompress(&compressed, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn deflate_bomb_stops_at_the_limit() {
+        // 16 MiB 的零压缩后只有十几 KiB
+        let bomb = vec![0; 16 << 20];
+        let mut deflate = Deflate::new(9);
+        let compressed = deflate.compress(&bomb).unwrap().to_vec();
+        assert!(compressed.len() < 32 << 10);
+
+        let max = 1 << 20;
+        assert!(matches!(
+            deflate.decompress(&compressed, max),
+            Err(CodecError::InflatedTooLarge { max: 1048576 })
+        ));
+        // 恰好等于上限时仍可解出
+        assert_eq!(
+            deflate.decompress(&compressed, bomb.len()).unwrap().len(),
+            bomb.len()
+        );
+    }
+
+    #[test]
+    fn invalid_data_is_malformed() {
+        let mut deflate = Deflate::new(6);
+        assert!(matches!(
+            deflate.decompress(&[0xff; 16], 1024),
+            Err(CodecError::Malformed(_))
+        ));
+        let compressed = deflate.compress(&[1; 1000]).unwrap().to_vec();
+        assert!(matches!(
+            deflate.decompress(&compressed[..compressed.len() / 2], 1024),
+            Err(CodecError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,357 @@
+//! [Elligator 2](https://elligator.org) encoding of ephemeral X25519 public
+//! keys, so that the keys sent in the clear during a handshake cannot be told
+//! from random bytes.
+//!
+//! A public key is sent as a representative, a field element that the
+//! Elligator 2 map takes to the key. Only about half of the points have one,
+//! [`generate`] draws keys until it finds such a point. The points of plain
+//! X25519 keys all lie in the prime order subgroup, which an observer could
+//! check, so a random low order point is added to each public key. Private
+//! keys are clamped to a multiple of 8, which cancels that point in every
+//! exchange. A representative is at most `(p - 1) / 2`, the two top bits of
+//! its 32 little endian bytes are filled with random bits.
+
+use curve25519_dalek::{constants::EIGHT_TORSION, EdwardsPoint, MontgomeryPoint};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::{cmp::Ordering, io};
+
+pub(crate) const KEY_LEN: usize = 32;
+
+/// Ephemeral key pair whose public key has a representative.
+pub(crate) struct KeyPair {
+    pub(crate) private: [u8; KEY_LEN],
+    /// Montgomery u-coordinate, the usual X25519 public key.
+    pub(crate) public: [u8; KEY_LEN],
+}
+
+/// Draws key pairs until the public key has a representative.
+pub(crate) fn generate() -> io::Result<KeyPair> {
+    let rng = SystemRandom::new();
+    loop {
+        let mut private = [0; KEY_LEN];
+        let mut torsion = [0];
+        rng.fill(&mut private).map_err(|_| random_error())?;
+        rng.fill(&mut torsion).map_err(|_| random_error())?;
+
+        let point =
+            EdwardsPoint::mul_base_clamped(private) + EIGHT_TORSION[torsion[0] as usize % 8];
+        let public = point.to_montgomery().to_bytes();
+        if representable(&Fe::from_bytes(&public)) {
+            return Ok(KeyPair { private, public });
+        }
+    }
+}
+
+/// Picks one of the two representatives of `public` at random, failing if
+/// it has none.
+pub(crate) fn encode(public: &[u8; KEY_LEN]) -> io::Result<[u8; KEY_LEN]> {
+    let mut random = [0];
+    SystemRandom::new()
+        .fill(&mut random)
+        .map_err(|_| random_error())?;
+
+    let u = Fe::from_bytes(public);
+    if !representable(&u) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "public key has no representative",
+        ));
+    }
+    // r² = -u / 2(u + A) 或 -(u + A) / 2u, 两者都映射回 u
+    let upa = u.add(&A);
+    let (num, den) = if random[0] & 1 == 0 {
+        (u, upa)
+    } else {
+        (upa, u)
+    };
+    let r = num
+        .neg()
+        .mul(&TWO.mul(&den).invert())
+        .sqrt()
+        .expect("representable");
+    let r = r.min(&r.neg());
+
+    let mut bytes = r.to_bytes();
+    bytes[KEY_LEN - 1] |= random[0] & 0xc0;
+    Ok(bytes)
+}
+
+/// Maps a representative to the public key it stands for.
+pub(crate) fn decode(representative: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+    let mut bytes = *representative;
+    bytes[KEY_LEN - 1] &= 0x3f;
+    let r = Fe::from_bytes(&bytes);
+
+    // w = -A / (1 + 2r²), 曲线上的点为 w 或 -w - A
+    let w = A.neg().mul(&ONE.add(&TWO.mul(&r.square())).invert());
+    let g = w.mul(&w.square().add(&A.mul(&w)).add(&ONE));
+    let u = if g.sqrt().is_some() {
+        w
+    } else {
+        w.neg().sub(&A)
+    };
+    u.to_bytes()
+}
+
+/// X25519 of `private` and the peer's `public` key, failing when the result
+/// is zero, which only a low order public key gives.
+pub(crate) fn agree(private: &[u8; KEY_LEN], public: &[u8; KEY_LEN]) -> io::Result<[u8; KEY_LEN]> {
+    let shared = MontgomeryPoint(*public).mul_clamped(*private).to_bytes();
+    if shared == [0; KEY_LEN] {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "low order public key",
+        ));
+    }
+    Ok(shared)
+}
+
+/// Whether the point with u-coordinate `u` has a representative, that is
+/// `u` is neither `0` nor `-A` and `-2u(u + A)` is a square.
+fn representable(u: &Fe) -> bool {
+    let upa = u.add(&A);
+    !u.is_zero() && !upa.is_zero() && TWO.mul(u).mul(&upa).neg().sqrt().is_some()
+}
+
+fn random_error() -> io::Error {
+    io::Error::other("system random failed")
+}
+
+/// Montgomery curve constant of Curve25519.
+const A: Fe = Fe([486662, 0, 0, 0]);
+const ONE: Fe = Fe([1, 0, 0, 0]);
+/// Non-square of the field used by the map.
+const TWO: Fe = Fe([2, 0, 0, 0]);
+const P: Fe = Fe([
+    0xffff_ffff_ffff_ffed,
+    0xffff_ffff_ffff_ffff,
+    0xffff_ffff_ffff_ffff,
+    0x7fff_ffff_ffff_ffff,
+]);
+
+/// Element of the field of integers modulo `p = 2^255 - 19`, as four little
+/// endian limbs below `2^256` that are only fully reduced by
+/// [`to_bytes`](Fe::to_bytes).
+///
+/// The arithmetic is not constant time, it only handles public keys.
+#[derive(Clone, Copy, Debug)]
+struct Fe([u64; 4]);
+
+impl Fe {
+    /// Reads 32 little endian bytes, ignoring the top bit.
+    fn from_bytes(bytes: &[u8; 32]) -> Fe {
+        let mut limbs = [0; 4];
+        for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks_exact(8)) {
+            let mut word = [0; 8];
+            word.copy_from_slice(chunk);
+            *limb = u64::from_le_bytes(word);
+        }
+        limbs[3] &= 0x7fff_ffff_ffff_ffff;
+        Fe(limbs)
+    }
+
+    fn to_bytes(self) -> [u8; 32] {
+        let Fe(limbs) = self.reduce();
+        let mut bytes = [0; 32];
+        for (chunk, limb) in bytes.chunks_exact_mut(8).zip(limbs.iter()) {
+            chunk.copy_from_slice(&limb.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Same element below `p`.
+    fn reduce(self) -> Fe {
+        let mut x = self;
+        while x.cmp_limbs(&P) != Ordering::Less {
+            let mut borrow = false;
+            for (a, b) in x.0.iter_mut().zip(P.0.iter()) {
+                let (d, b1) = a.overflowing_sub(*b);
+                let (d, b2) = d.overflowing_sub(borrow as u64);
+                *a = d;
+                borrow = b1 || b2;
+            }
+        }
+        x
+    }
+
+    fn cmp_limbs(&self, other: &Fe) -> Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+
+    fn is_zero(&self) -> bool {
+        self.reduce().0 == [0; 4]
+    }
+
+    /// Smaller of the two canonical values.
+    fn min(&self, other: &Fe) -> Fe {
+        let (a, b) = (self.reduce(), other.reduce());
+        if a.cmp_limbs(&b) == Ordering::Greater {
+            b
+        } else {
+            a
+        }
+    }
+
+    fn add(&self, other: &Fe) -> Fe {
+        let mut limbs = [0; 4];
+        let mut carry = 0u128;
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let sum = self.0[i] as u128 + other.0[i] as u128 + carry;
+            *limb = sum as u64;
+            carry = sum >> 64;
+        }
+        Fe::fold(limbs, carry as u64)
+    }
+
+    fn neg(&self) -> Fe {
+        let x = self.reduce();
+        let mut limbs = [0; 4];
+        let mut borrow = false;
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let (d, b1) = P.0[i].overflowing_sub(x.0[i]);
+            let (d, b2) = d.overflowing_sub(borrow as u64);
+            *limb = d;
+            borrow = b1 || b2;
+        }
+        Fe(limbs)
+    }
+
+    fn sub(&self, other: &Fe) -> Fe {
+        self.add(&other.neg())
+    }
+
+    fn mul(&self, other: &Fe) -> Fe {
+        let mut wide = [0u64; 8];
+        for i in 0..4 {
+            let mut carry = 0u128;
+            for j in 0..4 {
+                let t = self.0[i] as u128 * other.0[j] as u128 + wide[i + j] as u128 + carry;
+                wide[i + j] = t as u64;
+                carry = t >> 64;
+            }
+            wide[i + 4] = carry as u64;
+        }
+
+        // 2^256 ≡ 38 (mod p)
+        let mut limbs = [0; 4];
+        let mut carry = 0u128;
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let t = wide[i] as u128 + wide[i + 4] as u128 * 38 + carry;
+            *limb = t as u64;
+            carry = t >> 64;
+        }
+        Fe::fold(limbs, carry as u64)
+    }
+
+    fn square(&self) -> Fe {
+        self.mul(self)
+    }
+
+    /// Adds `carry * 2^256` back into the limbs as `carry * 38`.
+    fn fold(mut limbs: [u64; 4], mut carry: u64) -> Fe {
+        while carry != 0 {
+            let mut c = carry as u128 * 38;
+            for limb in limbs.iter_mut() {
+                let t = *limb as u128 + c;
+                *limb = t as u64;
+                c = t >> 64;
+            }
+            carry = c as u64;
+        }
+        Fe(limbs)
+    }
+
+    /// `self` raised to `exp`, given as little endian limbs.
+    fn pow(&self, exp: &[u64; 4]) -> Fe {
+        let mut result = ONE;
+        for limb in exp.iter().rev() {
+            for bit in (0..64).rev() {
+                result = result.square();
+                if limb >> bit & 1 == 1 {
+                    result = result.mul(self);
+                }
+            }
+        }
+        result
+    }
+
+    fn invert(&self) -> Fe {
+        // x^(p - 2)
+        self.pow(&[
+            0xffff_ffff_ffff_ffeb,
+            0xffff_ffff_ffff_ffff,
+            0xffff_ffff_ffff_ffff,
+            0x7fff_ffff_ffff_ffff,
+        ])
+    }
+
+    /// A square root, `None` if there is none.
+    fn sqrt(&self) -> Option<Fe> {
+        // p ≡ 5 (mod 8): x^((p + 3) / 8) 或其乘以 √-1
+        let root = self.pow(&[
+            0xffff_ffff_ffff_fffe,
+            0xffff_ffff_ffff_ffff,
+            0xffff_ffff_ffff_ffff,
+            0x0fff_ffff_ffff_ffff,
+        ]);
+        if root.square().sub(self).is_zero() {
+            return Some(root);
+        }
+        // √-1 = 2^((p - 1) / 4)
+        let sqrt_m1 = TWO.pow(&[
+            0xffff_ffff_ffff_fffb,
+            0xffff_ffff_ffff_ffff,
+            0xffff_ffff_ffff_ffff,
+            0x1fff_ffff_ffff_ffff,
+        ]);
+        let root = root.mul(&sqrt_m1);
+        if root.square().sub(self).is_zero() {
+            Some(root)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn representative_decodes_to_the_public_key() {
+        for _ in 0..64 {
+            let pair = generate().unwrap();
+            let representative = encode(&pair.public).unwrap();
+            assert_eq!(decode(&representative), pair.public);
+        }
+    }
+
+    #[test]
+    fn low_order_component_cancels_out() {
+        let (a, b) = (generate().unwrap(), generate().unwrap());
+        let shared = agree(&a.private, &b.public).unwrap();
+        assert_eq!(shared, agree(&b.private, &a.public).unwrap());
+
+        // 与不含低阶分量的标准公钥得到相同结果
+        let plain = MontgomeryPoint::mul_base_clamped(b.private).to_bytes();
+        assert_eq!(shared, agree(&a.private, &plain).unwrap());
+    }
+
+    #[test]
+    fn representatives_use_every_bit() {
+        let mut ones = [0; KEY_LEN * 8];
+        for _ in 0..256 {
+            let representative = encode(&generate().unwrap().public).unwrap();
+            for (bit, count) in ones.iter_mut().enumerate() {
+                *count += (representative[bit / 8] >> (bit % 8) & 1) as usize;
+            }
+        }
+        assert!(ones.iter().all(|&n| n > 64 && n < 192), "{:?}", ones);
+    }
+
+    #[test]
+    fn low_order_public_key_is_rejected() {
+        let pair = generate().unwrap();
+        assert!(agree(&pair.private, &[0; KEY_LEN]).is_err());
+    }
+}
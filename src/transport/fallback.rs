@@ -0,0 +1,164 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{copy_bidirectional, AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+    time::{self, Sleep},
+};
+
+/// How long a peer may pause in the middle of its first message before it
+/// is taken for a probe. A client writes its first message at once, while a
+/// probe sends a short request and waits for the answer.
+pub(crate) const PAUSE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Keeps a copy of everything read from `inner`, so that a connection whose
+/// handshake fails can be replayed to the fallback backend.
+pub(crate) struct Recorder<'a, S> {
+    inner: &'a mut S,
+    read: Vec<u8>,
+    written: bool,
+    pause: Option<Duration>,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<'a, S> Recorder<'a, S> {
+    pub(crate) fn new(inner: &'a mut S) -> Self {
+        Self {
+            inner,
+            read: Vec::new(),
+            written: false,
+            pause: None,
+            deadline: None,
+        }
+    }
+
+    /// Fails reads with [`TimedOut`](io::ErrorKind::TimedOut) once the peer
+    /// has sent something and then nothing for `pause`, until anything is
+    /// written back.
+    pub(crate) fn with_pause_timeout(mut self, pause: Option<Duration>) -> Self {
+        self.pause = pause;
+        self
+    }
+
+    /// Bytes read, or `None` if anything was written back, after which the
+    /// connection can no longer pass for the backend.
+    pub(crate) fn into_unanswered(self) -> Option<Vec<u8>> {
+        if self.written {
+            None
+        } else {
+            Some(self.read)
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recorder<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let result = Pin::new(&mut *this.inner).poll_read(cx, buf);
+        this.read.extend_from_slice(&buf.filled()[before..]);
+
+        // 收到部分数据后停顿, 不像 client 的第一条消息
+        let pause = this
+            .pause
+            .filter(|_| !this.written && !this.read.is_empty());
+        match (&result, pause) {
+            (Poll::Pending, Some(pause)) => {
+                let deadline = this
+                    .deadline
+                    .get_or_insert_with(|| Box::pin(time::sleep(pause)));
+                if deadline.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "peer paused in its first message",
+                    )));
+                }
+            }
+            _ => this.deadline = None,
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Recorder<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.written = true;
+        Pin::new(&mut *this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Connects to `addr`, sends it the bytes already read from `io`, then relays
+/// both directions until either side closes.
+///
+/// Only failing to reach the backend is an error, how the relayed connection
+/// ends is up to the peer and the backend.
+pub(crate) async fn relay<S>(io: &mut S, read: &[u8], addr: &str) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut backend = TcpStream::connect(addr).await?;
+    backend.write_all(read).await?;
+    let _ = copy_bidirectional(io, &mut backend).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{testing::builder, transport::Transport};
+    use std::{io, time::Instant};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[tokio::test]
+    async fn short_probe_is_relayed_without_waiting_for_the_handshake_timeout() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = backend.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut conn, _) = backend.accept().await.unwrap();
+            let mut request = [0; 18];
+            conn.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"GET / HTTP/1.0\r\n\r\n");
+            conn.write_all(b"HTTP/1.0 200 OK\r\n\r\n").await.unwrap();
+        });
+
+        let builder = builder().fallback(Some(addr));
+        let (mut probe, server) = tokio::io::duplex(1024);
+        let accept = tokio::spawn(async move {
+            let transport: io::Result<Transport<_, (), ()>> = builder.accept(server).await;
+            transport.map(|_| ())
+        });
+
+        let start = Instant::now();
+        probe.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+        let mut answer = Vec::new();
+        probe.read_to_end(&mut answer).await.unwrap();
+        assert_eq!(answer, b"HTTP/1.0 200 OK\r\n\r\n");
+        assert!(start.elapsed() < super::PAUSE_TIMEOUT * 4);
+        drop(probe);
+        assert!(accept.await.unwrap().is_err());
+    }
+}
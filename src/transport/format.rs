@@ -0,0 +1,391 @@
+use super::{CodecError, FrameCodec};
+use bincode::{DefaultOptions, Options};
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::{self, Write},
+    marker::PhantomData,
+    str::FromStr,
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Serialization of the messages a [`Transport`](super::Transport) carries,
+/// checked against the peer's during the handshake.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Compact binary encoding, the default.
+    #[default]
+    Bincode,
+    /// Human readable, for debugging and peers not written in Rust.
+    Json,
+}
+
+impl Format {
+    pub const ALL: [Format; 2] = [Format::Bincode, Format::Json];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Bincode => "bincode",
+            Format::Json => "json",
+        }
+    }
+
+    /// Identifier on the wire.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Format::Bincode => 1,
+            Format::Json => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.iter().copied().find(|f| f.id() == id)
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Format {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|f| f.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unknown format {:?}", s),
+                )
+            })
+    }
+}
+
+/// [`Format`] limited to messages of `max` bytes.
+///
+/// It stands in for the formats of `tokio-serde`, which serialize into a
+/// buffer of their own, copied into the frame afterwards, and cannot bound
+/// what deserializing allocates.
+///
+/// The limit also applies when deserializing, which bincode skips for input
+/// held in a slice, so a length claimed by a field cannot exceed it either.
+/// JSON claims no lengths, what it allocates is bounded by the input.
+pub(crate) struct Serializer {
+    format: Format,
+    max: u64,
+}
+
+impl Serializer {
+    pub(crate) fn new(format: Format, max: u64) -> Self {
+        Self { format, max }
+    }
+
+    pub(crate) fn format(&self) -> Format {
+        self.format
+    }
+
+    fn options(&self) -> impl Options {
+        DefaultOptions::new().with_limit(self.max)
+    }
+
+    fn too_large(&self) -> io::Error {
+        CodecError::MessageTooLarge { max: self.max }.into()
+    }
+
+    fn error(&self, e: bincode::Error) -> io::Error {
+        match *e {
+            bincode::ErrorKind::SizeLimit => self.too_large(),
+            _ => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+
+    fn deserialize<T>(&self, src: &[u8]) -> io::Result<T>
+    where
+        T: for<'a> Deserialize<'a>,
+    {
+        if src.len() as u64 > self.max {
+            return Err(self.too_large());
+        }
+        match self.format {
+            Format::Bincode => self
+                .options()
+                .deserialize_from(src)
+                .map_err(|e| self.error(e)),
+            Format::Json => serde_json::from_slice(src).map_err(io::Error::from),
+        }
+    }
+
+    /// Deserializes a `T` from the start of `src`, returning it with the
+    /// number of bytes it took.
+    fn deserialize_prefix<T>(&self, src: &[u8]) -> io::Result<(T, usize)>
+    where
+        T: for<'a> Deserialize<'a>,
+    {
+        let (item, len) = match self.format {
+            Format::Bincode => {
+                let mut rest = src;
+                let item = self
+                    .options()
+                    .deserialize_from(&mut rest)
+                    .map_err(|e| self.error(e))?;
+                (item, src.len() - rest.len())
+            }
+            Format::Json => {
+                let mut items = serde_json::Deserializer::from_slice(src).into_iter();
+                match items.next() {
+                    Some(item) => (item?, items.byte_offset()),
+                    None => return Err(io::ErrorKind::UnexpectedEof.into()),
+                }
+            }
+        };
+        if len as u64 > self.max {
+            return Err(self.too_large());
+        }
+        Ok((item, len))
+    }
+
+    /// Appends `item` to `dst`.
+    fn serialize_into<T>(&self, item: &T, dst: &mut BytesMut) -> io::Result<()>
+    where
+        T: Serialize,
+    {
+        let start = dst.len();
+        match self.format {
+            Format::Bincode => {
+                // 先算出长度, 一次性扩容后原地写入
+                let len = self
+                    .options()
+                    .serialized_size(item)
+                    .map_err(|e| self.error(e))?;
+                dst.resize(start + len as usize, 0);
+                let writer = SliceWriter {
+                    buf: &mut dst[start..],
+                    pos: 0,
+                };
+                self.options().serialize_into(writer, item).map_err(|e| {
+                    dst.truncate(start);
+                    self.error(e)
+                })
+            }
+            Format::Json => {
+                // 长度事先未知, 写完再检查
+                let result = serde_json::to_writer((&mut *dst).writer(), item);
+                if let Err(e) = result {
+                    dst.truncate(start);
+                    return Err(e.into());
+                }
+                if (dst.len() - start) as u64 > self.max {
+                    dst.truncate(start);
+                    return Err(self.too_large());
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Writes into a buffer sized beforehand.
+///
+/// Bincode writes sequences of bytes one byte at a time, which the writer for
+/// `&mut [u8]` turns into as many calls to `memcpy`.
+struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Write for SliceWriter<'_> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let end = self.pos + buf.len();
+        let dst = self
+            .buf
+            .get_mut(self.pos..end)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::WriteZero))?;
+        match buf {
+            [byte] => dst[0] = *byte,
+            _ => dst.copy_from_slice(buf),
+        }
+        self.pos = end;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Message followed in its frame by raw bytes, which skip serialization.
+///
+/// A [`Transport`](super::Transport) whose items are `WithPayload` writes the
+/// payload right after the serialized message, and hands it back as a slice
+/// of the received frame, so it is never copied by the serializer. The
+/// payload counts against the frame limit but not against the message limit.
+///
+/// With [`Format::Json`] a payload would leave the frame no longer a JSON
+/// document, sending one fails, and the dispatchers never agree on
+/// [`RAW`](crate::protocol::RAW) for it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WithPayload<T> {
+    pub message: T,
+    pub payload: Bytes,
+}
+
+impl<T> From<T> for WithPayload<T> {
+    /// `message` without payload, framed exactly as `message` alone.
+    fn from(message: T) -> Self {
+        WithPayload {
+            message,
+            payload: Bytes::new(),
+        }
+    }
+}
+
+/// Messages serialized with a [`Serializer`] and framed by a [`FrameCodec`].
+///
+/// Outgoing messages are serialized straight into the output buffer, where
+/// the codec frames them, [`SafeCodec`](super::SafeCodec) seals them in place.
+pub(crate) struct MessageCodec<Item, SinkItem, C> {
+    pub(crate) codec: C,
+    pub(crate) serializer: Serializer,
+    ghost: PhantomData<fn() -> (Item, SinkItem)>,
+}
+
+impl<Item, SinkItem, C> MessageCodec<Item, SinkItem, C> {
+    pub(crate) fn new(codec: C, serializer: Serializer) -> Self {
+        Self {
+            codec,
+            serializer,
+            ghost: PhantomData,
+        }
+    }
+}
+
+impl<Item, SinkItem, C> Decoder for MessageCodec<Item, SinkItem, C>
+where
+    Item: for<'a> Deserialize<'a>,
+    C: FrameCodec,
+{
+    type Item = Item;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Item>> {
+        match self.codec.decode(src)? {
+            Some(frame) => self.serializer.deserialize(&frame).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<Item, SinkItem, C> Encoder<SinkItem> for MessageCodec<Item, SinkItem, C>
+where
+    SinkItem: Serialize,
+    C: FrameCodec,
+{
+    type Error = io::Error;
+
+    fn encode(&mut self, item: SinkItem, dst: &mut BytesMut) -> io::Result<()> {
+        let serializer = &self.serializer;
+        self.codec
+            .encode_with(dst, |dst| serializer.serialize_into(&item, dst))
+    }
+}
+
+impl<T, SinkItem, C> Decoder for MessageCodec<WithPayload<T>, SinkItem, C>
+where
+    T: for<'a> Deserialize<'a>,
+    C: FrameCodec,
+{
+    type Item = WithPayload<T>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<WithPayload<T>>> {
+        match self.codec.decode(src)? {
+            Some(mut frame) => {
+                // 消息之后的部分即 payload, 不复制
+                let (message, len) = self.serializer.deserialize_prefix(&frame)?;
+                let payload = frame.split_off(len).freeze();
+                Ok(Some(WithPayload { message, payload }))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl<Item, T, C> Encoder<WithPayload<T>> for MessageCodec<Item, WithPayload<T>, C>
+where
+    T: Serialize,
+    C: FrameCodec,
+{
+    type Error = io::Error;
+
+    fn encode(&mut self, item: WithPayload<T>, dst: &mut BytesMut) -> io::Result<()> {
+        let serializer = &self.serializer;
+        if serializer.format == Format::Json && !item.payload.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "raw payloads cannot follow json messages",
+            ));
+        }
+        self.codec.encode_with(dst, |dst| {
+            serializer.serialize_into(&item.message, dst)?;
+            dst.extend_from_slice(&item.payload);
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::PlainCodec;
+
+    type Codec = MessageCodec<WithPayload<Vec<u8>>, WithPayload<Vec<u8>>, PlainCodec>;
+
+    #[test]
+    fn json_frames_are_json_documents() {
+        let mut codec: Codec =
+            MessageCodec::new(PlainCodec::new(), Serializer::new(Format::Json, 1024));
+        let mut buf = BytesMut::new();
+        codec
+            .encode(WithPayload::from(vec![1, 2, 3]), &mut buf)
+            .unwrap();
+        let frame: serde_json::Value = serde_json::from_slice(&buf[4..]).unwrap();
+        assert_eq!(frame, serde_json::json!([1, 2, 3]));
+
+        let raw = WithPayload {
+            message: Vec::new(),
+            payload: Bytes::from_static(b"raw"),
+        };
+        let e = codec.encode(raw, &mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded, WithPayload::from(vec![1, 2, 3]));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn bincode_payload_follows_the_message() {
+        let mut codec: Codec =
+            MessageCodec::new(PlainCodec::new(), Serializer::new(Format::Bincode, 1024));
+        let item = WithPayload {
+            message: vec![1, 2, 3],
+            payload: Bytes::from_static(b"raw"),
+        };
+        let mut buf = BytesMut::new();
+        codec.encode(item.clone(), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(item));
+    }
+}
@@ -0,0 +1,111 @@
+use super::{Stats, MAX_FRAME_LEN};
+use bytes::{Bytes, BytesMut};
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Splits the stream under a [`Transport`](super::Transport) into frames, one
+/// serialized message each.
+///
+/// Implemented by [`SafeCodec`](super::SafeCodec), which seals every frame,
+/// by [`PlainCodec`](super::PlainCodec) for links that are already secure,
+/// and by [`CustomCodec`] for any other frame encoder and decoder.
+pub trait FrameCodec: Decoder<Item = BytesMut, Error = io::Error> {
+    /// Appends one frame whose data `write` appends to `dst`. On error `dst`
+    /// is left as it was.
+    fn encode_with<F>(&mut self, dst: &mut BytesMut, write: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut BytesMut) -> io::Result<()>;
+
+    /// Largest frame accepted or sent, the default limit of messages.
+    fn max_frame_len(&self) -> usize;
+
+    fn stats(&self) -> &Stats;
+}
+
+/// Frames with a user supplied encoder and decoder, such as
+/// `tokio_util::codec::LengthDelimitedCodec`.
+///
+/// Messages are serialized into a buffer of their own, then handed to the
+/// encoder. Byte counts are those the encoder writes and the decoder
+/// consumes.
+pub struct CustomCodec<C> {
+    inner: C,
+    max_frame_len: usize,
+    consumed: usize,
+    stats: Stats,
+}
+
+impl<C> CustomCodec<C> {
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            max_frame_len: MAX_FRAME_LEN,
+            consumed: 0,
+            stats: Stats::default(),
+        }
+    }
+
+    /// Sets the frame limit reported to the [`Transport`](super::Transport),
+    /// which `inner` is expected to enforce.
+    pub fn with_max_frame_len(mut self, len: usize) -> Self {
+        self.max_frame_len = len;
+        self
+    }
+
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.inner
+    }
+}
+
+impl<C> Decoder for CustomCodec<C>
+where
+    C: Decoder<Item = BytesMut, Error = io::Error>,
+{
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        // 解码器可能分多次消费一帧, 累计后再记录
+        let before = src.len();
+        let frame = self.inner.decode(src)?;
+        self.consumed += before - src.len();
+        if frame.is_some() {
+            self.stats.record_received(self.consumed);
+            self.consumed = 0;
+        }
+        Ok(frame)
+    }
+}
+
+impl<C> FrameCodec for CustomCodec<C>
+where
+    C: Decoder<Item = BytesMut, Error = io::Error> + Encoder<Bytes, Error = io::Error>,
+{
+    fn encode_with<F>(&mut self, dst: &mut BytesMut, write: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut BytesMut) -> io::Result<()>,
+    {
+        let mut data = BytesMut::new();
+        write(&mut data)?;
+
+        let start = dst.len();
+        if let Err(e) = self.inner.encode(data.freeze(), dst) {
+            dst.truncate(start);
+            return Err(e);
+        }
+        self.stats.record_sent(dst.len() - start);
+        Ok(())
+    }
+
+    fn max_frame_len(&self) -> usize {
+        self.max_frame_len
+    }
+
+    fn stats(&self) -> &Stats {
+        &self.stats
+    }
+}
@@ -0,0 +1,587 @@
+//! Session handshake run before a [`Transport`](super::Transport) starts framing.
+//!
+//! ```text
+//! client hello: ephemeral public key | seal(body length) | seal(timestamp | cipher count | cipher ids | options | format)
+//! server hello: ephemeral public key | seal(body length) | seal(chosen cipher id | options | format [| cipher count | cipher ids])
+//! ```
+//!
+//! Each hello is sealed with ChaCha20-Poly1305 under a key derived from the
+//! pre-shared key and the public key it carries, the server hello also binds
+//! the whole client hello as associated data. Opening a hello therefore proves
+//! that the peer holds the pre-shared key, and nothing but the public keys is
+//! sent in the clear. The public keys are sent as their
+//! [Elligator 2](super::elligator) representatives, so that both hellos look
+//! like random bytes.
+//!
+//! A server may accept several pre-shared keys, one per user. It tries each of
+//! them on the client hello, the one that opens it identifies the user.
+//!
+//! The server turns away client hellos whose timestamp, in seconds since the
+//! Unix epoch, is more than half a replay window away from its own clock, and
+//! those whose public key is in its [`ReplayCache`], without answering them.
+//! A recorded hello replayed to probe the server is thus left unanswered.
+//!
+//! The server picks the first of its own ciphers that the client offers, or
+//! answers with cipher id `0` followed by its own ciphers when there is none,
+//! so both sides report the same [`NoCommonCipher`](HandshakeError::NoCommonCipher)
+//! error. Options are bits, such as [`DEFLATE`], that each side sets
+//! when it enables the option, the server answers with those both set. Each
+//! side also sends the id of its message [`Format`], and fails the handshake
+//! if the other one differs.
+//!
+//! The traffic secret of each direction is then derived with HKDF-SHA256
+//! from the X25519 shared secret, salted with the pre-shared key, so a later
+//! leak of the pre-shared key does not expose recorded sessions.
+
+use super::{
+    elligator::{self, KeyPair},
+    Cipher, Format, Key, ReplayCache,
+};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    hkdf,
+};
+use std::{
+    convert::TryInto,
+    error, fmt, io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const PUBLIC_KEY_LEN: usize = elligator::KEY_LEN;
+const TAG_LEN: usize = 16;
+pub(crate) const MAX_CIPHERS: usize = 16;
+const MAX_HELLO_LEN: usize = 1024;
+
+const CLIENT_LABEL: &[u8] = b"yew client hello";
+const SERVER_LABEL: &[u8] = b"yew server hello";
+const CLIENT_TO_SERVER: &[u8] = b"yew c2s";
+const SERVER_TO_CLIENT: &[u8] = b"yew s2c";
+
+/// Option: frames may be compressed.
+pub(crate) const DEFLATE: u8 = 0x01;
+
+/// Side of the connection a codec or handshake runs on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// Traffic secrets of one connection, one per direction.
+pub(crate) struct Session {
+    pub(crate) cipher: Cipher,
+    pub(crate) send: hkdf::Prk,
+    pub(crate) recv: hkdf::Prk,
+    /// Options both sides enabled.
+    pub(crate) options: u8,
+}
+
+impl Session {
+    /// Derives static traffic secrets from the pre-shared key alone.
+    pub(crate) fn from_key(psk: &Key, role: Role, cipher: Cipher) -> Self {
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, psk.as_bytes()).extract(&[]);
+        let c2s = expand(&prk, CLIENT_TO_SERVER, &[]).unwrap();
+        let s2c = expand(&prk, SERVER_TO_CLIENT, &[]).unwrap();
+        Session::new(role, cipher, c2s, s2c)
+    }
+
+    pub(crate) fn with_options(mut self, options: u8) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub(crate) fn new(role: Role, cipher: Cipher, c2s: hkdf::Prk, s2c: hkdf::Prk) -> Self {
+        match role {
+            Role::Client => Session {
+                cipher,
+                send: c2s,
+                recv: s2c,
+                options: 0,
+            },
+            Role::Server => Session {
+                cipher,
+                send: s2c,
+                recv: c2s,
+                options: 0,
+            },
+        }
+    }
+}
+
+/// Errors reported by the handshake.
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// The peer holds none of the pre-shared keys.
+    Authentication,
+    /// None of the ciphers offered by the client is enabled on the server,
+    /// reported by both sides.
+    NoCommonCipher {
+        offered: Vec<Cipher>,
+        supported: Vec<Cipher>,
+    },
+    /// The peer serializes messages in another format, `None` if this side
+    /// does not know it.
+    FormatMismatch {
+        local: Format,
+        remote: Option<Format>,
+    },
+    /// The client hello timestamp is `skew` seconds away from the server
+    /// clock, more than the replay window allows.
+    Stale { skew: u64 },
+    /// The client hello was already seen, it is being replayed.
+    Replayed,
+    /// The peer sent a handshake message that does not follow the protocol.
+    Malformed(&'static str),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Authentication => f.write_str("handshake authentication failed"),
+            HandshakeError::NoCommonCipher { offered, supported } => write!(
+                f,
+                "no common cipher, client offers [{}], server supports [{}]",
+                names(offered),
+                names(supported)
+            ),
+            HandshakeError::FormatMismatch { local, remote } => write!(
+                f,
+                "peer serializes messages as {}, this side as {}",
+                remote.map_or("unknown format", Format::name),
+                local
+            ),
+            HandshakeError::Stale { skew } => write!(
+                f,
+                "client hello timestamp is {} s away from the server clock",
+                skew
+            ),
+            HandshakeError::Replayed => f.write_str("client hello replayed"),
+            HandshakeError::Malformed(reason) => write!(f, "malformed handshake: {}", reason),
+        }
+    }
+}
+
+impl error::Error for HandshakeError {}
+
+impl From<HandshakeError> for io::Error {
+    fn from(e: HandshakeError) -> Self {
+        let kind = match e {
+            HandshakeError::Authentication
+            | HandshakeError::Stale { .. }
+            | HandshakeError::Replayed => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+pub(crate) async fn client<S>(
+    io: &mut S,
+    psk: &Key,
+    ciphers: &[Cipher],
+    options: u8,
+    format: Format,
+) -> io::Result<Session>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (key_pair, client_public) = generate()?;
+
+    // client hello
+    let mut body = now().to_be_bytes().to_vec();
+    body.extend(offer(ciphers, options, format));
+    let hello = write_hello(io, psk, CLIENT_LABEL, &client_public, &[], &body).await?;
+
+    // server hello
+    let (reply, server_public, body, _) = read_hello(io, &[psk], SERVER_LABEL, &hello).await?;
+    let (cipher, agreed) = parse_reply(&body, ciphers, options, format)?;
+
+    let (c2s, s2c) = derive(&key_pair, &server_public, psk, &[&hello, &reply])?;
+    Ok(Session::new(Role::Client, cipher, c2s, s2c).with_options(agreed))
+}
+
+/// Runs the server side, accepting a client that holds any of `keys`, and
+/// returns the session with the index of the client's key.
+pub(crate) async fn server<S>(
+    io: &mut S,
+    keys: &[&Key],
+    ciphers: &[Cipher],
+    options: u8,
+    format: Format,
+    replay: &ReplayCache,
+) -> io::Result<(Session, usize)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // client hello
+    let (hello, client_public, body, index) = read_hello(io, keys, CLIENT_LABEL, &[]).await?;
+    let psk = keys[index];
+    let body = check_fresh(&body, &client_public, replay)?;
+    let (offered, client_options, client_format) = parse_offer(body)?;
+    let cipher = ciphers.iter().copied().find(|c| offered.contains(c));
+    let agreed = options & client_options;
+
+    // server hello
+    let (key_pair, server_public) = generate()?;
+    let body = reply(cipher, agreed, format, ciphers);
+    let reply = write_hello(io, psk, SERVER_LABEL, &server_public, &hello, &body).await?;
+
+    let cipher = cipher.ok_or_else(|| HandshakeError::NoCommonCipher {
+        offered,
+        supported: ciphers.to_vec(),
+    })?;
+    check_format(format, client_format)?;
+
+    let (c2s, s2c) = derive(&key_pair, &client_public, psk, &[&hello, &reply])?;
+    let session = Session::new(Role::Server, cipher, c2s, s2c).with_options(agreed);
+    Ok((session, index))
+}
+
+/// Checks the timestamp leading a client hello `body` and records the
+/// client's ephemeral `public` key, returning the rest of the body.
+pub(crate) fn check_fresh<'a>(
+    body: &'a [u8],
+    public: &[u8],
+    replay: &ReplayCache,
+) -> Result<&'a [u8], HandshakeError> {
+    if body.len() < 8 {
+        return Err(HandshakeError::Malformed("missing timestamp"));
+    }
+    let (timestamp, body) = body.split_at(8);
+
+    // 重放检查: 时间戳超出窗口的一半, 或公钥已出现过, 不回复
+    let timestamp = u64::from_be_bytes(timestamp.try_into().unwrap());
+    let skew = now().abs_diff(timestamp);
+    if skew > replay.window().as_secs() / 2 {
+        return Err(HandshakeError::Stale { skew });
+    }
+    if !replay.insert(public) {
+        return Err(HandshakeError::Replayed);
+    }
+    Ok(body)
+}
+
+/// Encodes `count | ids | options | format`.
+pub(crate) fn offer(ciphers: &[Cipher], options: u8, format: Format) -> Vec<u8> {
+    let mut offer = vec![ciphers.len() as u8];
+    offer.extend(ciphers.iter().map(|c| c.id()));
+    offer.push(options);
+    offer.push(format.id());
+    offer
+}
+
+/// Decodes an offer written by [`offer`], skipping unknown ciphers.
+pub(crate) fn parse_offer(
+    offer: &[u8],
+) -> Result<(Vec<Cipher>, u8, Option<Format>), HandshakeError> {
+    let (count, rest) = offer
+        .split_first()
+        .ok_or(HandshakeError::Malformed("missing cipher list"))?;
+    let count = *count as usize;
+    if count > MAX_CIPHERS || rest.len() != count + 2 {
+        return Err(HandshakeError::Malformed("bad cipher list"));
+    }
+    // 忽略不认识的 cipher
+    let ciphers = rest[..count]
+        .iter()
+        .copied()
+        .filter_map(Cipher::from_id)
+        .collect();
+    Ok((ciphers, rest[count], Format::from_id(rest[count + 1])))
+}
+
+/// Fails unless the peer uses the `local` format.
+pub(crate) fn check_format(local: Format, remote: Option<Format>) -> Result<(), HandshakeError> {
+    if remote == Some(local) {
+        Ok(())
+    } else {
+        Err(HandshakeError::FormatMismatch { local, remote })
+    }
+}
+
+/// Encodes the server's `chosen cipher id | options | format` answer, followed
+/// by `count | ids` of its `supported` ciphers when none was chosen.
+pub(crate) fn reply(
+    cipher: Option<Cipher>,
+    options: u8,
+    format: Format,
+    supported: &[Cipher],
+) -> Vec<u8> {
+    let mut reply = vec![cipher.map_or(0, Cipher::id), options, format.id()];
+    if cipher.is_none() {
+        reply.push(supported.len() as u8);
+        reply.extend(supported.iter().map(|c| c.id()));
+    }
+    reply
+}
+
+/// Decodes an answer written by [`reply`] to an offer of `ciphers`, `options`
+/// and `format`, ignoring what follows it.
+pub(crate) fn parse_reply(
+    reply: &[u8],
+    ciphers: &[Cipher],
+    options: u8,
+    format: Format,
+) -> io::Result<(Cipher, u8)> {
+    let (id, agreed, server_format, rest) = match *reply {
+        [id, agreed, server_format, ref rest @ ..] => (id, agreed, server_format, rest),
+        _ => return Err(HandshakeError::Malformed("bad server reply").into()),
+    };
+    if agreed & !options != 0 {
+        let reason = "server enabled an option that was not offered";
+        return Err(HandshakeError::Malformed(reason).into());
+    }
+    let cipher = match Cipher::from_id(id) {
+        Some(cipher) if ciphers.contains(&cipher) => cipher,
+        _ if id == 0 => {
+            let supported = match rest.split_first() {
+                Some((&count, ids)) if ids.len() >= count as usize => &ids[..count as usize],
+                _ => return Err(HandshakeError::Malformed("missing cipher list").into()),
+            };
+            return Err(HandshakeError::NoCommonCipher {
+                offered: ciphers.to_vec(),
+                supported: supported
+                    .iter()
+                    .copied()
+                    .filter_map(Cipher::from_id)
+                    .collect(),
+            }
+            .into());
+        }
+        _ => {
+            let reason = "server chose a cipher that was not offered";
+            return Err(HandshakeError::Malformed(reason).into());
+        }
+    };
+    check_format(format, Format::from_id(server_format))?;
+    Ok((cipher, agreed))
+}
+
+/// Writes `public | seal(len) | seal(body)` and returns the bytes written.
+async fn write_hello<S>(
+    io: &mut S,
+    psk: &Key,
+    label: &[u8],
+    public: &[u8; PUBLIC_KEY_LEN],
+    aad: &[u8],
+    body: &[u8],
+) -> io::Result<Vec<u8>>
+where
+    S: AsyncWrite + Unpin,
+{
+    let key = hello_key(psk, label, public);
+    let mut msg = public.to_vec();
+
+    let mut len = (body.len() as u16).to_be_bytes().to_vec();
+    seal(&key, 0, aad, &mut len)?;
+    msg.extend_from_slice(&len);
+
+    let mut body = body.to_vec();
+    seal(&key, 1, aad, &mut body)?;
+    msg.extend_from_slice(&body);
+
+    io.write_all(&msg).await?;
+    io.flush().await?;
+    Ok(msg)
+}
+
+/// Reads a message written by [`write_hello`] under one of `keys`, returning
+/// the raw bytes, the public key, the opened body and the index of the key.
+async fn read_hello<S>(
+    io: &mut S,
+    keys: &[&Key],
+    label: &[u8],
+    aad: &[u8],
+) -> io::Result<(Vec<u8>, [u8; PUBLIC_KEY_LEN], Vec<u8>, usize)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut msg = vec![0; PUBLIC_KEY_LEN + 2 + TAG_LEN];
+    io.read_exact(&mut msg).await?;
+    let mut public = [0; PUBLIC_KEY_LEN];
+    public.copy_from_slice(&msg[..PUBLIC_KEY_LEN]);
+
+    // 逐个尝试, 能打开长度的即为对端使用的密钥
+    let (index, key, len) = keys
+        .iter()
+        .enumerate()
+        .find_map(|(index, psk)| {
+            let key = hello_key(psk, label, &public);
+            let mut len = msg[PUBLIC_KEY_LEN..].to_vec();
+            let len = open(&key, 0, aad, &mut len).ok()?;
+            Some((index, key, u16::from_be_bytes([len[0], len[1]]) as usize))
+        })
+        .ok_or(HandshakeError::Authentication)?;
+    if len > MAX_HELLO_LEN {
+        return Err(HandshakeError::Malformed("hello too long").into());
+    }
+
+    let start = msg.len();
+    msg.resize(start + len + TAG_LEN, 0);
+    io.read_exact(&mut msg[start..]).await?;
+    let mut body = msg[start..].to_vec();
+    let len = open(&key, 1, aad, &mut body)?.len();
+    body.truncate(len);
+
+    Ok((msg, public, body, index))
+}
+
+/// Key sealing one hello, known to every holder of the pre-shared key.
+fn hello_key(psk: &Key, label: &[u8], public: &[u8]) -> LessSafeKey {
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, public).extract(psk.as_bytes());
+    let info = [label];
+    let okm = prk.expand(&info, &CHACHA20_POLY1305).unwrap();
+    LessSafeKey::new(UnboundKey::from(okm))
+}
+
+fn seal(key: &LessSafeKey, seq: u8, aad: &[u8], data: &mut Vec<u8>) -> io::Result<()> {
+    key.seal_in_place_append_tag(nonce(seq), Aad::from(aad), data)
+        .map_err(|_| crypto_error())
+}
+
+fn open<'a>(key: &LessSafeKey, seq: u8, aad: &[u8], data: &'a mut [u8]) -> io::Result<&'a [u8]> {
+    key.open_in_place(nonce(seq), Aad::from(aad), data)
+        .map(|data| &*data)
+        .map_err(|_| HandshakeError::Authentication.into())
+}
+
+fn nonce(seq: u8) -> Nonce {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[NONCE_LEN - 1] = seq;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// Generates an ephemeral key pair and a representative of its public key,
+/// the form sent in a hello.
+fn generate() -> io::Result<(KeyPair, [u8; PUBLIC_KEY_LEN])> {
+    let key_pair = elligator::generate()?;
+    let representative = elligator::encode(&key_pair.public)?;
+    Ok((key_pair, representative))
+}
+
+/// Derives the client-to-server and server-to-client traffic secrets from
+/// the representative of the peer's public key.
+fn derive(
+    key_pair: &KeyPair,
+    peer_public: &[u8; PUBLIC_KEY_LEN],
+    psk: &Key,
+    transcript: &[&[u8]],
+) -> io::Result<(hkdf::Prk, hkdf::Prk)> {
+    let peer_public = elligator::decode(peer_public);
+    let shared = elligator::agree(&key_pair.private, &peer_public)?;
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, psk.as_bytes()).extract(&shared);
+    let c2s = expand(&prk, CLIENT_TO_SERVER, transcript)?;
+    let s2c = expand(&prk, SERVER_TO_CLIENT, transcript)?;
+    Ok((c2s, s2c))
+}
+
+fn expand(prk: &hkdf::Prk, label: &[u8], transcript: &[&[u8]]) -> io::Result<hkdf::Prk> {
+    let mut info = vec![label];
+    info.extend_from_slice(transcript);
+    prk.expand(&info, hkdf::HKDF_SHA256)
+        .map(hkdf::Prk::from)
+        .map_err(|_| crypto_error())
+}
+
+/// Seconds since the Unix epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.as_secs())
+}
+
+fn names(ciphers: &[Cipher]) -> String {
+    ciphers
+        .iter()
+        .map(|c| c.name())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub(crate) fn crypto_error() -> io::Error {
+    io::Error::other("handshake crypto error")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    /// Client hello under `psk` dated `timestamp`.
+    async fn hello(psk: &Key, timestamp: u64) -> Vec<u8> {
+        let (_, public) = generate().unwrap();
+        let mut body = timestamp.to_be_bytes().to_vec();
+        body.extend(offer(&Cipher::ALL, 0, Format::default()));
+        let mut hello = Vec::new();
+        write_hello(&mut hello, psk, CLIENT_LABEL, &public, &[], &body)
+            .await
+            .unwrap();
+        hello
+    }
+
+    async fn accept(hello: &[u8], psk: &Key, replay: &ReplayCache) -> io::Result<()> {
+        let (mut client, mut io) = duplex(4096);
+        client.write_all(hello).await?;
+        let ciphers = Cipher::ALL;
+        server(&mut io, &[psk], &ciphers, 0, Format::default(), replay).await?;
+        Ok(())
+    }
+
+    fn handshake_error(e: io::Error) -> HandshakeError {
+        e.into_inner()
+            .unwrap()
+            .downcast::<HandshakeError>()
+            .map(|e| *e)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn cipher_mismatch_fails_both_sides() {
+        let psk = Key::new(&[7; 32]).unwrap();
+        let replay = ReplayCache::default();
+        let (mut a, mut b) = duplex(4096);
+        let format = Format::default();
+        let keys = [&psk];
+        let (client_side, server_side) = tokio::join!(
+            client(&mut a, &psk, &[Cipher::ChaCha20Poly1305], 0, format),
+            server(&mut b, &keys, &[Cipher::Aes256Gcm], 0, format, &replay),
+        );
+
+        for e in [client_side.err().unwrap(), server_side.err().unwrap()] {
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            match handshake_error(e) {
+                HandshakeError::NoCommonCipher { offered, supported } => {
+                    assert_eq!(offered, [Cipher::ChaCha20Poly1305]);
+                    assert_eq!(supported, [Cipher::Aes256Gcm]);
+                }
+                e => panic!("unexpected error {}", e),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn replayed_hello_is_rejected() {
+        let psk = Key::new(&[7; 32]).unwrap();
+        let replay = ReplayCache::default();
+        let hello = hello(&psk, now()).await;
+
+        accept(&hello, &psk, &replay).await.unwrap();
+        let e = accept(&hello, &psk, &replay).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert!(matches!(handshake_error(e), HandshakeError::Replayed));
+    }
+
+    #[tokio::test]
+    async fn stale_hello_is_rejected() {
+        let psk = Key::new(&[7; 32]).unwrap();
+        let replay = ReplayCache::default();
+        let window = replay.window().as_secs();
+
+        for timestamp in [0, now() - window, now() + window, u64::MAX] {
+            let hello = hello(&psk, timestamp).await;
+            let e = accept(&hello, &psk, &replay).await.unwrap_err();
+            assert!(matches!(handshake_error(e), HandshakeError::Stale { .. }));
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use ring::digest::{digest, SHA256};
+use std::{env, fmt, fs, io, path::Path, time::SystemTime};
+
+/// Length in bytes of a tunnel key.
+pub const KEY_LEN: usize = 32;
+
+/// Pre-shared tunnel key, or an X25519 key of a [`Noise`](super::Noise)
+/// handshake.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key([u8; KEY_LEN]);
+
+impl Key {
+    pub fn new(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() != KEY_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("key must be {} bytes, got {}", KEY_LEN, bytes.len()),
+            ));
+        }
+
+        let mut key = [0; KEY_LEN];
+        key.copy_from_slice(bytes);
+        Ok(Self(key))
+    }
+
+    /// Decodes a base64 encoded key.
+    pub fn from_base64(s: &str) -> io::Result<Self> {
+        let bytes = base64::decode(s.trim()).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid base64 key: {}", e),
+            )
+        })?;
+        Self::new(&bytes)
+    }
+
+    /// Reads a key file holding either the raw key bytes or their base64 encoding.
+    ///
+    /// A file that reads as text, UTF-8 without control characters other than
+    /// whitespace, is decoded as base64, anything else is the raw key. So a 32
+    /// character passphrase, or the base64 encoding of a key of another length,
+    /// is refused instead of being taken for a raw key.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        match std::str::from_utf8(&bytes) {
+            Ok(s) if !s.chars().any(|c| c.is_control() && !c.is_whitespace()) => {
+                Self::from_base64(s)
+            }
+            _ => Self::new(&bytes),
+        }
+    }
+
+    /// Reads a base64 encoded key from the environment variable `name`.
+    pub fn from_env(name: &str) -> io::Result<Self> {
+        match env::var(name) {
+            Ok(s) => Self::from_base64(&s),
+            Err(e) => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{}: {}", name, e),
+            )),
+        }
+    }
+
+    /// Encodes the key in base64, the form [`from_base64`](Self::from_base64)
+    /// reads.
+    pub fn to_base64(&self) -> String {
+        base64::encode(self.0)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Short identifier of the key, safe to log: the first 8 bytes of its
+    /// SHA-256 digest in hex.
+    pub fn fingerprint(&self) -> String {
+        digest(&SHA256, &self.0).as_ref()[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// Time span during which a key is accepted, unbounded on the sides left
+/// `None`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Validity {
+    pub not_before: Option<SystemTime>,
+    pub not_after: Option<SystemTime>,
+}
+
+impl Validity {
+    pub fn always() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, time: SystemTime) -> bool {
+        self.not_before.is_none_or(|t| t <= time) && self.not_after.is_none_or(|t| time < t)
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `contents` to a key file named after `test` and loads it.
+    fn load(test: &str, contents: &[u8]) -> io::Result<Key> {
+        let path = env::temp_dir().join(format!("yew-{}-{}", test, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        let key = Key::from_file(&path);
+        fs::remove_file(&path).unwrap();
+        key
+    }
+
+    #[test]
+    fn key_file_holds_raw_or_base64_key() {
+        let raw: Vec<u8> = (0..KEY_LEN as u8).collect();
+        assert_eq!(load("key-raw", &raw).unwrap().as_bytes(), &raw[..]);
+
+        let text = format!("{}\n", base64::encode(&raw));
+        assert_eq!(
+            load("key-base64", text.as_bytes()).unwrap().as_bytes(),
+            &raw[..]
+        );
+    }
+
+    #[test]
+    fn key_file_of_32_characters_must_be_base64() {
+        // 24 字节密钥的 base64 与口令都恰好是 32 个字符
+        let short = base64::encode([7; 24]);
+        let passphrase = "correct horse battery staple 123";
+        for text in [short.as_str(), passphrase] {
+            assert_eq!(text.len(), KEY_LEN);
+            let e = load("key-text", text.as_bytes()).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+        let e = load("key-short", short.as_bytes()).unwrap_err();
+        assert_eq!(e.to_string(), "key must be 32 bytes, got 24");
+    }
+
+    #[test]
+    fn raw_key_file_of_another_length_is_refused() {
+        let e = load("key-raw-short", &[0; KEY_LEN - 1]).unwrap_err();
+        assert_eq!(e.to_string(), "key must be 32 bytes, got 31");
+    }
+}
@@ -0,0 +1,912 @@
+//! Byte stream over short HTTP/1.1 requests, for networks whose proxies
+//! allow plain HTTP but end long-lived connections and upgrades.
+//!
+//! The client opens a session with a random id, then `POST`s what is
+//! written as request bodies and `GET`s what is read as response bodies,
+//! each request numbered within its direction. A `GET` is held by the server
+//! until there is data or a poll timeout passes. A request whose answer is
+//! lost is sent again with the same number, which the server recognizes, so
+//! nothing is lost or repeated and a [`Transport`] runs over the resulting
+//! [`LongPollStream`] as over any other connection.
+//!
+//! [`Transport`]: super::Transport
+
+use super::tls::hex;
+use bytes::{Bytes, BytesMut};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{
+        AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf, ReadHalf,
+        WriteHalf,
+    },
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        Mutex as AsyncMutex,
+    },
+    time,
+};
+
+/// Largest body of a request or response.
+const MAX_BODY: usize = 256 * 1024;
+
+/// Largest request or status line with the headers.
+const MAX_HEAD: usize = 8 * 1024;
+
+/// How long the server holds a `GET` without data, below the idle timeouts
+/// of common proxies.
+const POLL_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// How long the client waits for an answer before sending the request again.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(40);
+
+/// Attempts of a request before the stream fails, and the pause between them.
+const ATTEMPTS: usize = 5;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// A session without requests for this long is dropped.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Bytes buffered in each direction between a stream and its requests.
+const BUFFER: usize = 256 * 1024;
+
+/// Length of session ids, in bytes.
+const SESSION_LEN: usize = 16;
+
+/// Path and `Host` of the requests.
+#[derive(Clone, Debug)]
+pub struct LongPoll {
+    path: String,
+    host: Option<String>,
+    proxy: bool,
+}
+
+impl LongPoll {
+    /// Sends and serves requests for `path`, such as `/poll`.
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            host: None,
+            proxy: false,
+        }
+    }
+
+    /// Sets the `Host` the client sends, with an optional port. A server
+    /// with a host only answers requests for it.
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+
+    /// Sends requests to an HTTP proxy, with the absolute URI of the server
+    /// as target.
+    pub fn proxy(mut self, proxy: bool) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn host_name(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    /// Opens a session, which needs a host, over connections made by
+    /// `connect` as needed.
+    ///
+    /// Requests run in the background until both sides have shut the
+    /// stream down, or the server stays unreachable for a few tries.
+    pub async fn connect<C, F, S>(&self, connect: C) -> io::Result<LongPollStream>
+    where
+        C: Fn() -> F + Send + Sync + 'static,
+        F: Future<Output = io::Result<S>> + Send + 'static,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let host = self
+            .host
+            .clone()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "long poll needs a host"))?;
+        let mut id = [0; SESSION_LEN];
+        SystemRandom::new()
+            .fill(&mut id)
+            .map_err(|_| io::Error::other("failed to generate a session id"))?;
+        let session = hex(&id);
+
+        let target = match self.proxy {
+            true => format!("http://{}{}?s={}", host, self.path, session),
+            false => format!("{}?s={}", self.path, session),
+        };
+        let connect = Arc::new(connect);
+        let mut requests = Requests {
+            connect: connect.clone(),
+            conn: None,
+            host: host.clone(),
+            target: target.clone(),
+        };
+        // 第 0 个 POST 建立 session
+        match requests.send("POST", 0, false, Bytes::new()).await? {
+            (200, _) => {}
+            (status, _) => return Err(status_error(status)),
+        }
+
+        let (inner, outer) = tokio::io::duplex(BUFFER);
+        let (reader, writer) = tokio::io::split(outer);
+        tokio::spawn(upload(reader, requests));
+        tokio::spawn(download(
+            writer,
+            Requests {
+                connect,
+                conn: None,
+                host,
+                target,
+            },
+        ));
+        Ok(LongPollStream { inner, session })
+    }
+}
+
+/// Sends what the stream writes, a `POST` at a time, then an empty one with
+/// `fin` once it is shut down.
+async fn upload<C, F, S>(
+    mut reader: ReadHalf<DuplexStream>,
+    mut requests: Requests<C, S>,
+) -> io::Result<()>
+where
+    C: Fn() -> F,
+    F: Future<Output = io::Result<S>>,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    for seq in 1.. {
+        // 请求进行中写入的数据由下一个请求一起发送
+        let mut body = BytesMut::with_capacity(MAX_BODY);
+        let fin = (&mut reader)
+            .take(MAX_BODY as u64)
+            .read_buf(&mut body)
+            .await?
+            == 0;
+        match requests.send("POST", seq, fin, body.freeze()).await? {
+            (200, _) if fin => break,
+            (200, _) => {}
+            (status, _) => return Err(status_error(status)),
+        }
+    }
+    Ok(())
+}
+
+/// Hands the bodies of `GET`s to the stream until the server shuts its side
+/// down, or the stream is dropped.
+async fn download<C, F, S>(
+    mut writer: WriteHalf<DuplexStream>,
+    mut requests: Requests<C, S>,
+) -> io::Result<()>
+where
+    C: Fn() -> F,
+    F: Future<Output = io::Result<S>>,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    for seq in 1.. {
+        match requests.send("GET", seq, false, Bytes::new()).await? {
+            (200, body) => writer.write_all(&body).await?,
+            (410, _) => break,
+            (status, _) => return Err(status_error(status)),
+        }
+    }
+    writer.shutdown().await
+}
+
+/// Requests of one direction of a session, over a connection kept open
+/// between them.
+struct Requests<C, S> {
+    connect: Arc<C>,
+    conn: Option<HttpConn<S>>,
+    host: String,
+    target: String,
+}
+
+impl<C, F, S> Requests<C, S>
+where
+    C: Fn() -> F,
+    F: Future<Output = io::Result<S>>,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Sends request `seq`, again on a new connection if it fails, and
+    /// returns the status and body of the answer.
+    async fn send(
+        &mut self,
+        method: &str,
+        seq: u64,
+        fin: bool,
+        body: Bytes,
+    ) -> io::Result<(u16, Bytes)> {
+        let target = match fin {
+            true => format!("{}&q={}&fin=1", self.target, seq),
+            false => format!("{}&q={}", self.target, seq),
+        };
+        let mut attempt = 1;
+        loop {
+            let result = time::timeout(REQUEST_TIMEOUT, self.exchange(method, &target, &body))
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+            match result {
+                // 代理返回的 5xx 同样重试
+                Ok((status, body)) if status < 500 => return Ok((status, body)),
+                Ok((status, _)) if attempt == ATTEMPTS => return Err(status_error(status)),
+                Err(e) if attempt == ATTEMPTS => return Err(e),
+                _ => {}
+            }
+            self.conn = None;
+            attempt += 1;
+            time::sleep(RETRY_DELAY).await;
+        }
+    }
+
+    async fn exchange(
+        &mut self,
+        method: &str,
+        target: &str,
+        body: &[u8],
+    ) -> io::Result<(u16, Bytes)> {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => self.conn.insert(HttpConn::new((self.connect)().await?)),
+        };
+        let head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nCache-Control: no-cache\r\n\r\n",
+            method,
+            target,
+            self.host,
+            body.len()
+        );
+        conn.write(head.as_bytes(), body).await?;
+        let response = conn.read_response().await?;
+        if response.close {
+            self.conn = None;
+        }
+        Ok((response.status, response.body))
+    }
+}
+
+/// Server side of the sessions of [`LongPoll`].
+///
+/// Each connection is handed to [`serve`](Self::serve), and each session
+/// a client opens is yielded by [`accept`](Self::accept).
+#[derive(Clone)]
+pub struct LongPollServer {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    config: LongPoll,
+    session_timeout: Duration,
+    sessions: Mutex<HashMap<String, Arc<Session>>>,
+    accept_sender: UnboundedSender<LongPollStream>,
+    accept_receiver: AsyncMutex<UnboundedReceiver<LongPollStream>>,
+}
+
+impl LongPollServer {
+    pub fn new(config: LongPoll) -> Self {
+        let (accept_sender, accept_receiver) = mpsc::unbounded_channel();
+        Self {
+            inner: Arc::new(Inner {
+                config,
+                session_timeout: SESSION_TIMEOUT,
+                sessions: Mutex::default(),
+                accept_sender,
+                accept_receiver: AsyncMutex::new(accept_receiver),
+            }),
+        }
+    }
+
+    pub fn config(&self) -> &LongPoll {
+        &self.inner.config
+    }
+
+    /// Drops sessions after `timeout` without requests instead of
+    /// [`SESSION_TIMEOUT`], before the server is cloned.
+    #[cfg(test)]
+    fn with_session_timeout(mut self, timeout: Duration) -> Self {
+        Arc::get_mut(&mut self.inner).unwrap().session_timeout = timeout;
+        self
+    }
+
+    /// Waits for the next session.
+    pub async fn accept(&self) -> Option<LongPollStream> {
+        self.inner.accept_receiver.lock().await.recv().await
+    }
+
+    /// Answers the requests read from `io` until the client closes it.
+    /// Requests for another path or host get a 404.
+    pub async fn serve<S>(&self, io: S) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut conn = HttpConn::new(io);
+        while let Some(request) = conn.read_request().await? {
+            let (status, body) = self.handle(&request).await;
+            let head = format!(
+                "HTTP/1.1 {} {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nCache-Control: no-store\r\n{}\r\n",
+                status,
+                reason(status),
+                body.len(),
+                if request.close { "Connection: close\r\n" } else { "" }
+            );
+            conn.write(head.as_bytes(), &body).await?;
+            if request.close {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle(&self, request: &HttpRequest) -> (u16, Bytes) {
+        let config = &self.inner.config;
+        let wanted = config
+            .host
+            .as_deref()
+            .is_none_or(|h| request.host.as_deref() == Some(h));
+        let query = match request.target.split_once('?') {
+            Some((path, query)) if path == config.path && wanted => query,
+            _ => return (404, Bytes::new()),
+        };
+        let (id, seq, fin) = match parse_query(query) {
+            Some(query) => query,
+            None => return (400, Bytes::new()),
+        };
+
+        let session = self.inner.sessions.lock().unwrap().get(id).cloned();
+        let session = match (session, request.method.as_str(), seq) {
+            (Some(session), _, _) => session,
+            (None, "POST", 0) => match self.open(id) {
+                Some(session) => session,
+                None => return (503, Bytes::new()),
+            },
+            (None, _, _) => return (404, Bytes::new()),
+        };
+        *session.last_seen.lock().unwrap() = Instant::now();
+
+        match request.method.as_str() {
+            "POST" if seq == 0 => (200, Bytes::new()),
+            "POST" => (session.upload(seq, fin, &request.body).await, Bytes::new()),
+            "GET" => session.download(seq).await,
+            _ => (405, Bytes::new()),
+        }
+    }
+
+    /// Starts session `id`, which is dropped once idle, `None` if nothing
+    /// accepts sessions anymore.
+    fn open(&self, id: &str) -> Option<Arc<Session>> {
+        // 同一 session 的并发请求只建立一次
+        let mut sessions = self.inner.sessions.lock().unwrap();
+        if let Some(session) = sessions.get(id) {
+            return Some(session.clone());
+        }
+        let (inner, outer) = tokio::io::duplex(BUFFER);
+        let (reader, writer) = tokio::io::split(outer);
+        let stream = LongPollStream {
+            inner,
+            session: id.to_owned(),
+        };
+        self.inner.accept_sender.send(stream).ok()?;
+        let session = Arc::new(Session {
+            upload: AsyncMutex::new(Upload {
+                received: 0,
+                writer,
+            }),
+            download: AsyncMutex::new(Download {
+                sent: 0,
+                last: Bytes::new(),
+                eof: false,
+                reader,
+            }),
+            last_seen: Mutex::new(Instant::now()),
+        });
+        sessions.insert(id.to_owned(), session.clone());
+
+        let weak = Arc::downgrade(&session);
+        let inner = self.inner.clone();
+        let id = id.to_owned();
+        tokio::spawn(async move {
+            while let Some(session) = weak.upgrade() {
+                let idle = session.last_seen.lock().unwrap().elapsed();
+                drop(session);
+                if idle > inner.session_timeout {
+                    inner.sessions.lock().unwrap().remove(&id);
+                    break;
+                }
+                time::sleep(inner.session_timeout / 4).await;
+            }
+        });
+        Some(session)
+    }
+}
+
+/// Parses `s=<session>&q=<seq>[&fin=1]`.
+fn parse_query(query: &str) -> Option<(&str, u64, bool)> {
+    let (mut id, mut seq, mut fin) = (None, None, false);
+    for pair in query.split('&') {
+        match pair.split_once('=')? {
+            ("s", s) if s.len() == SESSION_LEN * 2 && s.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                id = Some(s)
+            }
+            ("q", q) => seq = Some(q.parse().ok()?),
+            ("fin", "1") => fin = true,
+            _ => return None,
+        }
+    }
+    Some((id?, seq?, fin))
+}
+
+/// Both directions of a session on the server.
+struct Session {
+    upload: AsyncMutex<Upload>,
+    download: AsyncMutex<Download>,
+    last_seen: Mutex<Instant>,
+}
+
+/// Side of the stream the `POST`s write to.
+struct Upload {
+    /// Number of the last `POST` written.
+    received: u64,
+    writer: WriteHalf<DuplexStream>,
+}
+
+/// Side of the stream the `GET`s read from.
+struct Download {
+    /// Number of the last `GET` answered, and its body, sent again if the
+    /// client asks for it again.
+    sent: u64,
+    last: Bytes,
+    eof: bool,
+    reader: ReadHalf<DuplexStream>,
+}
+
+impl Session {
+    /// Writes the body of `POST` `seq` to the stream, once, and returns the
+    /// status of the answer.
+    async fn upload(&self, seq: u64, fin: bool, body: &[u8]) -> u16 {
+        let mut upload = self.upload.lock().await;
+        if seq <= upload.received {
+            // 已写入, 客户端没有收到应答
+            return 200;
+        }
+        if seq != upload.received + 1 {
+            return 409;
+        }
+        if upload.writer.write_all(body).await.is_err() {
+            return 410;
+        }
+        if fin {
+            let _ = upload.writer.shutdown().await;
+        }
+        upload.received = seq;
+        200
+    }
+
+    /// Waits for the body of `GET` `seq`, empty after [`POLL_TIMEOUT`], and
+    /// returns a 410 once the stream is shut down.
+    async fn download(&self, seq: u64) -> (u16, Bytes) {
+        let mut download = self.download.lock().await;
+        if seq == download.sent {
+            return (200, download.last.clone());
+        }
+        if seq != download.sent + 1 {
+            return (409, Bytes::new());
+        }
+        if download.eof {
+            return (410, Bytes::new());
+        }
+
+        let mut body = BytesMut::with_capacity(MAX_BODY);
+        let mut reader = (&mut download.reader).take(MAX_BODY as u64);
+        let read = reader.read_buf(&mut body);
+        match time::timeout(POLL_TIMEOUT, read).await {
+            Ok(Ok(0)) | Ok(Err(_)) => {
+                download.eof = true;
+                return (410, Bytes::new());
+            }
+            Ok(Ok(_)) | Err(_) => {}
+        }
+        download.sent = seq;
+        download.last = body.freeze();
+        (200, download.last.clone())
+    }
+}
+
+/// Bytes carried by the requests of a session.
+pub struct LongPollStream {
+    inner: DuplexStream,
+    session: String,
+}
+
+impl LongPollStream {
+    /// Id of the session, in hex.
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+}
+
+impl AsyncRead for LongPollStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for LongPollStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    target: String,
+    host: Option<String>,
+    close: bool,
+    body: Bytes,
+}
+
+struct HttpResponse {
+    status: u16,
+    close: bool,
+    body: Bytes,
+}
+
+/// HTTP/1.1 connection, with bodies of a `Content-Length`.
+struct HttpConn<S> {
+    io: S,
+    buf: BytesMut,
+}
+
+impl<S> HttpConn<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn new(io: S) -> Self {
+        Self {
+            io,
+            buf: BytesMut::new(),
+        }
+    }
+
+    async fn write(&mut self, head: &[u8], body: &[u8]) -> io::Result<()> {
+        self.io.write_all(head).await?;
+        self.io.write_all(body).await?;
+        self.io.flush().await
+    }
+
+    /// Reads the next request, `None` if the connection closes before it.
+    async fn read_request(&mut self) -> io::Result<Option<HttpRequest>> {
+        let len = match self.read_head().await? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut request = httparse::Request::new(&mut headers);
+        request.parse(&self.buf[..len]).map_err(http_error)?;
+        let method = request.method.unwrap_or_default().to_owned();
+        // 经过代理时为绝对 URI
+        let target = request.path.unwrap_or_default();
+        let target = match target.strip_prefix("http://") {
+            Some(rest) => rest.find('/').map_or("/", |i| &rest[i..]),
+            None => target,
+        }
+        .to_owned();
+        let host = header(request.headers, "host").map(str::to_owned);
+        let close = closes(request.version, request.headers);
+        let content_len = content_len(request.headers)?;
+
+        let body = self.read_body(len, content_len).await?;
+        Ok(Some(HttpRequest {
+            method,
+            target,
+            host,
+            close,
+            body,
+        }))
+    }
+
+    async fn read_response(&mut self) -> io::Result<HttpResponse> {
+        let len = self
+            .read_head()
+            .await?
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut response = httparse::Response::new(&mut headers);
+        response.parse(&self.buf[..len]).map_err(http_error)?;
+        let status = response.code.unwrap_or_default();
+        let close = closes(response.version, response.headers);
+        let content_len = content_len(response.headers)?;
+
+        let body = self.read_body(len, content_len).await?;
+        Ok(HttpResponse {
+            status,
+            close,
+            body,
+        })
+    }
+
+    /// Reads until the end of the head, returning its length.
+    async fn read_head(&mut self) -> io::Result<Option<usize>> {
+        loop {
+            if let Some(i) = self.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                return Ok(Some(i + 4));
+            }
+            if self.buf.len() > MAX_HEAD {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "http head too long",
+                ));
+            }
+            if self.io.read_buf(&mut self.buf).await? == 0 {
+                return match self.buf.is_empty() {
+                    true => Ok(None),
+                    false => Err(io::ErrorKind::UnexpectedEof.into()),
+                };
+            }
+        }
+    }
+
+    /// Reads the body of `len` bytes after the head, dropping both from the
+    /// buffer.
+    async fn read_body(&mut self, head: usize, len: usize) -> io::Result<Bytes> {
+        if len > MAX_BODY {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "http body too long",
+            ));
+        }
+        while self.buf.len() < head + len {
+            if self.io.read_buf(&mut self.buf).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        let _ = self.buf.split_to(head);
+        Ok(self.buf.split_to(len).freeze())
+    }
+}
+
+fn header<'a>(headers: &[httparse::Header<'a>], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .and_then(|h| std::str::from_utf8(h.value).ok())
+}
+
+fn content_len(headers: &[httparse::Header<'_>]) -> io::Result<usize> {
+    if header(headers, "transfer-encoding").is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "chunked http bodies are not supported",
+        ));
+    }
+    match header(headers, "content-length") {
+        Some(len) => len
+            .trim()
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid content length")),
+        None => Ok(0),
+    }
+}
+
+/// Whether the connection closes after this message: HTTP/1.0 or
+/// `Connection: close`.
+fn closes(version: Option<u8>, headers: &[httparse::Header<'_>]) -> bool {
+    let connection = header(headers, "connection").unwrap_or_default();
+    version == Some(0) || connection.eq_ignore_ascii_case("close")
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        410 => "Gone",
+        _ => "Service Unavailable",
+    }
+}
+
+fn status_error(status: u16) -> io::Error {
+    let kind = match status {
+        404 | 410 => io::ErrorKind::NotFound,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("long poll: http status {}", status))
+}
+
+fn http_error(e: httparse::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("http: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LongPoll, LongPollServer, Requests};
+    use crate::{
+        client, server,
+        testing::{builder, exchange},
+    };
+    use bytes::Bytes;
+    use futures::future::BoxFuture;
+    use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        time,
+    };
+
+    const SESSION: &str = "00112233445566778899aabbccddeeff";
+
+    fn config() -> LongPoll {
+        LongPoll::new("/poll").host("example.com")
+    }
+
+    /// Serves the connections of a local port with `server`.
+    async fn listen(server: LongPollServer) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                let server = server.clone();
+                tokio::spawn(async move { server.serve(conn).await });
+            }
+        });
+        addr
+    }
+
+    /// Requests of session [`SESSION`] to `addr`, numbered by hand.
+    fn requests(
+        addr: SocketAddr,
+    ) -> Requests<impl Fn() -> BoxFuture<'static, io::Result<TcpStream>>, TcpStream> {
+        let connect = move || -> BoxFuture<'static, io::Result<TcpStream>> {
+            Box::pin(TcpStream::connect(addr))
+        };
+        Requests {
+            connect: Arc::new(connect),
+            conn: None,
+            host: "example.com".to_owned(),
+            target: format!("/poll?s={}", SESSION),
+        }
+    }
+
+    #[tokio::test]
+    async fn channels_over_long_polling() {
+        let long_poll = LongPollServer::new(config());
+        let addr = listen(long_poll.clone()).await;
+        let server = tokio::spawn(async move {
+            let stream = long_poll.accept().await.unwrap();
+            server::accept(stream, builder()).await
+        });
+
+        let stream = config()
+            .connect(move || TcpStream::connect(addr))
+            .await
+            .unwrap();
+        let mut client = client::connect(stream, builder()).await.unwrap();
+
+        exchange(&mut client, server.await.unwrap().unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn idle_session_expires() {
+        let long_poll =
+            LongPollServer::new(config()).with_session_timeout(Duration::from_millis(200));
+        let addr = listen(long_poll.clone()).await;
+        let mut requests = requests(addr);
+
+        assert_eq!(
+            requests
+                .send("POST", 0, false, Bytes::new())
+                .await
+                .unwrap()
+                .0,
+            200
+        );
+        let mut stream = long_poll.accept().await.unwrap();
+        assert_eq!(stream.session(), SESSION);
+        assert_eq!(
+            requests.send("POST", 1, false, "a".into()).await.unwrap().0,
+            200
+        );
+
+        time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(
+            requests.send("POST", 2, false, "b".into()).await.unwrap().0,
+            404
+        );
+        let mut read = Vec::new();
+        stream.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, b"a");
+    }
+
+    #[tokio::test]
+    async fn requests_are_applied_once_and_in_order() {
+        let long_poll = LongPollServer::new(config());
+        let addr = listen(long_poll.clone()).await;
+        let mut requests = requests(addr);
+
+        assert_eq!(
+            requests
+                .send("POST", 0, false, Bytes::new())
+                .await
+                .unwrap()
+                .0,
+            200
+        );
+        let mut stream = long_poll.accept().await.unwrap();
+
+        // 跳过的请求被拒绝, 重复的请求只写入一次
+        assert_eq!(
+            requests.send("POST", 2, false, "b".into()).await.unwrap().0,
+            409
+        );
+        assert_eq!(
+            requests.send("POST", 1, false, "a".into()).await.unwrap().0,
+            200
+        );
+        assert_eq!(
+            requests.send("POST", 1, false, "a".into()).await.unwrap().0,
+            200
+        );
+        assert_eq!(
+            requests.send("POST", 2, true, "b".into()).await.unwrap().0,
+            200
+        );
+        let mut read = Vec::new();
+        stream.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, b"ab");
+
+        stream.write_all(b"xyz").await.unwrap();
+        stream.shutdown().await.unwrap();
+        assert_eq!(
+            requests
+                .send("GET", 2, false, Bytes::new())
+                .await
+                .unwrap()
+                .0,
+            409
+        );
+        assert_eq!(
+            requests.send("GET", 1, false, Bytes::new()).await.unwrap(),
+            (200, "xyz".into())
+        );
+        assert_eq!(
+            requests.send("GET", 1, false, Bytes::new()).await.unwrap(),
+            (200, "xyz".into())
+        );
+        assert_eq!(
+            requests
+                .send("GET", 2, false, Bytes::new())
+                .await
+                .unwrap()
+                .0,
+            410
+        );
+    }
+}
@@ -13,8 +13,63 @@
     }
 } */
 
+mod key;
+pub use key::*;
+
+mod users;
+pub use users::Users;
+
+mod builder;
+pub use builder::*;
+
+mod format;
+pub use format::{Format, WithPayload};
+
 mod serde_transport;
 pub use serde_transport::*;
 
+mod stats;
+pub use stats::Stats;
+
+mod cipher;
+pub use cipher::Cipher;
+
+mod compression;
+
+mod shaping;
+pub(crate) use shaping::Cover;
+pub use shaping::{Padding, MAX_PADDING};
+
+mod fallback;
+
+mod replay;
+pub use replay::ReplayCache;
+
+mod elligator;
+
+mod handshake;
+pub use handshake::{HandshakeError, Role};
+
+mod noise;
+pub use noise::{Noise, NoisePattern};
+
+mod framing;
+pub use framing::{CustomCodec, FrameCodec};
+
 mod safe_codec;
 pub use safe_codec::*;
+
+mod plain_codec;
+pub use plain_codec::PlainCodec;
+
+mod websocket;
+pub use websocket::{WebSocket, WsStream};
+
+mod tls;
+pub use tls::{Certificate, TlsClient, TlsClientStream, TlsServer, TlsServerStream, Verification};
+
+mod long_poll;
+pub use long_poll::{LongPoll, LongPollServer, LongPollStream};
+
+mod quic;
+pub use quic::{Connection, Incoming, QuicClient, QuicServer, QuicStream};
@@ -0,0 +1,691 @@
+//! [Noise](https://noiseprotocol.org/noise.html) handshake, run in place of
+//! the pre-shared key handshake when a [`Noise`] configuration is set.
+//!
+//! ```text
+//! NK: -> e, es                 (timestamp | cipher count | cipher ids | options | format)
+//!     <- e, ee                 (chosen cipher id | options | format [| cipher count | cipher ids])
+//! IK: -> e, es, s, ss          (timestamp | cipher count | cipher ids | options | format)
+//!     <- e, ee, se             (chosen cipher id | options | format [| cipher count | cipher ids])
+//! XX: -> e                     ()
+//!     <- e, ee, s, es          (cipher count | cipher ids | options | format)
+//!     -> s, se                 (cipher count | cipher ids | options | format)
+//! ```
+//!
+//! Payloads are in parentheses, cipher lists and replies are padded with
+//! zeros to [`MAX_CIPHERS`](handshake::MAX_CIPHERS) ids so that the length of
+//! each message follows from the pattern and is not sent. The ephemeral keys
+//! are sent as their [Elligator 2](super::elligator) representatives and the
+//! rest is encrypted, no message can be told from random bytes. The pattern
+//! uses X25519, ChaCha20-Poly1305 and BLAKE2s, the ciphers listed in the
+//! payloads only select the one framing the [`Transport`](super::Transport)
+//! afterwards, keyed from the final Noise cipher states. The server picks the
+//! first of its own ciphers that the client offers, in XX both sides learn
+//! both lists and pick the same one without a further message. Either way a
+//! mismatch fails both sides with the same
+//! [`NoCommonCipher`](HandshakeError::NoCommonCipher) error. Options are
+//! agreed the same way, and either side fails when the formats differ.
+//!
+//! With NK only the server is authenticated, any client that knows its
+//! public key is accepted. With XX and IK the server only accepts clients
+//! whose static public key belongs to one of its [`Users`](super::Users).
+//! An XX client checks the server's static key against the one set with
+//! [`Noise::remote`] before sending its own, without one it accepts any
+//! server and only the client is authenticated.
+//! NK and IK client messages carry a timestamp and are checked against the
+//! [`ReplayCache`] like pre-shared key hellos, and the server does not answer
+//! a first message it cannot decrypt. An XX server answers any first message,
+//! it is not probe resistant. Nothing in that message is authenticated, so it
+//! is not recorded in the cache either, where it would only let anyone fill
+//! the filters shared with the other handshakes.
+
+use super::{
+    elligator::{self, KeyPair},
+    handshake::{self, HandshakeError, Role, Session},
+    Cipher, Format, Key, ReplayCache,
+};
+use curve25519_dalek::MontgomeryPoint;
+use ring::hkdf;
+use snow::{
+    params::{CipherChoice, DHChoice, HashChoice},
+    resolvers::{CryptoResolver, DefaultResolver},
+    types::{self, Random},
+    HandshakeState,
+};
+use std::{convert::TryInto, fmt, io, str::FromStr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const KEY_LEN: usize = elligator::KEY_LEN;
+const TAG_LEN: usize = 16;
+/// Encrypted static key.
+const STATIC_LEN: usize = KEY_LEN + TAG_LEN;
+const TIMESTAMP_LEN: usize = 8;
+/// Padded `cipher count | cipher ids | options | format`.
+const OFFER_LEN: usize = 1 + handshake::MAX_CIPHERS + 2;
+/// Padded `chosen cipher id | options | format [| cipher count | cipher ids]`.
+const REPLY_LEN: usize = 3 + 1 + handshake::MAX_CIPHERS;
+
+/// Noise handshake pattern.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoisePattern {
+    /// Client knows the server's static key, only the server is
+    /// authenticated.
+    Nk,
+    /// Both sides send their static key during the handshake.
+    Xx,
+    /// Client knows the server's static key and sends its own in the first
+    /// message.
+    Ik,
+}
+
+impl NoisePattern {
+    pub const ALL: [NoisePattern; 3] = [NoisePattern::Nk, NoisePattern::Xx, NoisePattern::Ik];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            NoisePattern::Nk => "NK",
+            NoisePattern::Xx => "XX",
+            NoisePattern::Ik => "IK",
+        }
+    }
+
+    /// Whether the client must know the server's static key beforehand.
+    pub fn needs_remote(self) -> bool {
+        self != NoisePattern::Xx
+    }
+
+    /// Whether the server authenticates the client's static key.
+    pub fn mutual(self) -> bool {
+        self != NoisePattern::Nk
+    }
+
+    /// Length of the `message`th message of the handshake, counted from 0.
+    fn message_len(self, message: usize) -> usize {
+        let payload = |len| len + TAG_LEN;
+        match (self, message) {
+            (NoisePattern::Nk, 0) => KEY_LEN + payload(TIMESTAMP_LEN + OFFER_LEN),
+            (NoisePattern::Ik, 0) => KEY_LEN + STATIC_LEN + payload(TIMESTAMP_LEN + OFFER_LEN),
+            (NoisePattern::Nk, _) | (NoisePattern::Ik, _) => KEY_LEN + payload(REPLY_LEN),
+            (NoisePattern::Xx, 0) => KEY_LEN,
+            (NoisePattern::Xx, 1) => KEY_LEN + STATIC_LEN + payload(OFFER_LEN),
+            (NoisePattern::Xx, _) => STATIC_LEN + payload(OFFER_LEN),
+        }
+    }
+
+    fn params(self) -> String {
+        format!("Noise_{}_25519_ChaChaPoly_BLAKE2s", self.name())
+    }
+}
+
+impl fmt::Display for NoisePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for NoisePattern {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|p| p.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unknown noise pattern {:?}", s),
+                )
+            })
+    }
+}
+
+/// Noise handshake configuration of one side.
+#[derive(Clone, Debug)]
+pub struct Noise {
+    pattern: NoisePattern,
+    local: Key,
+    remote: Option<Key>,
+}
+
+impl Noise {
+    /// `local` is this side's static X25519 private key, any 32 random bytes.
+    pub fn new(pattern: NoisePattern, local: Key) -> Self {
+        Self {
+            pattern,
+            local,
+            remote: None,
+        }
+    }
+
+    /// Sets the server's static public key, needed by NK and IK clients. An
+    /// XX client fails the handshake when the server sends another one.
+    pub fn remote(mut self, key: Key) -> Self {
+        self.remote = Some(key);
+        self
+    }
+
+    pub fn pattern(&self) -> NoisePattern {
+        self.pattern
+    }
+
+    /// Static public key matching the private key, to hand out to peers.
+    pub fn public_key(&self) -> Key {
+        let mut dh = DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .expect("x25519");
+        dh.set(self.local.as_bytes());
+        Key::new(dh.pubkey()).expect("x25519 public key")
+    }
+
+    fn builder(&self) -> snow::Builder<'_> {
+        let params = self.pattern.params().parse().expect("noise params");
+        snow::Builder::with_resolver(params, Box::new(Resolver))
+            .local_private_key(self.local.as_bytes())
+    }
+}
+
+/// Default primitives, but with ephemeral keys that have a representative.
+struct Resolver;
+
+impl CryptoResolver for Resolver {
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
+        DefaultResolver.resolve_rng()
+    }
+
+    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<dyn types::Dh>> {
+        match choice {
+            DHChoice::Curve25519 => Some(Box::new(Dh::default())),
+            _ => None,
+        }
+    }
+
+    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<dyn types::Hash>> {
+        DefaultResolver.resolve_hash(choice)
+    }
+
+    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<dyn types::Cipher>> {
+        DefaultResolver.resolve_cipher(choice)
+    }
+}
+
+/// X25519 whose generated keys come from [`elligator::generate`].
+#[derive(Default)]
+struct Dh {
+    private: [u8; KEY_LEN],
+    public: [u8; KEY_LEN],
+}
+
+impl types::Dh for Dh {
+    fn name(&self) -> &'static str {
+        "25519"
+    }
+
+    fn pub_len(&self) -> usize {
+        KEY_LEN
+    }
+
+    fn priv_len(&self) -> usize {
+        KEY_LEN
+    }
+
+    fn set(&mut self, private: &[u8]) {
+        self.private = private.try_into().expect("x25519 private key");
+        self.public = MontgomeryPoint::mul_base_clamped(self.private).to_bytes();
+    }
+
+    fn generate(&mut self, _: &mut dyn Random) {
+        // 密钥由 elligator 使用系统随机数生成
+        let KeyPair { private, public } = elligator::generate().expect("ephemeral key");
+        self.private = private;
+        self.public = public;
+    }
+
+    fn pubkey(&self) -> &[u8] {
+        &self.public
+    }
+
+    fn privkey(&self) -> &[u8] {
+        &self.private
+    }
+
+    fn dh(&self, public: &[u8], out: &mut [u8]) -> Result<(), snow::Error> {
+        let public = public
+            .get(..KEY_LEN)
+            .and_then(|public| public.try_into().ok())
+            .ok_or(snow::Error::Dh)?;
+        let shared = elligator::agree(&self.private, &public).map_err(|_| snow::Error::Dh)?;
+        out[..KEY_LEN].copy_from_slice(&shared);
+        Ok(())
+    }
+}
+
+/// Runs the client side, returning the session and the server's static key.
+pub(crate) async fn client<S>(
+    io: &mut S,
+    noise: &Noise,
+    ciphers: &[Cipher],
+    options: u8,
+    format: Format,
+) -> io::Result<(Session, Key)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut builder = noise.builder();
+    if noise.pattern.needs_remote() {
+        let remote = noise.remote.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("noise {} needs the server public key", noise.pattern),
+            )
+        })?;
+        builder = builder.remote_public_key(remote.as_bytes());
+    }
+    let mut state = builder.build_initiator().map_err(noise_error)?;
+
+    let pattern = noise.pattern;
+    let (cipher, agreed) = match pattern {
+        NoisePattern::Nk | NoisePattern::Ik => {
+            let mut payload = handshake::now().to_be_bytes().to_vec();
+            payload.extend(offer(ciphers, options, format));
+            write_message(io, &mut state, pattern, 0, &payload).await?;
+
+            let (_, payload) = read_message(io, &mut state, pattern, 1).await?;
+            handshake::parse_reply(&payload, ciphers, options, format)?
+        }
+        NoisePattern::Xx => {
+            write_message(io, &mut state, pattern, 0, &[]).await?;
+
+            // 双方都知道两个列表, 按 server 的优先顺序选择
+            let (_, payload) = read_message(io, &mut state, pattern, 1).await?;
+            // 发送自己的公钥之前确认 server 的公钥
+            if let Some(remote) = &noise.remote {
+                if state.get_remote_static() != Some(remote.as_bytes()) {
+                    return Err(HandshakeError::Authentication.into());
+                }
+            }
+            let (supported, server_options, server_format) = parse_offer(&payload)?;
+            let offer = offer(ciphers, options, format);
+            write_message(io, &mut state, pattern, 2, &offer).await?;
+            let cipher = common(ciphers, &supported)?;
+            handshake::check_format(format, server_format)?;
+            (cipher, options & server_options)
+        }
+    };
+
+    let remote = Key::new(state.get_remote_static().unwrap_or_default())?;
+    let session = split(&mut state, Role::Client, cipher).with_options(agreed);
+    Ok((session, remote))
+}
+
+/// Runs the server side. With XX and IK the client must hold the private key
+/// of one of `peers`, whose index is returned with the session.
+pub(crate) async fn server<S>(
+    io: &mut S,
+    noise: &Noise,
+    peers: &[&Key],
+    ciphers: &[Cipher],
+    options: u8,
+    format: Format,
+    replay: &ReplayCache,
+) -> io::Result<(Session, Option<usize>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if noise.pattern.mutual() && peers.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("noise {} needs the public keys of the users", noise.pattern),
+        ));
+    }
+    let mut state = noise.builder().build_responder().map_err(noise_error)?;
+
+    let pattern = noise.pattern;
+    match pattern {
+        NoisePattern::Nk | NoisePattern::Ik => {
+            // 以解码后的公钥记入缓存, 同一公钥的其他表示也会被拒绝
+            let (ephemeral, payload) = read_message(io, &mut state, pattern, 0).await?;
+            let index = peer(&state, peers)?;
+            let body = handshake::check_fresh(&payload, &ephemeral, replay)?;
+            let (offered, client_options, client_format) = parse_offer(body)?;
+            let cipher = ciphers.iter().copied().find(|c| offered.contains(c));
+            let agreed = options & client_options;
+
+            let mut reply = handshake::reply(cipher, agreed, format, ciphers);
+            reply.resize(REPLY_LEN, 0);
+            write_message(io, &mut state, pattern, 1, &reply).await?;
+            let cipher = cipher.ok_or_else(|| HandshakeError::NoCommonCipher {
+                offered,
+                supported: ciphers.to_vec(),
+            })?;
+            handshake::check_format(format, client_format)?;
+            let session = split(&mut state, Role::Server, cipher).with_options(agreed);
+            Ok((session, index))
+        }
+        NoisePattern::Xx => {
+            // 第一条消息没有认证内容, 不记入重放缓存
+            read_message(io, &mut state, pattern, 0).await?;
+            let offer = offer(ciphers, options, format);
+            write_message(io, &mut state, pattern, 1, &offer).await?;
+
+            let (_, payload) = read_message(io, &mut state, pattern, 2).await?;
+            let index = peer(&state, peers)?;
+            let (offered, client_options, client_format) = parse_offer(&payload)?;
+            let cipher = common(&offered, ciphers)?;
+            handshake::check_format(format, client_format)?;
+            let session = split(&mut state, Role::Server, cipher);
+            Ok((session.with_options(options & client_options), index))
+        }
+    }
+}
+
+/// Index of the client's static key in `peers`, `None` without one.
+fn peer(state: &HandshakeState, peers: &[&Key]) -> Result<Option<usize>, HandshakeError> {
+    match state.get_remote_static() {
+        Some(remote) => peers
+            .iter()
+            .position(|key| key.as_bytes() == remote)
+            .map(Some)
+            .ok_or(HandshakeError::Authentication),
+        None => Ok(None),
+    }
+}
+
+/// Turns the final cipher states into the traffic secrets of a session.
+fn split(state: &mut HandshakeState, role: Role, cipher: Cipher) -> Session {
+    let (i2r, r2i) = state.dangerously_get_raw_split();
+    let c2s = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &i2r);
+    let s2c = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &r2i);
+    Session::new(role, cipher, c2s, s2c)
+}
+
+/// Writes the `message`th message of `pattern`, replacing its ephemeral
+/// key, if it has one, with a representative.
+async fn write_message<S>(
+    io: &mut S,
+    state: &mut HandshakeState,
+    pattern: NoisePattern,
+    message: usize,
+    payload: &[u8],
+) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    // snow 总是为认证标签留出空间, 即使还没有密钥
+    let mut msg = vec![0; pattern.message_len(message) + TAG_LEN];
+    let len = state
+        .write_message(payload, &mut msg)
+        .map_err(noise_error)?;
+    debug_assert_eq!(len, pattern.message_len(message));
+    msg.truncate(len);
+    if has_ephemeral(message) {
+        let public = msg[..KEY_LEN].try_into().unwrap();
+        msg[..KEY_LEN].copy_from_slice(&elligator::encode(&public)?);
+    }
+
+    io.write_all(&msg).await?;
+    io.flush().await
+}
+
+/// Reads the `message`th message of `pattern`, returning its ephemeral key,
+/// empty if it has none, and its decrypted payload.
+async fn read_message<S>(
+    io: &mut S,
+    state: &mut HandshakeState,
+    pattern: NoisePattern,
+    message: usize,
+) -> io::Result<(Vec<u8>, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut msg = vec![0; pattern.message_len(message)];
+    io.read_exact(&mut msg).await?;
+    let mut ephemeral = Vec::new();
+    if has_ephemeral(message) {
+        let public = elligator::decode(msg[..KEY_LEN].try_into().unwrap());
+        msg[..KEY_LEN].copy_from_slice(&public);
+        ephemeral.extend_from_slice(&public);
+    }
+
+    let mut payload = vec![0; msg.len()];
+    let len = state
+        .read_message(&msg, &mut payload)
+        .map_err(noise_error)?;
+    payload.truncate(len);
+    Ok((ephemeral, payload))
+}
+
+/// Each side sends its ephemeral key first in its first message.
+fn has_ephemeral(message: usize) -> bool {
+    message < 2
+}
+
+/// Offer written by [`handshake::offer`], padded to [`OFFER_LEN`].
+fn offer(ciphers: &[Cipher], options: u8, format: Format) -> Vec<u8> {
+    let mut offer = handshake::offer(ciphers, options, format);
+    offer.resize(OFFER_LEN, 0);
+    offer
+}
+
+/// Decodes an offer written by [`offer`].
+fn parse_offer(offer: &[u8]) -> Result<(Vec<Cipher>, u8, Option<Format>), HandshakeError> {
+    let count = *offer
+        .first()
+        .ok_or(HandshakeError::Malformed("missing cipher list"))? as usize;
+    let offer = offer
+        .get(..count + 3)
+        .ok_or(HandshakeError::Malformed("bad cipher list"))?;
+    handshake::parse_offer(offer)
+}
+
+/// First of the server's `supported` ciphers that the client `offered`.
+fn common(offered: &[Cipher], supported: &[Cipher]) -> Result<Cipher, HandshakeError> {
+    supported
+        .iter()
+        .copied()
+        .find(|c| offered.contains(c))
+        .ok_or_else(|| HandshakeError::NoCommonCipher {
+            offered: offered.to_vec(),
+            supported: supported.to_vec(),
+        })
+}
+
+/// Decryption failures mean the peer does not hold the expected keys.
+fn noise_error(e: snow::Error) -> io::Error {
+    match e {
+        snow::Error::Decrypt => HandshakeError::Authentication.into(),
+        e => io::Error::new(io::ErrorKind::InvalidData, format!("noise: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    fn key(byte: u8) -> Key {
+        Key::new(&[byte; 32]).unwrap()
+    }
+
+    /// Noise of the server and of a client that expects its static key.
+    fn pair(pattern: NoisePattern) -> (Noise, Noise) {
+        let server = Noise::new(pattern, key(1));
+        let client = Noise::new(pattern, key(2)).remote(server.public_key());
+        (client, server)
+    }
+
+    /// Runs both sides, the server accepting the clients of `peers`.
+    async fn handshake(
+        client: &Noise,
+        server: &Noise,
+        peers: &[&Key],
+    ) -> (
+        io::Result<(Session, Key)>,
+        io::Result<(Session, Option<usize>)>,
+    ) {
+        let (mut a, mut b) = duplex(4096);
+        let replay = ReplayCache::default();
+        let format = Format::default();
+        tokio::join!(
+            async {
+                let result = self::client(&mut a, client, &Cipher::ALL, 0, format).await;
+                drop(a);
+                result
+            },
+            async {
+                let result =
+                    self::server(&mut b, server, peers, &Cipher::ALL, 0, format, &replay).await;
+                drop(b);
+                result
+            },
+        )
+    }
+
+    fn secret(prk: &hkdf::Prk) -> [u8; 32] {
+        let mut secret = [0; 32];
+        let okm = prk.expand(&[b"test"], hkdf::HKDF_SHA256).unwrap();
+        okm.fill(&mut secret).unwrap();
+        secret
+    }
+
+    fn handshake_error(e: io::Error) -> HandshakeError {
+        e.into_inner()
+            .unwrap()
+            .downcast::<HandshakeError>()
+            .map(|e| *e)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        for pattern in NoisePattern::ALL {
+            let (client, server) = pair(pattern);
+            let peer = client.public_key();
+            let (client_side, server_side) = handshake(&client, &server, &[&key(3), &peer]).await;
+            let (client_session, remote) = client_side.unwrap();
+            let (server_session, index) = server_side.unwrap();
+
+            assert_eq!(remote.as_bytes(), server.public_key().as_bytes());
+            let expected = if pattern.mutual() { Some(1) } else { None };
+            assert_eq!(index, expected, "{}", pattern);
+            assert_eq!(client_session.cipher, server_session.cipher);
+            assert_eq!(secret(&client_session.send), secret(&server_session.recv));
+            assert_eq!(secret(&client_session.recv), secret(&server_session.send));
+            assert_ne!(secret(&client_session.send), secret(&client_session.recv));
+        }
+    }
+
+    #[tokio::test]
+    async fn wrong_server_key_is_rejected() {
+        for pattern in NoisePattern::ALL {
+            let (client, server) = pair(pattern);
+            let client = client.remote(key(3));
+            let peer = client.public_key();
+            let (client_side, server_side) = handshake(&client, &server, &[&peer]).await;
+
+            assert!(client_side.is_err(), "{}", pattern);
+            assert!(server_side.is_err(), "{}", pattern);
+            // XX 由 client 发现, NK 与 IK 由无法解密的 server 发现
+            let e = match pattern {
+                NoisePattern::Xx => client_side.err().unwrap(),
+                _ => server_side.err().unwrap(),
+            };
+            assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+            assert!(matches!(handshake_error(e), HandshakeError::Authentication));
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_client_key_is_rejected() {
+        for pattern in [NoisePattern::Xx, NoisePattern::Ik] {
+            let (client, server) = pair(pattern);
+            // XX 的 client 发出最后一条消息即完成, 只有 server 能发现
+            let (_, server_side) = handshake(&client, &server, &[&key(3)]).await;
+            let e = server_side.err().unwrap();
+            assert!(matches!(handshake_error(e), HandshakeError::Authentication));
+        }
+    }
+
+    #[tokio::test]
+    async fn cipher_mismatch_fails_both_sides() {
+        for pattern in NoisePattern::ALL {
+            let (client, server) = pair(pattern);
+            let peer = client.public_key();
+            let (mut a, mut b) = duplex(4096);
+            let replay = ReplayCache::default();
+            let format = Format::default();
+            let peers = [&peer];
+            let (client_side, server_side) = tokio::join!(
+                self::client(&mut a, &client, &[Cipher::ChaCha20Poly1305], 0, format),
+                self::server(
+                    &mut b,
+                    &server,
+                    &peers,
+                    &[Cipher::Aes256Gcm],
+                    0,
+                    format,
+                    &replay
+                ),
+            );
+
+            let errors = [client_side.err().unwrap(), server_side.err().unwrap()];
+            for e in errors {
+                match handshake_error(e) {
+                    HandshakeError::NoCommonCipher { offered, supported } => {
+                        assert_eq!(offered, [Cipher::ChaCha20Poly1305], "{}", pattern);
+                        assert_eq!(supported, [Cipher::Aes256Gcm], "{}", pattern);
+                    }
+                    e => panic!("{}: unexpected error {}", pattern, e),
+                }
+            }
+        }
+    }
+
+    /// First message of a client, as [`client`] writes it.
+    async fn first_message(noise: &Noise) -> Vec<u8> {
+        let remote = noise.remote.as_ref().unwrap();
+        let builder = noise.builder().remote_public_key(remote.as_bytes());
+        let mut state = builder.build_initiator().unwrap();
+        let mut payload = handshake::now().to_be_bytes().to_vec();
+        payload.extend(offer(&Cipher::ALL, 0, Format::default()));
+        let mut msg = Vec::new();
+        write_message(&mut msg, &mut state, noise.pattern, 0, &payload)
+            .await
+            .unwrap();
+        msg
+    }
+
+    /// `msg` with its ephemeral key under another representative.
+    fn reencoded(msg: &[u8]) -> Vec<u8> {
+        let public = elligator::decode(msg[..KEY_LEN].try_into().unwrap());
+        loop {
+            let representative = elligator::encode(&public).unwrap();
+            if representative[..] != msg[..KEY_LEN] {
+                return [&representative[..], &msg[KEY_LEN..]].concat();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn replayed_first_message_is_rejected() {
+        for pattern in [NoisePattern::Nk, NoisePattern::Ik] {
+            let (client, server) = pair(pattern);
+            let peer = client.public_key();
+            let msg = first_message(&client).await;
+            let replay = ReplayCache::default();
+
+            let (server, peer, replay) = (&server, &peer, &replay);
+            let accept = |msg: Vec<u8>| async move {
+                let (mut a, mut b) = duplex(4096);
+                a.write_all(&msg).await?;
+                let format = Format::default();
+                self::server(&mut b, server, &[peer], &Cipher::ALL, 0, format, replay).await
+            };
+            accept(msg.clone()).await.unwrap();
+            for msg in [msg.clone(), reencoded(&msg)] {
+                let e = accept(msg).await.err().unwrap();
+                assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+                assert!(matches!(handshake_error(e), HandshakeError::Replayed));
+            }
+        }
+    }
+}
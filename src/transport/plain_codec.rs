@@ -0,0 +1,110 @@
+use super::{CodecError, FrameCodec, Stats, MAX_FRAME_LEN};
+use bytes::{Buf, BufMut, BytesMut};
+use std::io;
+use tokio_util::codec::Decoder;
+
+/// Length of the frame length header.
+const HEADER_LEN: usize = 4;
+
+/// Frames in plaintext, each a big endian `u32` length followed by the data.
+///
+/// Meant for links that are already encrypted and authenticated, such as a
+/// TLS or SSH tunnel, or trusted local ones, where sealing every frame again
+/// is wasted work. Nothing is padded, compressed or rekeyed.
+pub struct PlainCodec {
+    next_len: Option<usize>,
+    max_frame_len: usize,
+    stats: Stats,
+}
+
+impl Default for PlainCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PlainCodec {
+    pub fn new() -> Self {
+        Self {
+            next_len: None,
+            max_frame_len: MAX_FRAME_LEN,
+            stats: Stats::default(),
+        }
+    }
+
+    /// Sets the largest frame accepted or sent, at most `u32::MAX`.
+    pub fn with_max_frame_len(mut self, len: usize) -> Self {
+        self.max_frame_len = len.min(u32::MAX as usize);
+        self
+    }
+
+    /// Counts into `stats`, shared with other codecs of the same connection.
+    pub(crate) fn with_stats(mut self, stats: Stats) -> Self {
+        self.stats = stats;
+        self
+    }
+}
+
+impl FrameCodec for PlainCodec {
+    fn encode_with<F>(&mut self, dst: &mut BytesMut, write: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut BytesMut) -> io::Result<()>,
+    {
+        // 长度头先占位, 写完数据后补上
+        let start = dst.len();
+        dst.put_bytes(0, HEADER_LEN);
+        if let Err(e) = write(dst) {
+            dst.truncate(start);
+            return Err(e);
+        }
+
+        let len = dst.len() - start - HEADER_LEN;
+        if len > self.max_frame_len {
+            dst.truncate(start);
+            let max = self.max_frame_len;
+            return Err(CodecError::FrameTooLarge { len, max }.into());
+        }
+        dst[start..start + HEADER_LEN].copy_from_slice(&(len as u32).to_be_bytes());
+        self.stats.record_sent(HEADER_LEN + len);
+        Ok(())
+    }
+
+    fn max_frame_len(&self) -> usize {
+        self.max_frame_len
+    }
+
+    fn stats(&self) -> &Stats {
+        &self.stats
+    }
+}
+
+impl Decoder for PlainCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        let len = match self.next_len {
+            Some(len) => len,
+            None => {
+                if src.len() < HEADER_LEN {
+                    return Ok(None);
+                }
+                let len = src.get_u32() as usize;
+                if len > self.max_frame_len {
+                    let max = self.max_frame_len;
+                    return Err(CodecError::FrameTooLarge { len, max }.into());
+                }
+                self.next_len = Some(len);
+                len
+            }
+        };
+
+        if src.len() < len {
+            src.reserve(len - src.len());
+            return Ok(None);
+        }
+        self.next_len = None;
+        self.stats.record_received(HEADER_LEN + len);
+        Ok(Some(src.split_to(len)))
+    }
+}
@@ -0,0 +1,220 @@
+//! QUIC under the tunnel, so that channels do not stall each other.
+//!
+//! Each channel runs on a QUIC stream of its own, a lost packet only holds up
+//! the channel whose data it carried, and the connection follows a client
+//! whose address changes. The server is authenticated by its
+//! [`Certificate`] as with TLS, the client by the handshake of the
+//! [`Builder`](super::Builder) over the first stream, see
+//! [`client::connect_quic`](crate::client::connect_quic) and
+//! [`server::accept_quic`](crate::server::accept_quic).
+
+use super::{
+    tls::{client_config, server_config},
+    Certificate, Verification,
+};
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    ClientConfig, Endpoint, IdleTimeout, RecvStream, SendStream, ServerConfig, TransportConfig,
+    VarInt,
+};
+use std::{
+    convert::TryFrom,
+    io,
+    net::{SocketAddr, UdpSocket},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+pub use quinn::{Connection, Incoming};
+
+/// Application protocol offered by both sides.
+const ALPN: &[u8] = b"h3";
+
+/// Streams, that is channels, a client may have open at once.
+const MAX_STREAMS: u32 = 1024;
+
+/// A connection without packets for this long is closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Clients send a keep-alive packet after this long without traffic, so that
+/// NATs keep their mapping.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// QUIC client side: a UDP socket, the server name and how its certificate
+/// is verified.
+#[derive(Clone)]
+pub struct QuicClient {
+    endpoint: Endpoint,
+    server_name: String,
+}
+
+impl QuicClient {
+    /// Binds a UDP socket to `bind`, such as `0.0.0.0:0`. `server_name` is
+    /// checked against the certificate as with
+    /// [`TlsClient`](super::TlsClient).
+    pub fn new(
+        bind: SocketAddr,
+        server_name: &str,
+        verification: Verification,
+    ) -> io::Result<Self> {
+        let crypto = QuicClientConfig::try_from(client_config(verification, ALPN)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("quic: {}", e)))?;
+        let mut transport = TransportConfig::default();
+        transport
+            .max_idle_timeout(Some(idle_timeout()))
+            .keep_alive_interval(Some(KEEP_ALIVE));
+        let mut config = ClientConfig::new(Arc::new(crypto));
+        config.transport_config(Arc::new(transport));
+
+        let mut endpoint = Endpoint::client(bind)?;
+        endpoint.set_default_client_config(config);
+        Ok(Self {
+            endpoint,
+            server_name: server_name.to_owned(),
+        })
+    }
+
+    /// Runs the QUIC handshake with the server at `addr`.
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<Connection> {
+        let connecting = self
+            .endpoint
+            .connect(addr, &self.server_name)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("quic: {}", e)))?;
+        Ok(connecting.await?)
+    }
+
+    /// Moves the endpoint to `socket`, as when the address of the client
+    /// changes. Open connections carry on from the new address.
+    pub fn rebind(&self, socket: UdpSocket) -> io::Result<()> {
+        self.endpoint.rebind(socket)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+}
+
+/// QUIC server side, presenting one certificate to every client.
+#[derive(Clone)]
+pub struct QuicServer {
+    endpoint: Endpoint,
+}
+
+impl QuicServer {
+    /// Listens on the UDP address `addr`.
+    pub fn bind(addr: SocketAddr, certificate: Certificate) -> io::Result<Self> {
+        let crypto = QuicServerConfig::try_from(server_config(certificate, ALPN)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("quic: {}", e)))?;
+        let mut transport = TransportConfig::default();
+        transport
+            .max_idle_timeout(Some(idle_timeout()))
+            .max_concurrent_bidi_streams(VarInt::from_u32(MAX_STREAMS))
+            .max_concurrent_uni_streams(VarInt::from_u32(0));
+        let mut config = ServerConfig::with_crypto(Arc::new(crypto));
+        config.transport_config(Arc::new(transport));
+
+        Ok(Self {
+            endpoint: Endpoint::server(config, addr)?,
+        })
+    }
+
+    /// Waits for the next client, `None` once the endpoint is closed. The
+    /// QUIC handshake runs when the returned [`Incoming`] is awaited.
+    pub async fn accept(&self) -> Option<Incoming> {
+        self.endpoint.accept().await
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+}
+
+fn idle_timeout() -> IdleTimeout {
+    IdleTimeout::try_from(IDLE_TIMEOUT).expect("idle timeout in range")
+}
+
+/// Bidirectional stream of a QUIC connection, under one channel or the
+/// handshake.
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl QuicStream {
+    /// Opens a stream, which the peer only sees once something is sent.
+    pub async fn open(connection: &Connection) -> io::Result<Self> {
+        let (send, recv) = connection.open_bi().await?;
+        Ok(Self { send, recv })
+    }
+
+    /// Waits for the peer to open a stream.
+    pub async fn accept(connection: &Connection) -> io::Result<Self> {
+        let (send, recv) = connection.accept_bi().await?;
+        Ok(Self { send, recv })
+    }
+
+    /// Index of the stream among those opened by the same side.
+    pub fn index(&self) -> u64 {
+        self.send.id().index()
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QuicClient, QuicServer};
+    use crate::{
+        client, server,
+        testing::{builder, exchange},
+        transport::{Certificate, Verification},
+    };
+
+    #[tokio::test]
+    async fn channels_over_quic() {
+        let certificate = Certificate::self_signed(&["localhost".to_owned()]).unwrap();
+        let pin = Verification::pin(&certificate.fingerprint()).unwrap();
+        let quic_server = QuicServer::bind("127.0.0.1:0".parse().unwrap(), certificate).unwrap();
+        let addr = quic_server.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let connection = quic_server.accept().await.unwrap().await?;
+            server::accept_quic(connection, builder()).await
+        });
+
+        let quic_client =
+            QuicClient::new("127.0.0.1:0".parse().unwrap(), "localhost", pin).unwrap();
+        let connection = quic_client.connect(addr).await.unwrap();
+        let mut client = client::connect_quic(connection, builder()).await.unwrap();
+
+        exchange(&mut client, server.await.unwrap().unwrap()).await;
+    }
+}
@@ -0,0 +1,171 @@
+use ring::digest::{digest, SHA256};
+use std::{
+    convert::TryInto,
+    fmt, mem,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Bits per expected entry and hashes per entry, about 1% false positives at
+/// full capacity.
+const BITS_PER_ENTRY: usize = 10;
+const HASHES: u64 = 7;
+
+/// Client hellos seen by a server over a sliding time window, shared by all of
+/// its connections.
+///
+/// Every hello is remembered for at least `window`, in two Bloom filters that
+/// are swapped every `window`, so memory stays bounded by `capacity` whatever
+/// the traffic. Past `capacity` hellos per window false positives grow and
+/// some honest clients are turned away. Hellos whose timestamp is more than
+/// half a `window` away from the server clock are rejected before reaching
+/// the cache, so a replay is either stale or still remembered.
+pub struct ReplayCache {
+    window: Duration,
+    capacity: usize,
+    filters: Mutex<Filters>,
+}
+
+struct Filters {
+    current: Bloom,
+    previous: Bloom,
+    since: Instant,
+}
+
+impl ReplayCache {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            window,
+            capacity,
+            filters: Mutex::new(Filters {
+                current: Bloom::default(),
+                previous: Bloom::default(),
+                since: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Records `salt`, returns `false` if it was already seen.
+    pub(crate) fn insert(&self, salt: &[u8]) -> bool {
+        let hash = digest(&SHA256, salt);
+        let hash = hash.as_ref();
+        let h1 = u64::from_be_bytes(hash[..8].try_into().unwrap());
+        let h2 = u64::from_be_bytes(hash[8..16].try_into().unwrap());
+
+        let mut filters = self.filters.lock().unwrap();
+        let elapsed = filters.since.elapsed();
+        if elapsed >= self.window {
+            filters.previous = if elapsed >= self.window * 2 {
+                Bloom::default()
+            } else {
+                mem::take(&mut filters.current)
+            };
+            filters.current = Bloom::default();
+            filters.since = Instant::now();
+        }
+
+        if filters.previous.contains(h1, h2) || filters.current.contains(h1, h2) {
+            return false;
+        }
+        filters.current.insert(h1, h2, self.capacity);
+        true
+    }
+}
+
+impl Default for ReplayCache {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(300), 1 << 16)
+    }
+}
+
+impl fmt::Debug for ReplayCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReplayCache")
+            .field("window", &self.window)
+            .field("capacity", &self.capacity)
+            .finish()
+    }
+}
+
+/// Bloom filter, allocated on first insert.
+#[derive(Default)]
+struct Bloom {
+    bits: Vec<u64>,
+}
+
+impl Bloom {
+    fn contains(&self, h1: u64, h2: u64) -> bool {
+        !self.bits.is_empty()
+            && self
+                .indexes(h1, h2)
+                .all(|i| self.bits[i / 64] & 1 << (i % 64) != 0)
+    }
+
+    fn insert(&mut self, h1: u64, h2: u64, capacity: usize) {
+        if self.bits.is_empty() {
+            self.bits = vec![0; (capacity.max(1) * BITS_PER_ENTRY).div_ceil(64)];
+        }
+        for i in self.indexes(h1, h2).collect::<Vec<_>>() {
+            self.bits[i / 64] |= 1 << (i % 64);
+        }
+    }
+
+    /// Double hashing, `h1 + i * h2` for each of the hashes.
+    fn indexes(&self, h1: u64, h2: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 64;
+        (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    #[test]
+    fn replay_is_rejected() {
+        let cache = ReplayCache::default();
+        assert!(cache.insert(b"hello 1"));
+        assert!(cache.insert(b"hello 2"));
+        assert!(!cache.insert(b"hello 1"));
+        assert!(!cache.insert(b"hello 2"));
+    }
+
+    #[test]
+    fn replay_is_rejected_across_a_rotation() {
+        let window = Duration::from_millis(200);
+        let cache = ReplayCache::new(window, 16);
+        assert!(cache.insert(b"hello"));
+
+        // 轮换后仍在上一个 filter 中
+        sleep(window + window / 4);
+        assert!(!cache.insert(b"hello"));
+        assert!(cache.insert(b"other"));
+
+        // 两个窗口之后才被遗忘, 此时时间戳检查已经拒绝它
+        sleep(window * 2);
+        assert!(cache.insert(b"hello"));
+    }
+
+    #[test]
+    fn cache_stays_bounded() {
+        let cache = ReplayCache::new(Duration::from_secs(60), 1000);
+        for i in 0..10_000u32 {
+            cache.insert(&i.to_be_bytes());
+        }
+        let filters = cache.filters.lock().unwrap();
+        assert_eq!(
+            filters.current.bits.len(),
+            (1000 * BITS_PER_ENTRY).div_ceil(64)
+        );
+        assert!(filters.previous.bits.is_empty());
+    }
+}
@@ -1,25 +1,306 @@
-use bytes::{BufMut, Bytes, BytesMut};
+use super::{
+    compression::Deflate,
+    handshake::{Role, Session},
+    Cipher, FrameCodec, Key, Padding, Stats,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use ring::{
-    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305},
-    rand::{SecureRandom, SystemRandom},
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN},
+    hkdf,
+    rand::SystemRandom,
+};
+use std::{
+    error, fmt, io,
+    time::{Duration, Instant},
 };
-use std::io;
-use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+use tokio_util::codec::{Decoder, Encoder};
 
-pub struct SafeCodec {
-    inner: LengthDelimitedCodec,
+const KEY_LABEL: &[u8] = b"yew key";
+const UPDATE_LABEL: &[u8] = b"yew update";
+
+/// Length of the frame length header before it is sealed.
+const HEADER_LEN: usize = 4;
+
+/// Default limit of a frame body, before sealing.
+pub const MAX_FRAME_LEN: usize = 8 * 1024 * 1024;
+
+/// Frame flag: this is the last frame sealed under the current key.
+const KEY_UPDATE: u8 = 0x01;
+/// Frame flag: a 2 byte padding length follows the flags, that many bytes of
+/// padding end the frame.
+const PADDED: u8 = 0x02;
+/// Frame flag: the data is compressed with raw deflate.
+const COMPRESSED: u8 = 0x04;
+
+/// When a sender replaces its key, whichever limit is reached first.
+///
+/// Each direction is rekeyed independently: the sender marks its last frame
+/// under the old key, then both sides derive the next key from the current
+/// traffic secret with HKDF, so open channels are not interrupted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RekeyPolicy {
+    /// Sealed bytes under one key.
+    pub bytes: Option<u64>,
+    /// Frames under one key.
+    pub frames: Option<u64>,
+    /// Age of a key.
+    pub interval: Option<Duration>,
+}
+
+impl RekeyPolicy {
+    pub fn never() -> Self {
+        Self {
+            bytes: None,
+            frames: None,
+            interval: None,
+        }
+    }
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        Self {
+            bytes: Some(1 << 30),
+            frames: None,
+            interval: Some(Duration::from_secs(3600)),
+        }
+    }
+}
+
+/// Key state of one direction.
+struct Direction {
+    secret: hkdf::Prk,
     key: LessSafeKey,
+    seq: u64,
+    frames: u64,
+    bytes: u64,
+    since: Instant,
+}
+
+impl Direction {
+    fn new(cipher: Cipher, secret: hkdf::Prk) -> Self {
+        Self {
+            key: traffic_key(cipher, &secret),
+            secret,
+            seq: 0,
+            frames: 0,
+            bytes: 0,
+            since: Instant::now(),
+        }
+    }
+
+    /// Seals `buf` in place, its last bytes receiving the tag.
+    fn seal(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let nonce = next_nonce(&mut self.seq)?;
+        let (data, tag) = buf.split_at_mut(buf.len() - self.key.algorithm().tag_len());
+        let sealed = self
+            .key
+            .seal_in_place_separate_tag(nonce, Aad::empty(), data)
+            .map_err(|_| io::Error::other("encode error"))?;
+        tag.copy_from_slice(sealed.as_ref());
+        Ok(())
+    }
+
+    /// Opens `buf` in place, leaving the plaintext followed by the tag.
+    fn open(&mut self, buf: &mut [u8]) -> Result<(), CodecError> {
+        let nonce = next_nonce(&mut self.seq)?;
+        self.key
+            .open_in_place(nonce, Aad::empty(), buf)
+            .map(|_| ())
+            .map_err(|_| CodecError::Authentication { frame: self.frames })
+    }
+
+    fn due(&self, policy: &RekeyPolicy) -> bool {
+        policy.bytes.is_some_and(|n| self.bytes >= n)
+            || policy.frames.is_some_and(|n| self.frames >= n)
+            || policy.interval.is_some_and(|t| self.since.elapsed() >= t)
+    }
+
+    /// Moves on to the next traffic secret.
+    fn update(&mut self, cipher: Cipher) {
+        let okm = self.secret.expand(&[UPDATE_LABEL], hkdf::HKDF_SHA256);
+        *self = Self::new(cipher, hkdf::Prk::from(okm.unwrap()));
+    }
+}
+
+/// Seals frames so the stream carries no plaintext: each frame is a sealed
+/// length header followed by the sealed body, both indistinguishable from
+/// random bytes.
+pub struct SafeCodec {
+    cipher: Cipher,
+    next_len: Option<usize>,
+    send: Direction,
+    recv: Direction,
+    rekey: RekeyPolicy,
+    padding: Padding,
+    rng: SystemRandom,
+    deflate: Option<Deflate>,
+    max_frame_len: usize,
+    stats: Stats,
 }
 
 impl SafeCodec {
-    pub fn new() -> Self {
-        let unbound_key = UnboundKey::new(&CHACHA20_POLY1305, &PRIVATE_KEY).unwrap();
-        let key = LessSafeKey::new(unbound_key);
+    /// Derives the per-direction keys from `key` alone, without a handshake.
+    pub fn with_key(key: &Key, role: Role, cipher: Cipher) -> Self {
+        Self::new(Session::from_key(key, role, cipher))
+    }
+
+    /// Uses the per-direction keys negotiated by the handshake.
+    pub(crate) fn new(session: Session) -> Self {
         Self {
-            inner: LengthDelimitedCodec::new(),
-            key,
+            cipher: session.cipher,
+            next_len: None,
+            send: Direction::new(session.cipher, session.send),
+            recv: Direction::new(session.cipher, session.recv),
+            rekey: RekeyPolicy::default(),
+            padding: Padding::None,
+            rng: SystemRandom::new(),
+            deflate: None,
+            max_frame_len: MAX_FRAME_LEN,
+            stats: Stats::default(),
         }
     }
+
+    pub fn with_rekey(mut self, policy: RekeyPolicy) -> Self {
+        self.rekey = policy;
+        self
+    }
+
+    pub fn with_padding(mut self, padding: Padding) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Compresses frames at `level`, and accepts compressed frames, which
+    /// both peers must agree on. `None` disables compression.
+    pub fn with_compression(mut self, level: Option<u32>) -> Self {
+        self.deflate = level.map(Deflate::new);
+        self
+    }
+
+    /// Sets the largest frame body accepted or sent, at most `u32::MAX`.
+    ///
+    /// Decompressed frames are held to the same limit.
+    pub fn with_max_frame_len(mut self, len: usize) -> Self {
+        self.max_frame_len = len.min(u32::MAX as usize);
+        self
+    }
+
+    pub fn max_frame_len(&self) -> usize {
+        self.max_frame_len
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+}
+
+impl FrameCodec for SafeCodec {
+    /// Seals one frame whose data `write` appends to `dst`.
+    ///
+    /// The frame is built and sealed in place at the end of `dst`, so the data
+    /// is written once and nothing but `dst` itself is allocated. On error
+    /// `dst` is left as it was.
+    fn encode_with<F>(&mut self, dst: &mut BytesMut, write: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut BytesMut) -> io::Result<()>,
+    {
+        // 'seal(length)' + 'seal(flags + [padding length] + data + [padding])', nonce 为发送序号
+        let tag_len = self.cipher.algorithm().tag_len();
+        let start = dst.len();
+        let padded = self.padding != Padding::None;
+
+        // 长度头与 flags, padding length 先占位
+        dst.put_bytes(0, HEADER_LEN + tag_len + 1);
+        if padded {
+            dst.put_u16(0);
+        }
+        let head = dst.len() - start;
+        if let Err(e) = write(dst) {
+            dst.truncate(start);
+            return Err(e);
+        }
+
+        // 压缩后没有变小则原样发送
+        let mut compressed = false;
+        if let Some(deflate) = &mut self.deflate {
+            let data = start + head;
+            if let Some(out) = deflate.compress(&dst[data..]) {
+                self.stats.record_compressed(dst.len() - data, out.len());
+                dst.truncate(data);
+                dst.extend_from_slice(out);
+                compressed = true;
+            }
+        }
+
+        let data_len = dst.len() - start - head;
+        let pad = self
+            .padding
+            .pad(3 + data_len, &self.rng)
+            .filter(|pad| 3 + data_len + pad <= self.max_frame_len);
+        if let Some(pad) = pad {
+            dst.put_bytes(0, pad);
+        }
+        let len = dst.len() - start - HEADER_LEN - tag_len;
+        if len > self.max_frame_len {
+            dst.truncate(start);
+            let max = self.max_frame_len;
+            return Err(CodecError::FrameTooLarge { len, max }.into());
+        }
+
+        let update = self.send.due(&self.rekey);
+        let body = start + HEADER_LEN + tag_len;
+        dst[body] = if update { KEY_UPDATE } else { 0 };
+        if compressed {
+            dst[body] |= COMPRESSED;
+        }
+        if padded {
+            // 超出 max_frame_len 时不填充, 但仍保留 padding length
+            let pad = pad.unwrap_or(0);
+            dst[body] |= PADDED;
+            dst[body + 1..body + 3].copy_from_slice(&(pad as u16).to_be_bytes());
+            self.stats.record_padding_sent(2 + pad);
+        }
+        dst[start..start + HEADER_LEN].copy_from_slice(&(len as u32).to_be_bytes());
+        dst.put_bytes(0, tag_len);
+
+        let sealed = self
+            .send
+            .seal(&mut dst[start..body])
+            .and_then(|_| self.send.seal(&mut dst[body..]));
+        if let Err(e) = sealed {
+            dst.truncate(start);
+            return Err(e);
+        }
+
+        let sealed_len = dst.len() - start;
+        self.send.bytes += sealed_len as u64;
+        self.send.frames += 1;
+        self.stats.record_sent(sealed_len);
+
+        if update {
+            self.send.update(self.cipher);
+            self.stats.record_rekey_sent();
+        }
+        Ok(())
+    }
+
+    fn max_frame_len(&self) -> usize {
+        self.max_frame_len
+    }
+
+    fn stats(&self) -> &Stats {
+        &self.stats
+    }
+}
+
+fn traffic_key(cipher: Cipher, secret: &hkdf::Prk) -> LessSafeKey {
+    let okm = secret.expand(&[KEY_LABEL], cipher.algorithm()).unwrap();
+    LessSafeKey::new(UnboundKey::from(okm))
 }
 
 impl Decoder for SafeCodec {
@@ -27,31 +308,76 @@ impl Decoder for SafeCodec {
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        // 'encrypted data' + 'nonce'
-        // return self.inner.decode(src);
+        // 'seal(length)' + 'seal(flags + data)', nonce 为接收序号
+        let tag_len = self.cipher.algorithm().tag_len();
 
-        // codec
-        let data = self.inner.decode(src)?;
-
-        // 解密
-        match data {
-            Some(mut data) => {
-                let nonce = data.split_off(data.len() - 12);
+        let len = match self.next_len {
+            Some(len) => len,
+            None => {
+                if src.len() < HEADER_LEN + tag_len {
+                    return Ok(None);
+                }
 
-                if let Ok(nonce) = Nonce::try_assume_unique_for_key(nonce.as_ref()) {
-                    if let Ok(ret) = self.key.open_in_place(nonce, Aad::empty(), &mut data) {
-                        let len = ret.len();
-                        let data = data.split_to(len);
+                let mut header = src.split_to(HEADER_LEN + tag_len);
+                self.recv.open(&mut header)?;
+                self.recv.bytes += header.len() as u64;
 
-                        return Ok(Some(data));
-                    }
+                let len = header.get_u32() as usize;
+                if len == 0 {
+                    return Err(CodecError::Malformed("empty frame").into());
+                }
+                if len > self.max_frame_len {
+                    let max = self.max_frame_len;
+                    return Err(CodecError::FrameTooLarge { len, max }.into());
                 }
+                self.next_len = Some(len);
+                len
+            }
+        };
+
+        if src.len() < len + tag_len {
+            src.reserve(len + tag_len - src.len());
+            return Ok(None);
+        }
+        self.next_len = None;
+
+        // 解密
+        let mut data = src.split_to(len + tag_len);
+        self.recv.open(&mut data)?;
+        data.truncate(len);
+        self.recv.bytes += (len + tag_len) as u64;
+        self.recv.frames += 1;
+        self.stats
+            .record_received(HEADER_LEN + tag_len + len + tag_len);
 
-                return Err(io::Error::new(io::ErrorKind::Other, "nonce error"));
+        let flags = data.get_u8();
+        if flags & !(KEY_UPDATE | PADDED | COMPRESSED) != 0 {
+            return Err(CodecError::Malformed("unknown frame flags").into());
+        }
+        if flags & PADDED != 0 {
+            if data.len() < 2 {
+                return Err(CodecError::Malformed("missing padding length").into());
+            }
+            let pad = data.get_u16() as usize;
+            if pad > data.len() {
+                return Err(CodecError::Malformed("padding longer than frame").into());
             }
-            None => {}
+            data.truncate(data.len() - pad);
+            self.stats.record_padding_received(2 + pad);
+        }
+        if flags & KEY_UPDATE != 0 {
+            self.recv.update(self.cipher);
+            self.stats.record_rekey_received();
+        }
+        if flags & COMPRESSED != 0 {
+            let deflate = self
+                .deflate
+                .as_mut()
+                .ok_or(CodecError::Malformed("compression not negotiated"))?;
+            data = deflate.decompress(&data, self.max_frame_len)?;
         }
-        Ok(None)
+
+        Ok(Some(data))
     }
 }
 
@@ -59,37 +385,288 @@ impl Encoder<Bytes> for SafeCodec {
     type Error = io::Error;
 
     fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        // 'encrypted data' + 'nonce'
-        // return self.inner.encode(item, dst);
+        self.encode_with(dst, |dst| {
+            dst.put_slice(&item);
+            Ok(())
+        })
+    }
+}
 
-        let mut buf = BytesMut::from(item.as_ref());
+/// Builds the nonce for record `seq` and advances the counter.
+///
+/// Every frame is two sealed records, its length header then its body. Nonces
+/// are never sent: both peers count the records of each direction, so a
+/// replayed, reordered or dropped frame is opened with the wrong nonce and
+/// fails authentication.
+fn next_nonce(seq: &mut u64) -> Result<Nonce, CodecError> {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[NONCE_LEN - 8..].copy_from_slice(&seq.to_be_bytes());
+    *seq = seq.checked_add(1).ok_or(CodecError::Exhausted)?;
+    Ok(Nonce::assume_unique_for_key(nonce))
+}
 
-        // nonce
-        let rand = SystemRandom::new();
-        let mut nonce = [0; 12];
-        rand.fill(&mut nonce).unwrap();
+/// Errors reported by [`SafeCodec`] and [`Transport`](super::Transport).
+///
+/// They reach the caller wrapped in an `io::Error`, of kind `PermissionDenied`
+/// for authentication failures and `InvalidData` otherwise, use
+/// [`CodecError::of`] to tell them apart.
+#[derive(Debug)]
+pub enum CodecError {
+    /// The frame did not authenticate as frame `frame` of the stream: it was
+    /// tampered with, replayed, reordered, or an earlier frame was dropped.
+    Authentication { frame: u64 },
+    /// The frame counter of a direction overflowed.
+    Exhausted,
+    /// The frame authenticated but its content is not valid.
+    Malformed(&'static str),
+    /// The frame body is `len` bytes, more than the limit of `max`.
+    FrameTooLarge { len: usize, max: usize },
+    /// The message does not fit in the limit of `max` bytes once serialized.
+    MessageTooLarge { max: u64 },
+    /// The frame decompresses to more than the limit of `max` bytes.
+    InflatedTooLarge { max: usize },
+}
 
-        // encrypt
-        if let Err(_) = self.key.seal_in_place_append_tag(
-            Nonce::assume_unique_for_key(nonce),
-            Aad::empty(),
-            &mut buf,
-        ) {
-            return Err(io::Error::new(io::ErrorKind::Other, "encode error"));
+impl CodecError {
+    /// The codec error carried by `e`, if any.
+    pub fn of(e: &io::Error) -> Option<&CodecError> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Authentication { frame } => {
+                write!(f, "frame {} failed authentication", frame)
+            }
+            CodecError::Exhausted => f.write_str("frame counter exhausted"),
+            CodecError::Malformed(reason) => write!(f, "malformed frame: {}", reason),
+            CodecError::FrameTooLarge { len, max } => {
+                write!(f, "frame of {} bytes exceeds the limit of {}", len, max)
+            }
+            CodecError::MessageTooLarge { max } => {
+                write!(f, "message exceeds the limit of {} bytes", max)
+            }
+            CodecError::InflatedTooLarge { max } => {
+                write!(f, "decompressed frame exceeds the limit of {} bytes", max)
+            }
         }
+    }
+}
 
-        buf.put_slice(&nonce);
+impl error::Error for CodecError {}
 
-        // codec
-        self.inner.encode(buf.into(), dst)
+impl From<CodecError> for io::Error {
+    fn from(e: CodecError) -> Self {
+        let kind = match e {
+            CodecError::Authentication { .. } => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, e)
     }
 }
 
-/* const PRIVATE_KEY: [u8; 32] = [
-    220, 56, 180, 32, 105, 143, 199, 54, 209, 108, 65, 140, 67, 107, 220, 12, 222, 129, 71, 73,
-    230, 198, 119, 11, 98, 216, 146, 46, 94, 139, 86, 230,
-]; */
-const PRIVATE_KEY: [u8; 32] = [
-    178, 177, 42, 211, 51, 232, 172, 141, 190, 131, 170, 161, 104, 51, 165, 165, 56, 225, 12, 167,
-    174, 228, 43, 55, 91, 129, 174, 173, 117, 32, 195, 252,
-];
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Codecs of both ends of a connection.
+    fn pair(cipher: Cipher) -> (SafeCodec, SafeCodec) {
+        let key = Key::new(&[7; 32]).unwrap();
+        let client = SafeCodec::with_key(&key, Role::Client, cipher);
+        let server = SafeCodec::with_key(&key, Role::Server, cipher);
+        (client, server)
+    }
+
+    /// Seals a frame whose header claims `len` and whose body, flags first,
+    /// is `body`, bypassing the checks of the encoder.
+    fn seal(codec: &mut SafeCodec, len: u32, body: &[u8]) -> BytesMut {
+        let tag_len = codec.cipher.algorithm().tag_len();
+        let mut header = len.to_be_bytes().to_vec();
+        header.resize(HEADER_LEN + tag_len, 0);
+        codec.send.seal(&mut header).unwrap();
+        let mut sealed = body.to_vec();
+        sealed.resize(body.len() + tag_len, 0);
+        codec.send.seal(&mut sealed).unwrap();
+
+        let mut frame = BytesMut::from(&header[..]);
+        frame.extend_from_slice(&sealed);
+        frame
+    }
+
+    fn codec_error(e: io::Error) -> CodecError {
+        e.into_inner()
+            .unwrap()
+            .downcast::<CodecError>()
+            .map(|e| *e)
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        for cipher in Cipher::ALL {
+            let (client, server) = pair(cipher);
+            // 每两帧换一次密钥
+            let mut client = client
+                .with_padding(Padding::Random(32))
+                .with_compression(Some(6))
+                .with_rekey(RekeyPolicy {
+                    frames: Some(2),
+                    ..RekeyPolicy::never()
+                });
+            let mut server = server.with_compression(Some(6));
+            let frames: Vec<Bytes> = vec![
+                Bytes::from_static(b"x"),
+                Bytes::from(vec![b'a'; 4096]),
+                Bytes::from((0..=255).collect::<Vec<u8>>()),
+                Bytes::from_static(b"last"),
+            ];
+            let mut buf = BytesMut::new();
+            for frame in &frames {
+                client.encode(frame.clone(), &mut buf).unwrap();
+            }
+            // 逐字节到达也能解出
+            let mut src = BytesMut::new();
+            let mut decoded = Vec::new();
+            for byte in buf {
+                src.extend_from_slice(&[byte]);
+                if let Some(frame) = server.decode(&mut src).unwrap() {
+                    decoded.push(frame.freeze());
+                }
+            }
+            assert_eq!(decoded, frames);
+            assert!(src.is_empty());
+        }
+    }
+
+    #[test]
+    fn tampered_header_is_rejected() {
+        let (mut client, mut server) = pair(Cipher::ChaCha20Poly1305);
+        let mut buf = BytesMut::new();
+        client
+            .encode(Bytes::from_static(b"hello"), &mut buf)
+            .unwrap();
+        buf[1] ^= 0x01;
+        let e = server.decode(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert!(matches!(
+            codec_error(e),
+            CodecError::Authentication { frame: 0 }
+        ));
+    }
+
+    #[test]
+    fn tampered_body_is_rejected() {
+        let (mut client, mut server) = pair(Cipher::Aes256Gcm);
+        let mut buf = BytesMut::new();
+        client
+            .encode(Bytes::from_static(b"hello"), &mut buf)
+            .unwrap();
+        let last = buf.len() - 1;
+        buf[last] ^= 0x80;
+        let e = server.decode(&mut buf).unwrap_err();
+        assert!(matches!(codec_error(e), CodecError::Authentication { .. }));
+    }
+
+    #[test]
+    fn replayed_frame_is_rejected() {
+        let (mut client, mut server) = pair(Cipher::ChaCha20Poly1305);
+        let mut first = BytesMut::new();
+        client
+            .encode(Bytes::from_static(b"one"), &mut first)
+            .unwrap();
+        let mut replay = first.clone();
+        assert!(server.decode(&mut first).unwrap().is_some());
+
+        let e = server.decode(&mut replay).unwrap_err();
+        assert!(matches!(
+            codec_error(e),
+            CodecError::Authentication { frame: 1 }
+        ));
+    }
+
+    #[test]
+    fn reordered_frame_is_rejected() {
+        let (mut client, mut server) = pair(Cipher::ChaCha20Poly1305);
+        let mut first = BytesMut::new();
+        client
+            .encode(Bytes::from_static(b"one"), &mut first)
+            .unwrap();
+        let mut second = BytesMut::new();
+        client
+            .encode(Bytes::from_static(b"two"), &mut second)
+            .unwrap();
+
+        let e = server.decode(&mut second).unwrap_err();
+        assert!(matches!(
+            codec_error(e),
+            CodecError::Authentication { frame: 0 }
+        ));
+    }
+
+    #[test]
+    fn empty_frame_is_rejected() {
+        let (mut client, mut server) = pair(Cipher::ChaCha20Poly1305);
+        let mut buf = seal(&mut client, 0, &[0]);
+        let e = server.decode(&mut buf).unwrap_err();
+        assert!(matches!(codec_error(e), CodecError::Malformed(_)));
+    }
+
+    #[test]
+    fn oversized_frame_is_rejected_before_its_body() {
+        let (mut client, server) = pair(Cipher::ChaCha20Poly1305);
+        let mut server = server.with_max_frame_len(1024);
+        // 只有长度头, 不等待 body 即报错
+        let mut buf = seal(&mut client, 1025, &[0]);
+        buf.truncate(HEADER_LEN + 16);
+        let e = server.decode(&mut buf).unwrap_err();
+        assert!(matches!(
+            codec_error(e),
+            CodecError::FrameTooLarge {
+                len: 1025,
+                max: 1024
+            }
+        ));
+    }
+
+    #[test]
+    fn unknown_flags_are_rejected() {
+        let (mut client, mut server) = pair(Cipher::ChaCha20Poly1305);
+        let mut buf = seal(&mut client, 3, &[0x80, 1, 2]);
+        let e = server.decode(&mut buf).unwrap_err();
+        assert!(matches!(codec_error(e), CodecError::Malformed(_)));
+    }
+
+    #[test]
+    fn bad_padding_length_is_rejected() {
+        let (mut client, mut server) = pair(Cipher::ChaCha20Poly1305);
+        let mut buf = seal(&mut client, 2, &[PADDED, 0]);
+        let e = server.decode(&mut buf).unwrap_err();
+        assert!(matches!(codec_error(e), CodecError::Malformed(_)));
+
+        let (mut client, mut server) = pair(Cipher::ChaCha20Poly1305);
+        let mut buf = seal(&mut client, 5, &[PADDED, 0, 3, 1, 2]);
+        let e = server.decode(&mut buf).unwrap_err();
+        assert!(matches!(codec_error(e), CodecError::Malformed(_)));
+    }
+
+    #[test]
+    fn compressed_frame_needs_compression() {
+        let (mut client, mut server) = pair(Cipher::ChaCha20Poly1305);
+        let mut buf = seal(&mut client, 3, &[COMPRESSED, 1, 2]);
+        let e = server.decode(&mut buf).unwrap_err();
+        assert!(matches!(codec_error(e), CodecError::Malformed(_)));
+    }
+
+    #[test]
+    fn garbage_never_panics() {
+        let (_, mut server) = pair(Cipher::ChaCha20Poly1305);
+        let mut buf = BytesMut::from(&[0xa5; 64][..]);
+        assert!(server.decode(&mut buf).is_err());
+
+        let (_, mut server) = pair(Cipher::Aes256Gcm);
+        let mut buf = BytesMut::from(&[0; 3][..]);
+        assert!(server.decode(&mut buf).unwrap().is_none());
+    }
+}
@@ -1,4 +1,8 @@
-use super::safe_codec::SafeCodec;
+use super::{
+    format::{MessageCodec, Serializer},
+    safe_codec::SafeCodec,
+    Builder, Format, FrameCodec, Stats, WithPayload,
+};
 use futures::{Sink, Stream};
 use pin_project_lite::pin_project;
 use serde::{Deserialize, Serialize};
@@ -6,24 +10,29 @@ use std::{
     io,
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_serde::{formats::Bincode, Framed as SerdeFramed};
 use tokio_util::codec::Framed;
 
 // pub type Transport<S, Item, SinkItem> = SerdeFramed<Framed<S, SafeCodec>, Item, SinkItem, Bincode<Item, SinkItem>>;
 
 pin_project! {
-    pub struct Transport<S, Item, SinkItem> {
+    /// Serialized messages over `S`, framed by the codec `C`.
+    pub struct Transport<S, Item, SinkItem, C = SafeCodec> {
         #[pin]
-        inner: SerdeFramed<Framed<S, SafeCodec>, Item, SinkItem, Bincode<Item, SinkItem>>,
+        inner: Framed<S, MessageCodec<Item, SinkItem, C>>,
+        user: Option<String>,
+        key_id: Option<String>,
+        cover: Option<Duration>,
     }
 }
 
-impl<S, Item, SinkItem> Stream for Transport<S, Item, SinkItem>
+impl<S, Item, SinkItem, C> Stream for Transport<S, Item, SinkItem, C>
 where
     S: AsyncRead,
     Item: for<'a> Deserialize<'a>,
+    C: FrameCodec,
 {
     type Item = io::Result<Item>;
 
@@ -32,10 +41,11 @@ where
     }
 }
 
-impl<S, Item, SinkItem> Sink<SinkItem> for Transport<S, Item, SinkItem>
+impl<S, Item, SinkItem, C> Sink<SinkItem> for Transport<S, Item, SinkItem, C>
 where
     S: AsyncWrite,
     SinkItem: Serialize,
+    C: FrameCodec,
 {
     type Error = io::Error;
 
@@ -56,13 +66,117 @@ where
     }
 }
 
-impl<S, Item, SinkItem> From<S> for Transport<S, Item, SinkItem>
+impl<S, T, SinkItem, C> Stream for Transport<S, WithPayload<T>, SinkItem, C>
+where
+    S: AsyncRead,
+    T: for<'a> Deserialize<'a>,
+    C: FrameCodec,
+{
+    type Item = io::Result<WithPayload<T>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().inner.poll_next(cx)
+    }
+}
+
+impl<S, Item, T, C> Sink<WithPayload<T>> for Transport<S, Item, WithPayload<T>, C>
+where
+    S: AsyncWrite,
+    T: Serialize,
+    C: FrameCodec,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: WithPayload<T>) -> io::Result<()> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<S, Item, SinkItem, C> Transport<S, Item, SinkItem, C>
 where
     S: AsyncWrite + AsyncRead,
+    C: FrameCodec,
 {
-    fn from(inner: S) -> Self {
+    /// Limits serialized messages to the largest frame of `codec`.
+    pub fn new(inner: S, codec: C) -> Self {
+        let max = codec.max_frame_len() as u64;
+        Self::with_max_message_len(inner, codec, max)
+    }
+
+    /// Limits serialized messages to `max` bytes, including the sizes their
+    /// fields claim while being deserialized.
+    pub fn with_max_message_len(inner: S, codec: C, max: u64) -> Self {
+        Self::with_format(inner, codec, Format::default(), max)
+    }
+
+    /// Serializes messages with `format`, limited to `max` bytes. Nothing
+    /// checks that the peer uses the same format, see
+    /// [`Builder::format`](super::Builder::format).
+    pub fn with_format(inner: S, codec: C, format: Format, max: u64) -> Self {
+        let serializer = Serializer::new(format, max);
         Transport {
-            inner: SerdeFramed::new(Framed::new(inner, SafeCodec::new()), Bincode::default()),
+            inner: Framed::new(inner, MessageCodec::new(codec, serializer)),
+            user: None,
+            key_id: None,
+            cover: None,
         }
     }
+
+    /// Has the dispatcher running this transport send a cover frame whenever
+    /// the connection has been idle for about `interval`, see
+    /// [`Builder::cover`].
+    pub fn with_cover(mut self, interval: Option<Duration>) -> Self {
+        self.cover = interval;
+        self
+    }
+
+    pub fn cover_interval(&self) -> Option<Duration> {
+        self.cover
+    }
+
+    pub(crate) fn with_identity(mut self, user: Option<String>, key_id: Option<String>) -> Self {
+        self.user = user;
+        self.key_id = key_id;
+        self
+    }
+
+    /// User the peer authenticated as, `None` for a client or a peer that
+    /// used the shared key.
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// [Fingerprint](super::Key::fingerprint) of the pre-shared key the
+    /// handshake used, or of the peer's static key with [`Noise`](super::Noise).
+    /// `None` for a Noise NK server, whose clients have no static key, and
+    /// without a handshake.
+    pub fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+
+    pub fn stats(&self) -> &Stats {
+        self.inner.codec().codec.stats()
+    }
+
+    pub fn format(&self) -> Format {
+        self.inner.codec().serializer.format()
+    }
+}
+
+impl Transport<(), (), ()> {
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
 }
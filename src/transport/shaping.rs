@@ -0,0 +1,167 @@
+use futures::{ready, Future};
+use pin_project_lite::pin_project;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::{
+    fmt, io,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::time::{self, Instant, Sleep};
+
+/// Largest amount of padding a single frame can carry.
+pub const MAX_PADDING: usize = u16::MAX as usize;
+
+/// How much random padding is sealed into each frame, hiding the size of the
+/// data it carries.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Padding {
+    #[default]
+    None,
+    /// Pads each frame up to the smallest of these sizes that fits it, or to a
+    /// multiple of the largest one.
+    Buckets(Vec<usize>),
+    /// Appends between 0 and N bytes, chosen uniformly.
+    Random(usize),
+}
+
+impl Padding {
+    /// Fails if a size is out of range.
+    pub fn check(&self) -> io::Result<()> {
+        let ok = match self {
+            Padding::None => true,
+            Padding::Buckets(sizes) => {
+                !sizes.is_empty() && sizes.iter().all(|&n| n > 0 && n <= MAX_PADDING)
+            }
+            Padding::Random(n) => *n <= MAX_PADDING,
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("padding sizes must be between 1 and {}", MAX_PADDING),
+            ))
+        }
+    }
+
+    /// Padding for a frame of `len` bytes, including the padding header, or
+    /// `None` if padding is disabled.
+    pub(crate) fn pad(&self, len: usize, rng: &SystemRandom) -> Option<usize> {
+        let pad = match self {
+            Padding::None => return None,
+            Padding::Buckets(sizes) => {
+                let largest = sizes.iter().copied().max().unwrap_or(1);
+                let target = sizes
+                    .iter()
+                    .copied()
+                    .filter(|&n| n >= len)
+                    .min()
+                    .unwrap_or_else(|| len.div_ceil(largest) * largest);
+                target - len
+            }
+            Padding::Random(n) => random(rng, *n),
+        };
+        Some(pad.min(MAX_PADDING))
+    }
+}
+
+impl fmt::Display for Padding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Padding::None => f.write_str("none"),
+            Padding::Buckets(sizes) => {
+                let sizes: Vec<_> = sizes.iter().map(|n| n.to_string()).collect();
+                write!(f, "buckets:{}", sizes.join(","))
+            }
+            Padding::Random(n) => write!(f, "random:{}", n),
+        }
+    }
+}
+
+/// Parses `none`, `random:<max>` or `buckets:<size>,<size>,...`.
+impl FromStr for Padding {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid padding {:?}", s),
+            )
+        };
+
+        let s = s.trim();
+        let (kind, arg) = match s.find(':') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => (s, ""),
+        };
+
+        let padding = match kind {
+            "none" if arg.is_empty() => Padding::None,
+            "random" => Padding::Random(arg.trim().parse().map_err(|_| invalid())?),
+            "buckets" => {
+                let mut sizes = arg
+                    .split(',')
+                    .map(|n| n.trim().parse())
+                    .collect::<Result<Vec<usize>, _>>()
+                    .map_err(|_| invalid())?;
+                sizes.sort_unstable();
+                sizes.dedup();
+                Padding::Buckets(sizes)
+            }
+            _ => return Err(invalid()),
+        };
+        padding.check()?;
+        Ok(padding)
+    }
+}
+
+pin_project! {
+    /// Fires when the connection has sent nothing for about `interval`, so
+    /// that a cover frame can be sent in place of real data.
+    pub(crate) struct Cover {
+        #[pin]
+        sleep: Sleep,
+        interval: Duration,
+        rng: SystemRandom,
+    }
+}
+
+impl Cover {
+    pub(crate) fn new(interval: Duration) -> Self {
+        let rng = SystemRandom::new();
+        Self {
+            sleep: time::sleep_until(deadline(&rng, interval)),
+            interval,
+            rng,
+        }
+    }
+
+    /// Pushes the next cover frame back, called whenever a frame is sent.
+    pub(crate) fn reset(self: Pin<&mut Self>) {
+        let this = self.project();
+        this.sleep.reset(deadline(this.rng, *this.interval));
+    }
+
+    pub(crate) fn poll_due(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        ready!(self.as_mut().project().sleep.poll(cx));
+        self.reset();
+        Poll::Ready(())
+    }
+}
+
+/// Between half and one and a half `interval` from now, so cover frames do
+/// not form a regular beat.
+fn deadline(rng: &SystemRandom, interval: Duration) -> Instant {
+    let millis = interval.as_millis().min(u32::MAX as u128) as usize;
+    Instant::now() + Duration::from_millis((millis / 2 + random(rng, millis)) as u64)
+}
+
+/// Uniformly random number in `0..=max`.
+fn random(rng: &SystemRandom, max: usize) -> usize {
+    let mut bytes = [0; 8];
+    rng.fill(&mut bytes).expect("system random");
+    (u64::from_be_bytes(bytes) % (max as u64 + 1)) as usize
+}
@@ -0,0 +1,155 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// Traffic counters of one connection, shared with the codec that updates them.
+#[derive(Clone, Debug, Default)]
+pub struct Stats(Arc<Counters>);
+
+#[derive(Debug, Default)]
+struct Counters {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    frames_sent: AtomicU64,
+    frames_received: AtomicU64,
+    rekeys_sent: AtomicU64,
+    rekeys_received: AtomicU64,
+    padding_sent: AtomicU64,
+    padding_received: AtomicU64,
+    cover_sent: AtomicU64,
+    cover_received: AtomicU64,
+    compressed_in: AtomicU64,
+    compressed_out: AtomicU64,
+}
+
+impl Stats {
+    /// Bytes written, including frame headers and tags.
+    pub fn bytes_sent(&self) -> u64 {
+        self.0.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Bytes read, including frame headers and tags.
+    pub fn bytes_received(&self) -> u64 {
+        self.0.bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub fn frames_sent(&self) -> u64 {
+        self.0.frames_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn frames_received(&self) -> u64 {
+        self.0.frames_received.load(Ordering::Relaxed)
+    }
+
+    /// Times the sending key was replaced.
+    pub fn rekeys_sent(&self) -> u64 {
+        self.0.rekeys_sent.load(Ordering::Relaxed)
+    }
+
+    /// Times the receiving key was replaced.
+    pub fn rekeys_received(&self) -> u64 {
+        self.0.rekeys_received.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of padding sent, the overhead of the padding policy.
+    pub fn padding_sent(&self) -> u64 {
+        self.0.padding_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn padding_received(&self) -> u64 {
+        self.0.padding_received.load(Ordering::Relaxed)
+    }
+
+    /// Cover frames sent while the connection was idle.
+    pub fn cover_sent(&self) -> u64 {
+        self.0.cover_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn cover_received(&self) -> u64 {
+        self.0.cover_received.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of the frames sent compressed, before compression.
+    pub fn compressed_in(&self) -> u64 {
+        self.0.compressed_in.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of the frames sent compressed, after compression.
+    pub fn compressed_out(&self) -> u64 {
+        self.0.compressed_out.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_sent(&self, len: usize) {
+        self.0.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        self.0.frames_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_received(&self, len: usize) {
+        self.0
+            .bytes_received
+            .fetch_add(len as u64, Ordering::Relaxed);
+        self.0.frames_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_rekey_sent(&self) {
+        self.0.rekeys_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_rekey_received(&self) {
+        self.0.rekeys_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_padding_sent(&self, len: usize) {
+        self.0.padding_sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_padding_received(&self, len: usize) {
+        self.0
+            .padding_received
+            .fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_cover_sent(&self) {
+        self.0.cover_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_cover_received(&self) {
+        self.0.cover_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_compressed(&self, before: usize, after: usize) {
+        self.0
+            .compressed_in
+            .fetch_add(before as u64, Ordering::Relaxed);
+        self.0
+            .compressed_out
+            .fetch_add(after as u64, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent {} bytes in {} frames, received {} bytes in {} frames, rekeyed {} sent / {} received, \
+             padding {} sent / {} received bytes, cover {} sent / {} received frames, \
+             compressed {} to {} bytes",
+            self.bytes_sent(),
+            self.frames_sent(),
+            self.bytes_received(),
+            self.frames_received(),
+            self.rekeys_sent(),
+            self.rekeys_received(),
+            self.padding_sent(),
+            self.padding_received(),
+            self.cover_sent(),
+            self.cover_received(),
+            self.compressed_in(),
+            self.compressed_out()
+        )
+    }
+}
@@ -0,0 +1,460 @@
+//! TLS under the tunnel, so that it looks like ordinary HTTPS.
+//!
+//! The [`Transport`](super::Transport) runs inside the TLS stream with its own
+//! handshake and sealing unchanged. Both sides offer the `http/1.1`
+//! application protocol, so a WebSocket on top makes the connection a plain
+//! `wss://` one.
+
+use ring::digest::{digest, SHA256};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{self, CryptoProvider},
+    pki_types::{
+        pem::PemObject, CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime,
+    },
+    ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+};
+use std::{convert::TryFrom, fmt, fs, io, path::Path, sync::Arc};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+pub use tokio_rustls::{
+    client::TlsStream as TlsClientStream, server::TlsStream as TlsServerStream,
+};
+
+/// Application protocol offered by both sides.
+const ALPN: &[u8] = b"http/1.1";
+
+/// Certificate chain and private key of a TLS server.
+pub struct Certificate {
+    chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+}
+
+impl Certificate {
+    /// Loads a PEM certificate chain, leaf first, and its PEM private key.
+    pub fn from_pem_files<P: AsRef<Path>>(cert: P, key: P) -> io::Result<Self> {
+        let chain = CertificateDer::pem_file_iter(cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(pem_error)?;
+        if chain.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no certificate in PEM file",
+            ));
+        }
+        let key = PrivateKeyDer::from_pem_file(key).map_err(pem_error)?;
+        Ok(Self { chain, key })
+    }
+
+    /// Generates a self-signed certificate for `names`, host names or IP
+    /// addresses. Clients have to [pin](Verification::pin) it.
+    pub fn self_signed(names: &[String]) -> io::Result<Self> {
+        let generated = rcgen::generate_simple_self_signed(names.to_vec()).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("certificate: {}", e))
+        })?;
+        let key = PrivatePkcs8KeyDer::from(generated.key_pair.serialize_der()).into();
+        Ok(Self {
+            chain: vec![generated.cert.der().clone()],
+            key,
+        })
+    }
+
+    /// Writes the certificate chain and the private key as PEM files, the
+    /// key readable by the owner only.
+    pub fn save_pem_files<P: AsRef<Path>>(&self, cert: P, key: P) -> io::Result<()> {
+        let chain: String = self
+            .chain
+            .iter()
+            .map(|cert| pem("CERTIFICATE", cert))
+            .collect();
+        fs::write(cert, chain)?;
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        io::Write::write_all(
+            &mut options.open(key)?,
+            pem("PRIVATE KEY", self.key.secret_der()).as_bytes(),
+        )
+    }
+
+    /// SHA-256 digest of the leaf certificate in hex, what clients
+    /// [pin](Verification::pin).
+    pub fn fingerprint(&self) -> String {
+        hex(&sha256(&self.chain[0]))
+    }
+}
+
+impl fmt::Debug for Certificate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Certificate")
+            .field("fingerprint", &self.fingerprint())
+            .finish()
+    }
+}
+
+/// How a client checks the server's certificate.
+#[derive(Clone, Debug, Default)]
+pub enum Verification {
+    /// Signed by one of the public web CAs, and valid for the server name.
+    #[default]
+    WebPki,
+    /// Signed by one of these CAs, and valid for the server name.
+    Ca(Vec<CertificateDer<'static>>),
+    /// The leaf certificate has this SHA-256 digest. Names and validity
+    /// periods are not checked, which suits self-signed certificates.
+    Pin([u8; 32]),
+}
+
+impl Verification {
+    /// Trusts the CAs of a PEM file.
+    pub fn ca_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let cas = CertificateDer::pem_file_iter(path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(pem_error)?;
+        if cas.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no certificate in CA file",
+            ));
+        }
+        Ok(Verification::Ca(cas))
+    }
+
+    /// Pins a SHA-256 fingerprint in hex, with or without colons as printed
+    /// by `openssl x509 -fingerprint -sha256`.
+    pub fn pin(fingerprint: &str) -> io::Result<Self> {
+        let hex: String = fingerprint.trim().chars().filter(|&c| c != ':').collect();
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid certificate fingerprint {:?}", fingerprint),
+            )
+        };
+        // from_str_radix would take a sign, so check the digits first
+        if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+
+        let mut digest = [0; 32];
+        for (i, byte) in digest.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Verification::Pin(digest))
+    }
+}
+
+/// TLS client side: the server name sent as SNI and checked against the
+/// certificate, and how the certificate is verified.
+#[derive(Clone)]
+pub struct TlsClient {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl TlsClient {
+    /// `server_name` is a host name, sent as SNI, or an IP address, for
+    /// which no SNI is sent.
+    pub fn new(server_name: &str, verification: Verification) -> io::Result<Self> {
+        let server_name = ServerName::try_from(server_name.to_owned()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid TLS server name {:?}", server_name),
+            )
+        })?;
+
+        let config = client_config(verification, ALPN)?;
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+
+    /// Runs the TLS handshake over `io`.
+    pub async fn connect<S>(&self, io: S) -> io::Result<TlsClientStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.connector.connect(self.server_name.clone(), io).await
+    }
+}
+
+/// TLS server side, presenting one certificate to every client.
+#[derive(Clone)]
+pub struct TlsServer {
+    acceptor: TlsAcceptor,
+}
+
+impl TlsServer {
+    pub fn new(certificate: Certificate) -> io::Result<Self> {
+        let config = server_config(certificate, ALPN)?;
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    /// Runs the TLS handshake over `io`.
+    pub async fn accept<S>(&self, io: S) -> io::Result<TlsServerStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.acceptor.accept(io).await
+    }
+}
+
+/// Client configuration verifying the server as `verification` says and
+/// offering the application protocol `alpn`.
+pub(crate) fn client_config(verification: Verification, alpn: &[u8]) -> io::Result<ClientConfig> {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?;
+    let builder = match verification {
+        Verification::WebPki => builder.with_root_certificates(RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        }),
+        Verification::Ca(cas) => {
+            let mut roots = RootCertStore::empty();
+            for ca in cas {
+                roots.add(ca).map_err(tls_error)?;
+            }
+            builder.with_root_certificates(roots)
+        }
+        Verification::Pin(fingerprint) => {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(Pinned {
+                    fingerprint,
+                    provider,
+                }))
+        }
+    };
+    let mut config = builder.with_no_client_auth();
+    config.alpn_protocols = vec![alpn.to_vec()];
+    Ok(config)
+}
+
+/// Server configuration presenting `certificate` and offering the
+/// application protocol `alpn`.
+pub(crate) fn server_config(certificate: Certificate, alpn: &[u8]) -> io::Result<ServerConfig> {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_no_client_auth()
+        .with_single_cert(certificate.chain, certificate.key)
+        .map_err(tls_error)?;
+    config.alpn_protocols = vec![alpn.to_vec()];
+    Ok(config)
+}
+
+/// Accepts the one certificate whose digest is pinned.
+#[derive(Debug)]
+struct Pinned {
+    fingerprint: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for Pinned {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _: &[CertificateDer<'_>],
+        _: &ServerName<'_>,
+        _: &[u8],
+        _: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = sha256(end_entity);
+        if fingerprint == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "certificate fingerprint {} is not the pinned one",
+                hex(&fingerprint)
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.provider.signature_verification_algorithms;
+        crypto::verify_tls12_signature(message, cert, dss, algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.provider.signature_verification_algorithms;
+        crypto::verify_tls13_signature(message, cert, dss, algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut out = [0; 32];
+    out.copy_from_slice(digest(&SHA256, data).as_ref());
+    out
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// PEM block of `der` with the given label.
+fn pem(label: &str, der: &[u8]) -> String {
+    let body = base64::encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in body.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).expect("base64"));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+fn pem_error(e: rustls::pki_types::pem::Error) -> io::Error {
+    match e {
+        rustls::pki_types::pem::Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, format!("PEM: {:?}", e)),
+    }
+}
+
+fn tls_error(e: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("tls: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Certificate, TlsClient, TlsServer, Verification};
+    use crate::{
+        client,
+        server::{self, Server},
+        testing::{builder, exchange},
+    };
+    use bytes::Bytes;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use std::io;
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        task::JoinHandle,
+    };
+
+    fn self_signed() -> Certificate {
+        Certificate::self_signed(&["localhost".to_owned()]).unwrap()
+    }
+
+    /// A CA and a certificate for `localhost` it signed.
+    fn ca_signed() -> (Verification, Certificate) {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = params.self_signed(&ca_key).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["localhost".to_owned()])
+            .unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+        let certificate = Certificate {
+            chain: vec![cert.der().clone()],
+            key: PrivatePkcs8KeyDer::from(key.serialize_der()).into(),
+        };
+        (Verification::Ca(vec![ca.der().clone()]), certificate)
+    }
+
+    /// A TLS server presenting `certificate`, on a local port.
+    async fn listen(
+        certificate: Certificate,
+    ) -> (String, JoinHandle<io::Result<Server<Bytes, Bytes>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let tls = TlsServer::new(certificate).unwrap();
+        let server = tokio::spawn(async move {
+            let (conn, _) = listener.accept().await?;
+            server::accept(tls.accept(conn).await?, builder()).await
+        });
+        (addr, server)
+    }
+
+    async fn connect(addr: &str, verification: Verification) -> io::Result<()> {
+        let conn = TcpStream::connect(addr).await?;
+        TlsClient::new("localhost", verification)?
+            .connect(conn)
+            .await
+            .map(drop)
+    }
+
+    #[tokio::test]
+    async fn channels_over_tls() {
+        let certificate = self_signed();
+        let pin = Verification::pin(&certificate.fingerprint()).unwrap();
+        let (addr, server) = listen(certificate).await;
+
+        let conn = TcpStream::connect(addr).await.unwrap();
+        let conn = TlsClient::new("localhost", pin)
+            .unwrap()
+            .connect(conn)
+            .await
+            .unwrap();
+        let mut client = client::connect(conn, builder()).await.unwrap();
+
+        exchange(&mut client, server.await.unwrap().unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn wrong_pin_is_rejected() {
+        let pin = Verification::pin(&self_signed().fingerprint()).unwrap();
+        let (addr, server) = listen(self_signed()).await;
+        assert!(connect(&addr, pin).await.is_err());
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn trusted_ca_is_accepted() {
+        let (ca, certificate) = ca_signed();
+        let (addr, _server) = listen(certificate).await;
+        connect(&addr, ca).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn untrusted_ca_is_rejected() {
+        let (ca, _) = ca_signed();
+        let (_, certificate) = ca_signed();
+        let (addr, server) = listen(certificate).await;
+        assert!(connect(&addr, ca).await.is_err());
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[test]
+    fn pin_takes_two_hex_digits_per_byte() {
+        let hex = "00112233445566778899aabbccddeeff00112233445566778899AABBCCDDEEFF";
+        assert!(matches!(Verification::pin(hex), Ok(Verification::Pin(d)) if d[31] == 0xff));
+        let colons: Vec<_> = hex
+            .as_bytes()
+            .chunks(2)
+            .map(|b| std::str::from_utf8(b).unwrap())
+            .collect();
+        assert!(Verification::pin(&colons.join(":")).is_ok());
+
+        let signed = format!("+f{}", &hex[2..]);
+        assert!(Verification::pin(&signed).is_err());
+        assert!(Verification::pin(&hex[2..]).is_err());
+        assert!(Verification::pin(&format!("{}0", hex)).is_err());
+        assert!(Verification::pin(&format!("g{}", &hex[1..])).is_err());
+    }
+}
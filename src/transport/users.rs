@@ -0,0 +1,353 @@
+use super::{Key, Validity};
+use std::{
+    fs, io,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Named keys a server accepts, each user holding their own key.
+///
+/// The server finds the user of a connection by trying every key on the
+/// client hello, so removing a user revokes only that user's key. A user may
+/// hold several keys with overlapping validity, so that clients can move to a
+/// new key while the old one is still accepted.
+#[derive(Clone, Debug, Default)]
+pub struct Users {
+    keys: Vec<UserKey>,
+}
+
+#[derive(Clone, Debug)]
+struct UserKey {
+    name: String,
+    key: Key,
+    validity: Validity,
+}
+
+impl Users {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a key for user `name`, always valid.
+    pub fn insert(&mut self, name: impl Into<String>, key: Key) -> io::Result<()> {
+        self.insert_with_validity(name, key, Validity::always())
+    }
+
+    /// Adds a key for user `name`, accepted only during `validity`.
+    ///
+    /// Fails if the key already belongs to a user.
+    pub fn insert_with_validity(
+        &mut self,
+        name: impl Into<String>,
+        key: Key,
+        validity: Validity,
+    ) -> io::Result<()> {
+        let name = name.into();
+        if name.is_empty() || name.chars().any(char::is_whitespace) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid user name {:?}", name),
+            ));
+        }
+        if let Some(other) = self.keys.iter().find(|k| k.key == key) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("user {:?} reuses a key of user {:?}", name, other.name),
+            ));
+        }
+
+        self.keys.push(UserKey {
+            name,
+            key,
+            validity,
+        });
+        Ok(())
+    }
+
+    /// Removes every key of a user, returns whether it existed.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.keys.len();
+        self.keys.retain(|k| k.name != name);
+        self.keys.len() != len
+    }
+
+    /// Reads a users file, one `<name> <base64 key> [<not before> [<not after>]]`
+    /// entry per line.
+    ///
+    /// Times are seconds since the Unix epoch, `-` leaves a side unbounded. A
+    /// name may appear on several lines to give a user several keys. Blank
+    /// lines and lines starting with `#` are ignored.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+
+        let mut users = Self::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let at_line = |e: io::Error| io::Error::new(e.kind(), format!("line {}: {}", n + 1, e));
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 2 || fields.len() > 4 {
+                let e = io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "expected <name> <key> [<not before> [<not after>]]",
+                );
+                return Err(at_line(e));
+            }
+
+            let key = Key::from_base64(fields[1]).map_err(at_line)?;
+            let validity = Validity {
+                not_before: parse_time(fields.get(2)).map_err(at_line)?,
+                not_after: parse_time(fields.get(3)).map_err(at_line)?,
+            };
+            users
+                .insert_with_validity(fields[0], key, validity)
+                .map_err(at_line)?;
+        }
+        Ok(users)
+    }
+
+    /// Number of keys, over all users.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Names of the users, in the order they were added.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = Vec::new();
+        for k in &self.keys {
+            if !names.contains(&k.name.as_str()) {
+                names.push(&k.name);
+            }
+        }
+        names
+    }
+
+    /// Every key with its user and validity.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Key, &Validity)> {
+        self.keys
+            .iter()
+            .map(|k| (k.name.as_str(), &k.key, &k.validity))
+    }
+
+    /// Keys valid at `time`, with the name of their user.
+    pub(crate) fn valid_at(&self, time: SystemTime) -> impl Iterator<Item = (&str, &Key)> {
+        self.keys
+            .iter()
+            .filter(move |k| k.validity.contains(time))
+            .map(|k| (k.name.as_str(), &k.key))
+    }
+}
+
+fn parse_time(field: Option<&&str>) -> io::Result<Option<SystemTime>> {
+    match field {
+        None | Some(&"-") => Ok(None),
+        Some(s) => s
+            .parse()
+            .map(|secs| Some(UNIX_EPOCH + Duration::from_secs(secs)))
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "invalid time {:?}, expected seconds since the Unix epoch",
+                        s
+                    ),
+                )
+            }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{Builder, Transport};
+    use tokio::io::{duplex, DuplexStream};
+
+    fn key(byte: u8) -> Key {
+        Key::new(&[byte; 32]).unwrap()
+    }
+
+    /// Writes `text` to a users file named after `test` and loads it.
+    fn load(test: &str, text: &str) -> io::Result<Users> {
+        let path = std::env::temp_dir().join(format!("yew-{}-{}", test, std::process::id()));
+        fs::write(&path, text).unwrap();
+        let users = Users::from_file(&path);
+        fs::remove_file(&path).unwrap();
+        users
+    }
+
+    /// Connects a client holding `key` to a server accepting `users`,
+    /// returning the user and key id the server found.
+    async fn accept(users: Users, key: Key) -> io::Result<(Option<String>, Option<String>)> {
+        let (a, b) = duplex(4096);
+        let client = Builder::new().key(key);
+        let server = Builder::new().users(users);
+        let (_, transport) = tokio::join!(
+            async {
+                let transport: io::Result<Transport<DuplexStream, (), ()>> =
+                    client.connect(a).await;
+                transport
+            },
+            async {
+                let transport: io::Result<Transport<DuplexStream, (), ()>> = server.accept(b).await;
+                transport
+            },
+        );
+        let transport = transport?;
+        let user = transport.user().map(str::to_owned);
+        Ok((user, transport.key_id().map(str::to_owned)))
+    }
+
+    #[test]
+    fn from_file_reads_every_entry() {
+        let text = format!(
+            "# users\n\nalice {a}\nbob {b}\n  bob {c}  \n",
+            a = key(1).to_base64(),
+            b = key(2).to_base64(),
+            c = key(3).to_base64(),
+        );
+        let users = load("users-entries", &text).unwrap();
+
+        assert_eq!(users.len(), 3);
+        assert_eq!(users.names(), ["alice", "bob"]);
+        let entries: Vec<_> = users
+            .iter()
+            .map(|(name, key, _)| (name, key.fingerprint()))
+            .collect();
+        let expected = [("alice", 1), ("bob", 2), ("bob", 3)]
+            .map(|(name, byte)| (name, key(byte).fingerprint()));
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn from_file_reports_the_bad_line() {
+        let a = key(1).to_base64();
+        let cases = [
+            format!("alice {}\nbob\n", a),
+            format!("alice {}\nbob {}\n", a, a),
+            format!("# bad key\nalice {}\n", &a[1..]),
+            format!("alice {} 1 2 3\n", a),
+        ];
+        for (text, line) in cases.iter().zip([2, 2, 2, 1]) {
+            let e = load("users-errors", text).unwrap_err();
+            assert!(
+                e.to_string().starts_with(&format!("line {}:", line)),
+                "{}",
+                e
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn user_is_identified_by_key() {
+        let mut users = Users::new();
+        users.insert("alice", key(1)).unwrap();
+        users.insert("bob", key(2)).unwrap();
+        users.insert("bob", key(3)).unwrap();
+
+        for (byte, name) in [(1, "alice"), (2, "bob"), (3, "bob")] {
+            let (user, key_id) = accept(users.clone(), key(byte)).await.unwrap();
+            assert_eq!(user.as_deref(), Some(name));
+            assert_eq!(key_id, Some(key(byte).fingerprint()));
+        }
+        let e = accept(users, key(4)).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn from_file_reads_validity() {
+        let text = format!(
+            "alice {a} 100\nalice {b} - 200\nbob {c} 100 200\n",
+            a = key(1).to_base64(),
+            b = key(2).to_base64(),
+            c = key(3).to_base64(),
+        );
+        let users = load("users-validity", &text).unwrap();
+
+        let at = |secs| Some(UNIX_EPOCH + Duration::from_secs(secs));
+        let validities: Vec<_> = users.iter().map(|(_, _, validity)| *validity).collect();
+        let expected = [(at(100), None), (None, at(200)), (at(100), at(200))].map(
+            |(not_before, not_after)| Validity {
+                not_before,
+                not_after,
+            },
+        );
+        assert_eq!(validities, expected);
+
+        let e = load(
+            "users-validity",
+            &format!("alice {} soon\n", key(1).to_base64()),
+        );
+        assert!(e.unwrap_err().to_string().starts_with("line 1:"));
+    }
+
+    #[test]
+    fn valid_at_applies_the_intervals() {
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let mut users = Users::new();
+        // 旧密钥在 200 过期, 新密钥从 100 起有效
+        let old = Validity {
+            not_before: None,
+            not_after: Some(at(200)),
+        };
+        let new = Validity {
+            not_before: Some(at(100)),
+            not_after: None,
+        };
+        users.insert_with_validity("alice", key(1), old).unwrap();
+        users.insert_with_validity("alice", key(2), new).unwrap();
+        users.insert("bob", key(3)).unwrap();
+
+        let valid = |secs| -> Vec<String> {
+            users
+                .valid_at(at(secs))
+                .map(|(name, key)| format!("{} {}", name, key.as_bytes()[0]))
+                .collect()
+        };
+        assert_eq!(valid(0), ["alice 1", "bob 3"]);
+        assert_eq!(valid(99), ["alice 1", "bob 3"]);
+        assert_eq!(valid(100), ["alice 1", "alice 2", "bob 3"]);
+        assert_eq!(valid(199), ["alice 1", "alice 2", "bob 3"]);
+        assert_eq!(valid(200), ["alice 2", "bob 3"]);
+        assert_eq!(valid(u32::MAX as u64), ["alice 2", "bob 3"]);
+    }
+
+    #[tokio::test]
+    async fn expired_key_is_refused_during_rotation() {
+        let now = SystemTime::now();
+        let minute = Duration::from_secs(60);
+        let mut users = Users::new();
+        let expired = Validity {
+            not_before: None,
+            not_after: Some(now - minute),
+        };
+        let old = Validity {
+            not_before: None,
+            not_after: Some(now + minute),
+        };
+        let new = Validity {
+            not_before: Some(now - minute),
+            not_after: None,
+        };
+        users
+            .insert_with_validity("alice", key(1), expired)
+            .unwrap();
+        users.insert_with_validity("alice", key(2), old).unwrap();
+        users.insert_with_validity("alice", key(3), new).unwrap();
+
+        let e = accept(users.clone(), key(1)).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        // 重叠期内新旧密钥都被接受
+        for byte in [2, 3] {
+            let (user, key_id) = accept(users.clone(), key(byte)).await.unwrap();
+            assert_eq!(user.as_deref(), Some("alice"));
+            assert_eq!(key_id, Some(key(byte).fingerprint()));
+        }
+    }
+}
@@ -0,0 +1,250 @@
+//! Byte stream over a WebSocket, so that the tunnel can sit behind HTTP
+//! reverse proxies and CDNs.
+//!
+//! The client sends an HTTP/1.1 upgrade request for a configured path and
+//! `Host`, afterwards each write becomes one binary message. A [`Transport`]
+//! runs over the resulting [`WsStream`] as over any other connection, the
+//! handshake and sealing are unchanged.
+//!
+//! [`Transport`]: super::Transport
+
+use bytes::{Buf, Bytes};
+use futures::{ready, Sink, Stream};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{
+    tungstenite::{
+        self,
+        client::IntoClientRequest,
+        handshake::server::{ErrorResponse, Request, Response},
+        http::{header, StatusCode},
+        Message,
+    },
+    WebSocketStream,
+};
+
+/// Largest message a single write turns into.
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+/// Path and `Host` of the HTTP upgrade.
+#[derive(Clone, Debug)]
+pub struct WebSocket {
+    path: String,
+    host: Option<String>,
+}
+
+impl WebSocket {
+    /// Upgrades requests for `path`, such as `/ws`.
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            host: None,
+        }
+    }
+
+    /// Sets the `Host` the client sends, with an optional port. A server
+    /// with a host only upgrades requests for it.
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn host_name(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    /// Sends the upgrade request over `io`, which needs a host.
+    pub async fn connect<S>(&self, io: S) -> io::Result<WsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let host = self
+            .host
+            .as_deref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "websocket needs a host"))?;
+        let request = format!("ws://{}{}", host, self.path)
+            .into_client_request()
+            .map_err(ws_error)?;
+
+        let (inner, _) = tokio_tungstenite::client_async(request, io)
+            .await
+            .map_err(ws_error)?;
+        Ok(WsStream::new(inner))
+    }
+
+    /// Answers the upgrade request read from `io`. Requests for another path
+    /// or host get a 404 and fail.
+    pub async fn accept<S>(&self, io: S) -> io::Result<WsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        // 签名由 tungstenite 决定
+        #[allow(clippy::result_large_err)]
+        let check = |request: &Request, response: Response| {
+            let host = request
+                .headers()
+                .get(header::HOST)
+                .and_then(|host| host.to_str().ok());
+            let wanted = self.host.as_deref().is_none_or(|h| Some(h) == host);
+            if request.uri().path() == self.path && wanted {
+                Ok(response)
+            } else {
+                let mut response = ErrorResponse::new(None);
+                *response.status_mut() = StatusCode::NOT_FOUND;
+                Err(response)
+            }
+        };
+
+        let inner = tokio_tungstenite::accept_hdr_async(io, check)
+            .await
+            .map_err(ws_error)?;
+        Ok(WsStream::new(inner))
+    }
+}
+
+/// Bytes carried by the binary messages of a WebSocket.
+///
+/// A close message ends the stream, text messages are an error. Pings are
+/// answered by the next read or write.
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    read: Bytes,
+}
+
+impl<S> WsStream<S> {
+    fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            read: Bytes::new(),
+        }
+    }
+}
+
+impl<S> AsyncRead for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        // 上一条消息读完后再读下一条
+        while self.read.is_empty() {
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => self.read = data,
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "text message on a binary websocket",
+                    )));
+                }
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Poll::Ready(Err(ws_error(e))),
+            }
+        }
+
+        let len = self.read.len().min(buf.remaining());
+        buf.put_slice(&self.read[..len]);
+        self.read.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(ws_error)?;
+        let len = buf.len().min(MAX_MESSAGE_LEN);
+        let message = Message::Binary(Bytes::copy_from_slice(&buf[..len]));
+        Pin::new(&mut self.inner)
+            .start_send(message)
+            .map_err(ws_error)?;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(ws_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx).map_err(ws_error)
+    }
+}
+
+fn ws_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            io::ErrorKind::BrokenPipe.into()
+        }
+        e => io::Error::new(io::ErrorKind::InvalidData, format!("websocket: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WebSocket;
+    use crate::{
+        client,
+        server::{self, Server},
+        testing::{builder, exchange},
+    };
+    use bytes::Bytes;
+    use std::io;
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        task::JoinHandle,
+    };
+
+    /// A server upgrading `/ws` for `example.com`, on a local port.
+    async fn listen() -> (String, JoinHandle<io::Result<Server<Bytes, Bytes>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (conn, _) = listener.accept().await?;
+            let ws = WebSocket::new("/ws").host("example.com");
+            server::accept(ws.accept(conn).await?, builder()).await
+        });
+        (addr, server)
+    }
+
+    #[tokio::test]
+    async fn channels_over_a_websocket() {
+        let (addr, server) = listen().await;
+        let conn = TcpStream::connect(addr).await.unwrap();
+        let ws = WebSocket::new("/ws").host("example.com");
+        let conn = ws.connect(conn).await.unwrap();
+        let mut client = client::connect(conn, builder()).await.unwrap();
+
+        exchange(&mut client, server.await.unwrap().unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn wrong_path_or_host_is_rejected() {
+        for ws in [
+            WebSocket::new("/other").host("example.com"),
+            WebSocket::new("/ws").host("example.org"),
+        ] {
+            let (addr, server) = listen().await;
+            let conn = TcpStream::connect(addr).await.unwrap();
+            assert!(ws.connect(conn).await.is_err());
+            assert!(server.await.unwrap().is_err());
+        }
+    }
+}